@@ -1,29 +1,669 @@
-use crate::{controller_auth, controller_user, db};
-use axum::{routing, Router};
+use crate::{
+    controller_admin, controller_auth, controller_counter, controller_dialog, controller_friend,
+    controller_group, controller_post, controller_user, counters, db, dialog, dialog_push, feed,
+    feed_push, feed_queue, feed_warmup, memory_store, presence, read_your_writes, shard,
+    user_repository::UserRepository,
+};
+use axum::{extract::FromRef, routing, Router};
 use std::sync::Arc;
 
+pub struct AppState<R> {
+    db: Arc<db::DB>,
+    users: Arc<R>,
+    feed: Arc<feed::FeedCache>,
+    queue: Arc<feed_queue::FeedQueue>,
+    push: Arc<feed_push::FeedPush>,
+    warmup: Arc<feed_warmup::FeedWarmup>,
+    shards: Arc<shard::Shards>,
+    store: Arc<dialog::Store>,
+    counters: Arc<counters::Counters>,
+    dialog_push: Arc<dialog_push::DialogPush>,
+    presence: Arc<presence::Presence>,
+}
+
+impl<R> Clone for AppState<R> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            users: self.users.clone(),
+            feed: self.feed.clone(),
+            queue: self.queue.clone(),
+            push: self.push.clone(),
+            warmup: self.warmup.clone(),
+            shards: self.shards.clone(),
+            store: self.store.clone(),
+            counters: self.counters.clone(),
+            dialog_push: self.dialog_push.clone(),
+            presence: self.presence.clone(),
+        }
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<db::DB> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.db.clone()
+    }
+}
+
+impl<R: UserRepository> FromRef<AppState<R>> for Arc<R> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.users.clone()
+    }
+}
+
+impl<R: UserRepository> FromRef<AppState<R>> for Arc<dyn UserRepository> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.users.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<feed::FeedCache> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.feed.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<feed_queue::FeedQueue> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.queue.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<feed_warmup::FeedWarmup> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.warmup.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<feed_push::FeedPush> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.push.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<shard::Shards> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.shards.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<dialog::Store> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.store.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<counters::Counters> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.counters.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<dialog_push::DialogPush> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.dialog_push.clone()
+    }
+}
+
+impl<R> FromRef<AppState<R>> for Arc<presence::Presence> {
+    fn from_ref(state: &AppState<R>) -> Self {
+        state.presence.clone()
+    }
+}
+
+/// State of the user API served alone, by a user repository that needs no postgres.
+pub struct UsersState<R> {
+    users: Arc<R>,
+    presence: Arc<presence::Presence>,
+}
+
+impl<R> Clone for UsersState<R> {
+    fn clone(&self) -> Self {
+        Self {
+            users: self.users.clone(),
+            presence: self.presence.clone(),
+        }
+    }
+}
+
+impl<R: UserRepository> FromRef<UsersState<R>> for Arc<R> {
+    fn from_ref(state: &UsersState<R>) -> Self {
+        state.users.clone()
+    }
+}
+
+impl<R: UserRepository> FromRef<UsersState<R>> for Arc<dyn UserRepository> {
+    fn from_ref(state: &UsersState<R>) -> Self {
+        state.users.clone()
+    }
+}
+
+impl<R> FromRef<UsersState<R>> for Arc<presence::Presence> {
+    fn from_ref(state: &UsersState<R>) -> Self {
+        state.presence.clone()
+    }
+}
+
+/// Where the API is served and the token of its /admin routes.
+pub struct Listen<'a> {
+    pub bind_string: &'a str,
+    pub admin_token: Option<&'a str>,
+}
+
+/// How often the counters are reconciled and the presence times are flushed.
+pub struct Intervals {
+    pub counters_reconcile: std::time::Duration,
+    pub presence_flush: std::time::Duration,
+}
+
 pub struct App {}
 impl App {
-    pub async fn run(
-        conn_string: &str,
-        bind_string: &str,
-        pg_conn_size: usize,
+    pub async fn run<R: UserRepository>(
+        db: Arc<db::DB>,
+        users: Arc<R>,
+        listen: &Listen<'_>,
+        feed_queue_config: feed_queue::Config,
+        feed_cache_config: feed::CacheConfig,
+        store_config: &dialog::StoreConfig,
+        intervals: &Intervals,
     ) -> anyhow::Result<()> {
-        let db = Arc::new(db::DB::new(conn_string, pg_conn_size).await?);
+        let pg_conn_size = db.pool_size();
+        let presence = Arc::new(presence::Presence::new(
+            users.clone(),
+            intervals.presence_flush,
+        ));
+        presence.spawn_workers();
+        let (shards, store) = match store_config {
+            dialog::StoreConfig::Postgres(shard_config) => {
+                let shards =
+                    Arc::new(shard::Shards::new(shard_config, db.clone(), pg_conn_size).await?);
+                shards.spawn_workers();
+                (shards.clone(), dialog::Store::Postgres(shards))
+            }
+            dialog::StoreConfig::Memory(memory_config) => {
+                // no message shards then, the main DB stands in for them
+                let shards = Arc::new(
+                    shard::Shards::new(&shard::Config::default(), db.clone(), pg_conn_size).await?,
+                );
+                let store = Arc::new(memory_store::MemoryStore::open(memory_config, db.clone())?);
+                store.spawn_workers();
+                (shards, dialog::Store::Memory(store))
+            }
+        };
+        let store = Arc::new(store);
+        let counters = Arc::new(counters::Counters::new(
+            db.clone(),
+            store.clone(),
+            intervals.counters_reconcile,
+        ));
+        counters.spawn_workers();
+        let feed = Arc::new(feed::FeedCache::new(feed_cache_config.max_users));
+        let push = Arc::new(feed_push::FeedPush::new(presence.clone()));
+        let queue = Arc::new(feed_queue::FeedQueue::new(
+            db.clone(),
+            feed.clone(),
+            push.clone(),
+            feed_queue_config,
+        ));
+        queue.spawn_workers();
+        let warmup = Arc::new(feed_warmup::FeedWarmup::new(
+            db.clone(),
+            feed.clone(),
+            feed_cache_config.warmup_users,
+        ));
+        let dialog_push = Arc::new(dialog_push::DialogPush::new(
+            presence.clone(),
+            db.clone(),
+            store.clone(),
+        ));
+        let state = AppState {
+            db,
+            users,
+            feed,
+            queue,
+            push,
+            warmup: warmup.clone(),
+            shards,
+            store,
+            counters,
+            dialog_push,
+            presence,
+        };
+        // a route layer only wraps the routes added before it, the writing ones go first
+        let writes_main = axum::middleware::from_fn(read_your_writes::writes_main);
         let app = Router::new()
-            .route("/login", routing::post(controller_auth::login))
+            .merge(App::user_routes::<R, AppState<R>>())
+            .route("/counters", routing::get(controller_counter::counters))
             .nest(
-                "/user",
+                "/friend",
+                Router::new()
+                    .route("/set/:user_id", routing::put(controller_friend::set_friend))
+                    .route(
+                        "/delete/:user_id",
+                        routing::put(controller_friend::delete_friend),
+                    )
+                    .route_layer(writes_main.clone()),
+            )
+            .nest(
+                "/post",
                 Router::new()
-                    .route("/register", routing::post(controller_user::create_user))
-                    .route("/get/:id", routing::get(controller_user::get_user))
-                    .route("/search", routing::get(controller_user::search_user)),
+                    .route("/create", routing::post(controller_post::create_post))
+                    .route("/update", routing::put(controller_post::update_post))
+                    .route("/delete/:id", routing::put(controller_post::delete_post))
+                    .route_layer(writes_main.clone())
+                    .route("/get/:id", routing::get(controller_post::get_post))
+                    .route("/feed", routing::get(controller_post::feed))
+                    .route("/feed/posted", routing::get(controller_post::feed_posted)),
             )
-            .with_state(db);
-        let listener = tokio::net::TcpListener::bind(bind_string).await.unwrap();
+            .nest(
+                "/dialog",
+                Router::new()
+                    .route(
+                        "/:user_id/send",
+                        routing::post(controller_dialog::send_message),
+                    )
+                    .route(
+                        "/:user_id/read",
+                        routing::post(controller_dialog::read_messages),
+                    )
+                    .route_layer(writes_main.clone())
+                    .route(
+                        "/:user_id/list",
+                        routing::get(controller_dialog::list_messages),
+                    )
+                    .route("/events", routing::get(controller_dialog::dialog_events))
+                    .route("/search", routing::get(controller_dialog::search_messages)),
+            )
+            .nest(
+                "/group",
+                Router::new()
+                    .route("/create", routing::post(controller_group::create_group))
+                    .route(
+                        "/:group_id/rename",
+                        routing::put(controller_group::rename_group),
+                    )
+                    .route(
+                        "/:group_id/add/:user_id",
+                        routing::put(controller_group::add_member),
+                    )
+                    .route(
+                        "/:group_id/remove/:user_id",
+                        routing::put(controller_group::remove_member),
+                    )
+                    .route(
+                        "/:group_id/role/:user_id",
+                        routing::put(controller_group::set_role),
+                    )
+                    .route(
+                        "/:group_id/send",
+                        routing::post(controller_group::send_message),
+                    )
+                    .route_layer(writes_main.clone())
+                    .route("/list", routing::get(controller_group::list_groups))
+                    .route("/:group_id", routing::get(controller_group::get_group))
+                    .route(
+                        "/:group_id/list",
+                        routing::get(controller_group::list_messages),
+                    ),
+            )
+            .nest(
+                "/admin",
+                Router::new()
+                    .route(
+                        "/reshard/cutover",
+                        routing::put(controller_admin::reshard_cut_over),
+                    )
+                    .route(
+                        "/reshard/rollback",
+                        routing::put(controller_admin::reshard_rollback),
+                    )
+                    .route_layer(writes_main)
+                    .route(
+                        "/feed/rebuild",
+                        routing::post(controller_admin::rebuild_feeds)
+                            .get(controller_admin::rebuild_feeds_progress),
+                    )
+                    .route("/db", routing::get(controller_admin::db_stats))
+                    .route("/topology", routing::get(controller_admin::db_topology))
+                    .route("/reshard", routing::get(controller_admin::reshard_status))
+                    .route_layer(axum::middleware::from_fn_with_state(
+                        controller_admin::AdminToken(listen.admin_token.map(Arc::from)),
+                        controller_admin::authorize,
+                    )),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                read_your_writes::track_writes,
+            ))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind(listen.bind_string)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to bind {}: {}", listen.bind_string, e))?;
+        // feeds that aren't warm yet are loaded on the first read meanwhile
+        tokio::spawn(async move {
+            if let Err(err) = warmup.warmup().await {
+                tracing::error!("feed warmup failed: {err:?}");
+            }
+        });
+        axum::serve(listener, app)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to start app: {}", e))?;
+        Ok(())
+    }
+    /// Serves login, registration and the user profiles only, the rest of the API needs
+    /// postgres.
+    pub async fn run_users<R: UserRepository>(
+        users: Arc<R>,
+        bind_string: &str,
+        presence_flush_interval: std::time::Duration,
+    ) -> anyhow::Result<()> {
+        let presence = Arc::new(presence::Presence::new(
+            users.clone(),
+            presence_flush_interval,
+        ));
+        presence.spawn_workers();
+        let app = App::users_router(users, presence);
+        let listener = tokio::net::TcpListener::bind(bind_string)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to bind {}: {}", bind_string, e))?;
         axum::serve(listener, app)
             .await
             .map_err(|e| anyhow::anyhow!("failed to start app: {}", e))?;
         Ok(())
     }
+
+    fn users_router<R: UserRepository>(users: Arc<R>, presence: Arc<presence::Presence>) -> Router {
+        App::user_routes::<R, UsersState<R>>().with_state(UsersState { users, presence })
+    }
+
+    /// Login, registration and the user profiles, everything the user repository serves.
+    fn user_routes<R, S>() -> Router<S>
+    where
+        R: UserRepository,
+        S: Clone + Send + Sync + 'static,
+        Arc<R>: FromRef<S>,
+        Arc<dyn UserRepository>: FromRef<S>,
+        Arc<presence::Presence>: FromRef<S>,
+    {
+        let writes_main = axum::middleware::from_fn(read_your_writes::writes_main);
+        Router::new()
+            .route("/login", routing::post(controller_auth::login::<R>))
+            .route_layer(writes_main.clone())
+            .nest(
+                "/user",
+                Router::new()
+                    .route(
+                        "/register",
+                        routing::post(controller_user::create_user::<R>),
+                    )
+                    .route(
+                        "/presence",
+                        routing::put(controller_user::set_presence::<R>),
+                    )
+                    .route_layer(writes_main)
+                    .route("/get/:id", routing::get(controller_user::get_user::<R>))
+                    .route("/search", routing::get(controller_user::search_user::<R>)),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory_users::MemoryUserRepository, sqlite_users::SqliteUserRepository};
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    fn app<R: UserRepository>(users: R) -> Router {
+        let users = Arc::new(users);
+        let presence = Arc::new(presence::Presence::new(
+            users.clone(),
+            std::time::Duration::from_secs(10),
+        ));
+        App::users_router(users, presence)
+    }
+
+    /// The same API on every repository that needs no server.
+    async fn apps() -> Vec<Router> {
+        vec![
+            app(MemoryUserRepository::default()),
+            app(SqliteUserRepository::open(":memory:").await.unwrap()),
+        ]
+    }
+
+    async fn call(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let body = match body {
+            Some(body) => {
+                request = request.header(header::CONTENT_TYPE, "application/json");
+                Body::from(body.to_string())
+            }
+            None => Body::empty(),
+        };
+        let response = app
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn register(app: &Router, first_name: &str, second_name: &str) -> String {
+        let (status, body) = call(
+            app,
+            Method::POST,
+            "/user/register",
+            None,
+            Some(json!({
+                "first_name": first_name,
+                "second_name": second_name,
+                "birthdate": "1990-01-01",
+                "biography": "reading",
+                "city": "Москва",
+                "password": "secret",
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+        body["user_id"].as_str().unwrap().to_owned()
+    }
+
+    async fn login(app: &Router, user_id: &str, password: &str) -> (StatusCode, Value) {
+        call(
+            app,
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "id": user_id, "password": password })),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn registered_user_is_found_by_id() {
+        for app in apps().await {
+            let user_id = register(&app, "Иван", "Петров").await;
+
+            let (status, user) = call(
+                &app,
+                Method::GET,
+                &format!("/user/get/{user_id}"),
+                None,
+                None,
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(user["id"], user_id.as_str());
+            assert_eq!(user["first_name"], "Иван");
+            assert_eq!(user["second_name"], "Петров");
+            assert_eq!(user["birthdate"], "1990-01-01");
+            assert_eq!(user["city"], "Москва");
+            assert_eq!(user["online"], false);
+            assert!(user.get("password_hash").is_none());
+            assert!(user.get("token").is_none());
+
+            let (status, _) = call(&app, Method::GET, "/user/get/unknown", None, None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_registration_is_rejected() {
+        for app in apps().await {
+            let (status, _) = call(
+                &app,
+                Method::POST,
+                "/user/register",
+                None,
+                Some(json!({ "first_name": "Иван", "second_name": "Петров" })),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+
+            let too_young = chrono::Local::now().format("%Y-%m-%d").to_string();
+            let (status, body) = call(
+                &app,
+                Method::POST,
+                "/user/register",
+                None,
+                Some(json!({
+                    "first_name": "Иван",
+                    "second_name": "Петров",
+                    "birthdate": too_young,
+                    "biography": "",
+                    "city": "Москва",
+                    "password": "secret",
+                })),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(body["message"].as_str().unwrap().contains("18"));
+        }
+    }
+
+    #[tokio::test]
+    async fn login_token_authenticates_requests() {
+        for app in apps().await {
+            let user_id = register(&app, "Иван", "Петров").await;
+
+            let (status, _) = login(&app, &user_id, "wrong").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let (status, _) = login(&app, "unknown", "secret").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let (status, body) = login(&app, &user_id, "secret").await;
+            assert_eq!(status, StatusCode::OK);
+            let token = body["token"].as_str().unwrap();
+
+            let hide = Some(json!({ "visible": false }));
+            let (status, _) = call(&app, Method::PUT, "/user/presence", None, hide.clone()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            let (status, _) = call(
+                &app,
+                Method::PUT,
+                "/user/presence",
+                Some("not-a-token"),
+                hide.clone(),
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            // the authenticated request marks the user online
+            let show = Some(json!({ "visible": true }));
+            let (status, _) = call(&app, Method::PUT, "/user/presence", Some(token), show).await;
+            assert_eq!(status, StatusCode::OK);
+            let uri = format!("/user/get/{user_id}");
+            let (_, user) = call(&app, Method::GET, &uri, None, None).await;
+            assert_eq!(user["online"], true);
+            assert!(user["last_seen"].is_string());
+
+            let (status, _) = call(&app, Method::PUT, "/user/presence", Some(token), hide).await;
+            assert_eq!(status, StatusCode::OK);
+            let (_, user) = call(&app, Method::GET, &uri, None, None).await;
+            assert!(user.get("online").is_none());
+            assert!(user.get("last_seen").is_none());
+
+            // a new login replaces the token
+            let (_, body) = login(&app, &user_id, "secret").await;
+            assert_ne!(body["token"].as_str().unwrap(), token);
+            let (status, _) = call(
+                &app,
+                Method::PUT,
+                "/user/presence",
+                Some(token),
+                Some(json!({ "visible": true })),
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn users_are_searched_by_name_prefixes() {
+        for app in apps().await {
+            let ivan_petrov = register(&app, "Иван", "Петров").await;
+            let ivan_sidorov = register(&app, "Иван", "Сидоров").await;
+            let petr_petrenko = register(&app, "Пётр", "Петренко").await;
+
+            let search = |query: &'static str| {
+                let app = app.clone();
+                async move {
+                    let (status, body) = call(
+                        &app,
+                        Method::GET,
+                        &format!("/user/search?{query}"),
+                        None,
+                        None,
+                    )
+                    .await;
+                    assert_eq!(status, StatusCode::OK);
+                    let mut ids: Vec<String> = body
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|user| user["id"].as_str().unwrap().to_owned())
+                        .collect();
+                    ids.sort();
+                    ids
+                }
+            };
+            let sorted = |mut ids: Vec<&String>| {
+                ids.sort();
+                ids.into_iter().cloned().collect::<Vec<_>>()
+            };
+
+            assert_eq!(
+                search("first_name=%D0%98%D0%B2").await,
+                sorted(vec![&ivan_petrov, &ivan_sidorov])
+            );
+            assert_eq!(
+                search("second_name=%D0%9F%D0%B5%D1%82%D1%80").await,
+                sorted(vec![&ivan_petrov, &petr_petrenko])
+            );
+            assert_eq!(
+                search("first_name=%D0%98%D0%B2&second_name=%D0%9F%D0%B5%D1%82%D1%80").await,
+                vec![ivan_petrov]
+            );
+            assert!(search("first_name=%D0%90%D0%BD%D0%BD%D0%B0")
+                .await
+                .is_empty());
+            assert!(search("first_name=&second_name=").await.is_empty());
+        }
+    }
 }
@@ -1,3 +1,10 @@
+use crate::db;
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
 #[derive(Debug, serde::Serialize, Clone)]
 pub struct Error {
     pub message: String,
@@ -8,3 +15,34 @@ impl From<Error> for serde_json::Value {
         serde_json::to_value(error).unwrap()
     }
 }
+
+pub fn error_response(
+    status: StatusCode,
+    message: impl ToString,
+) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        status,
+        serde_json::Value::from(Error {
+            message: message.to_string(),
+        })
+        .into(),
+    )
+}
+
+/// Response to a failed database call: 503 with Retry-After when the database is
+/// saturated, 500 otherwise.
+pub fn db_error_response(err: anyhow::Error) -> Response {
+    match err.downcast_ref::<db::Saturated>() {
+        Some(saturated) => {
+            let retry_after = saturated.retry_after().as_secs().to_string();
+            let (status, body) = error_response(StatusCode::SERVICE_UNAVAILABLE, saturated);
+            (status, [(header::RETRY_AFTER, retry_after)], body).into_response()
+        }
+        None => error_response(StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct WebSocketParams {
+    pub token: Option<String>,
+}
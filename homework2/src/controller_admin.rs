@@ -0,0 +1,140 @@
+use crate::{controller, controller_auth, db, feed_warmup, shard};
+use axum::{
+    extract::{self, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Secret the /admin routes are called with in the `Authorization: Bearer <token>` header.
+/// Without one configured the routes refuse every request.
+#[derive(Clone)]
+pub struct AdminToken(pub Option<Arc<str>>);
+
+pub async fn authorize(
+    extract::State(AdminToken(token)): extract::State<AdminToken>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = token else {
+        return controller::error_response(
+            StatusCode::FORBIDDEN,
+            "admin API is disabled, the server is run without an admin token",
+        )
+        .into_response();
+    };
+    match controller_auth::bearer_token(request.headers()) {
+        None => controller::error_response(StatusCode::UNAUTHORIZED, "missing bearer token")
+            .into_response(),
+        Some(given) if !same_token(given, &token) => {
+            controller::error_response(StatusCode::FORBIDDEN, "invalid admin token").into_response()
+        }
+        Some(_) => next.run(request).await,
+    }
+}
+
+/// Compares in time independent of where the tokens differ.
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub async fn rebuild_feeds(
+    extract::State(warmup): extract::State<Arc<feed_warmup::FeedWarmup>>,
+) -> impl IntoResponse {
+    match warmup.start_rebuild() {
+        Err(err) => controller::error_response(StatusCode::CONFLICT, err),
+        Ok(progress) => {
+            tracing::info!("feed rebuild started");
+            (
+                StatusCode::ACCEPTED,
+                serde_json::to_value(progress).unwrap().into(),
+            )
+        }
+    }
+}
+
+pub async fn rebuild_feeds_progress(
+    extract::State(warmup): extract::State<Arc<feed_warmup::FeedWarmup>>,
+) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        axum::Json(serde_json::to_value(warmup.progress()).unwrap()),
+    )
+}
+
+/// Which server of the main database takes the writes, how the failover candidates and
+/// the replicas were doing when last checked.
+pub async fn db_topology(extract::State(db): extract::State<Arc<db::DB>>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        axum::Json(serde_json::to_value(db.topology()).unwrap()),
+    )
+}
+
+/// Connection pools of the main database and of the message shards, with the replication
+/// lag of the replicas and the last error met on every server.
+pub async fn db_stats(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(shards): extract::State<Arc<shard::Shards>>,
+) -> impl IntoResponse {
+    let mut message_shards = BTreeMap::new();
+    for (name, shard_db) in shards.all() {
+        // without shards the messages are kept in the main database
+        if !Arc::ptr_eq(shard_db, &db) {
+            message_shards.insert(name.to_owned(), shard_db.stats().await);
+        }
+    }
+    (
+        StatusCode::OK,
+        axum::Json(
+            serde_json::to_value(DbStats {
+                main: db.stats().await,
+                message_shards,
+            })
+            .unwrap(),
+        ),
+    )
+}
+
+pub async fn reshard_status(
+    extract::State(shards): extract::State<Arc<shard::Shards>>,
+) -> impl IntoResponse {
+    match shards.status().await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(status) => (StatusCode::OK, serde_json::to_value(status).unwrap().into()),
+    }
+}
+
+pub async fn reshard_cut_over(
+    extract::State(shards): extract::State<Arc<shard::Shards>>,
+) -> impl IntoResponse {
+    switch_cut_over(&shards, true).await
+}
+
+pub async fn reshard_rollback(
+    extract::State(shards): extract::State<Arc<shard::Shards>>,
+) -> impl IntoResponse {
+    switch_cut_over(&shards, false).await
+}
+
+async fn switch_cut_over(
+    shards: &shard::Shards,
+    cut_over: bool,
+) -> (StatusCode, axum::Json<serde_json::Value>) {
+    match shards.set_cut_over(cut_over).await {
+        Err(err) => controller::error_response(StatusCode::CONFLICT, err),
+        Ok(status) => (StatusCode::OK, serde_json::to_value(status).unwrap().into()),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DbStats {
+    main: Vec<db::PoolStats>,
+    message_shards: BTreeMap<String, Vec<db::PoolStats>>,
+}
@@ -1,7 +1,80 @@
-use crate::{controller, db, db_user, schema};
-use axum::{extract, http::StatusCode, response::IntoResponse, Json};
+use crate::{controller, db_user, presence, schema, user_repository::UserRepository};
+use axum::{
+    extract::{self, FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
 use std::sync::Arc;
 
+/// Authenticated caller, resolved from the `Authorization: Bearer <token>` header
+/// against the token issued by `/login`. The caller is marked as seen just now.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: String,
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    Arc<dyn UserRepository>: FromRef<S>,
+    Arc<presence::Presence>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(&parts.headers).ok_or_else(|| {
+            controller::error_response(StatusCode::UNAUTHORIZED, "missing bearer token")
+                .into_response()
+        })?;
+        let users = Arc::<dyn UserRepository>::from_ref(state);
+        let auth = AuthUser::from_token(token, users.as_ref()).await?;
+        Arc::<presence::Presence>::from_ref(state).touch(&auth.user_id);
+        Ok(auth)
+    }
+}
+
+impl AuthUser {
+    pub async fn from_token(token: &str, users: &dyn UserRepository) -> Result<Self, Response> {
+        match users.user_id_by_token(token).await {
+            Err(err) => Err(controller::db_error_response(err)),
+            Ok(None) => Err(
+                controller::error_response(StatusCode::UNAUTHORIZED, "invalid token")
+                    .into_response(),
+            ),
+            Ok(Some(user_id)) => Ok(AuthUser { user_id }),
+        }
+    }
+
+    /// Browsers can't set headers on WebSocket requests, so the token of a WebSocket
+    /// request may come in the `token` query parameter instead.
+    pub async fn from_websocket_request(
+        headers: &HeaderMap,
+        query_token: Option<&str>,
+        users: &dyn UserRepository,
+    ) -> Result<Self, Response> {
+        match bearer_token(headers).or(query_token) {
+            None => Err(controller::error_response(
+                StatusCode::UNAUTHORIZED,
+                "missing bearer token",
+            )
+            .into_response()),
+            Some(token) => AuthUser::from_token(token, users).await,
+        }
+    }
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
 #[derive(Debug, serde::Deserialize, Clone)]
 struct Login {
     #[serde(rename = "id")]
@@ -27,42 +100,21 @@ fn validate_login_request(payload: &serde_json::Value) -> anyhow::Result<Login>
     Ok(login)
 }
 
-pub async fn login(
-    extract::State(db): extract::State<Arc<db::DB>>,
+pub async fn login<R: UserRepository>(
+    extract::State(users): extract::State<Arc<R>>,
     Json(payload): Json<serde_json::Value>,
 ) -> impl IntoResponse {
-    let response: (StatusCode, Json<serde_json::Value>) = match validate_login_request(&payload) {
-        Err(err) => (
-            StatusCode::BAD_REQUEST,
-            serde_json::Value::from(controller::Error {
-                message: err.to_string(),
-            })
-            .into(),
-        ),
+    let response: Response = match validate_login_request(&payload) {
+        Err(err) => controller::error_response(StatusCode::BAD_REQUEST, err).into_response(),
         Ok(login) => {
-            let mut pg_pool = match db.get().await {
-                Err(err) => {
-                    return (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json::<serde_json::Value>(serde_json::Value::from(controller::Error {
-                            message: err.to_string(),
-                        })),
-                    )
-                }
-                Ok(object) => object,
-            };
-
-            match db_user::User::update_token(&login.user_id, &login.user_password, &mut pg_pool)
+            match users
+                .update_token(&login.user_id, &login.user_password)
                 .await
             {
-                Err(err) => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    serde_json::Value::from(controller::Error {
-                        message: err.to_string(),
-                    })
-                    .into(),
-                ),
-                Ok(result) => update_token_result_to_resonse(&login.user_id, result),
+                Err(err) => controller::db_error_response(err),
+                Ok(result) => {
+                    update_token_result_to_resonse(&login.user_id, result).into_response()
+                }
             }
         }
     };
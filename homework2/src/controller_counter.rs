@@ -0,0 +1,34 @@
+use crate::{controller, controller_auth::AuthUser, db, db_counter};
+use axum::{extract, http::StatusCode, response::IntoResponse};
+use std::sync::Arc;
+use tokio_postgres::GenericClient;
+
+/// Unread messages of the user, per dialog and in total. The counters are updated
+/// asynchronously, so they may lag behind the messages for a moment.
+pub async fn counters(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+) -> impl IntoResponse {
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+    match db_counter::Counter::list(&auth.user_id, pg_pool.client()).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(dialogs) => (
+            StatusCode::OK,
+            serde_json::to_value(CountersResponse {
+                total: dialogs.iter().map(|counter| counter.unread).sum(),
+                dialogs,
+            })
+            .unwrap()
+            .into(),
+        ),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct CountersResponse {
+    total: i64,
+    dialogs: Vec<db_counter::Counter>,
+}
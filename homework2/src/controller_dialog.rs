@@ -0,0 +1,265 @@
+use crate::{
+    controller, controller_auth::AuthUser, counters, db, db_user, dialog, dialog_push, schema,
+    user_repository::UserRepository,
+};
+use axum::{
+    extract::{self, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+const MAX_LIST_LIMIT: usize = 1000;
+const MAX_SEARCH_LIMIT: usize = 100;
+/// Every shard is searched for the whole window, so deep pages are not served.
+const MAX_SEARCH_OFFSET: usize = 1000;
+const MAX_SEARCH_QUERY_LENGTH: usize = 256;
+
+pub async fn send_message(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(store): extract::State<Arc<dialog::Store>>,
+    extract::State(counters): extract::State<Arc<counters::Counters>>,
+    auth: AuthUser,
+    extract::Path(user_id): extract::Path<String>,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_send_message_request(&payload) {
+        Err(err) => return controller::error_response(StatusCode::BAD_REQUEST, err),
+        Ok(request) => request,
+    };
+    if user_id == auth.user_id {
+        return controller::error_response(
+            StatusCode::BAD_REQUEST,
+            "you can't send a message to yourself",
+        );
+    }
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    match db_user::User::exists(&user_id, &pg_pool).await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(false) => return (StatusCode::NOT_FOUND, serde_json::json!({}).into()),
+        Ok(true) => {}
+    }
+    match dialog::send(&store, &auth.user_id, &user_id, &request.text).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(message) => {
+            tracing::debug!(
+                "message {} sent from {} to {}",
+                message.id,
+                auth.user_id,
+                user_id
+            );
+            counters.notify();
+            (
+                StatusCode::CREATED,
+                serde_json::Value::from(SendMessageResponse {
+                    message_id: message.id,
+                })
+                .into(),
+            )
+        }
+    }
+}
+
+/// Lists the dialog newest first with the delivered/read state of every message. The listed
+/// messages sent to the user become delivered, the sender is told so over WebSocket.
+pub async fn list_messages(
+    extract::State(store): extract::State<Arc<dialog::Store>>,
+    extract::State(push): extract::State<Arc<dialog_push::DialogPush>>,
+    auth: AuthUser,
+    extract::Path(user_id): extract::Path<String>,
+    extract::Query(params): extract::Query<ListParams>,
+) -> impl IntoResponse {
+    if params.limit > MAX_LIST_LIMIT {
+        return controller::error_response(
+            StatusCode::BAD_REQUEST,
+            format!("limit must not exceed {}", MAX_LIST_LIMIT),
+        );
+    }
+
+    match dialog::list(&store, &auth.user_id, &user_id, params.offset, params.limit).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok((messages, delivered)) => {
+            if let Some(message_id) = delivered {
+                push.send(
+                    [&user_id],
+                    &dialog_push::Event::Delivered {
+                        user_id: auth.user_id.clone(),
+                        message_id,
+                    },
+                );
+            }
+            (
+                StatusCode::OK,
+                serde_json::to_value(messages).unwrap().into(),
+            )
+        }
+    }
+}
+
+/// Finds messages of the caller's own dialogs by words of their text.
+pub async fn search_messages(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(store): extract::State<Arc<dialog::Store>>,
+    auth: AuthUser,
+    extract::Query(params): extract::Query<SearchParams>,
+) -> impl IntoResponse {
+    let query = params.q.trim();
+    if query.is_empty() || query.chars().count() > MAX_SEARCH_QUERY_LENGTH {
+        return controller::error_response(
+            StatusCode::BAD_REQUEST,
+            format!("q must have 1 to {} characters", MAX_SEARCH_QUERY_LENGTH),
+        );
+    }
+    if params.limit > MAX_SEARCH_LIMIT || params.offset > MAX_SEARCH_OFFSET {
+        return controller::error_response(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "limit must not exceed {} and offset must not exceed {}",
+                MAX_SEARCH_LIMIT, MAX_SEARCH_OFFSET
+            ),
+        );
+    }
+
+    match dialog::search(
+        &store,
+        &db,
+        &auth.user_id,
+        query,
+        params.offset,
+        params.limit,
+    )
+    .await
+    {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(found) => (StatusCode::OK, serde_json::to_value(found).unwrap().into()),
+    }
+}
+
+/// Marks the messages sent to the user up to `message_id` as read, the sender is told
+/// so over WebSocket.
+pub async fn read_messages(
+    extract::State(store): extract::State<Arc<dialog::Store>>,
+    extract::State(counters): extract::State<Arc<counters::Counters>>,
+    extract::State(push): extract::State<Arc<dialog_push::DialogPush>>,
+    auth: AuthUser,
+    extract::Path(user_id): extract::Path<String>,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_read_messages_request(&payload) {
+        Err(err) => return controller::error_response(StatusCode::BAD_REQUEST, err),
+        Ok(request) => request,
+    };
+    if user_id == auth.user_id {
+        return controller::error_response(
+            StatusCode::BAD_REQUEST,
+            "you can't read a dialog with yourself",
+        );
+    }
+
+    match dialog::mark_read(&store, &auth.user_id, &user_id, request.message_id).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(read) => {
+            if let Some(message_id) = read {
+                counters.notify();
+                push.send(
+                    [&user_id],
+                    &dialog_push::Event::Read {
+                        user_id: auth.user_id.clone(),
+                        message_id,
+                    },
+                );
+            }
+            (
+                StatusCode::OK,
+                serde_json::json!({ "last_read_id": read }).into(),
+            )
+        }
+    }
+}
+
+/// Streams delivered/read receipts and typing indicators of the caller's dialogs, and
+/// forwards the caller's typing indicators. Authenticated the same way as the feed stream.
+pub async fn dialog_events(
+    extract::State(users): extract::State<Arc<dyn UserRepository>>,
+    extract::State(push): extract::State<Arc<dialog_push::DialogPush>>,
+    extract::Query(params): extract::Query<controller::WebSocketParams>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> axum::response::Response {
+    let auth =
+        match AuthUser::from_websocket_request(&headers, params.token.as_deref(), users.as_ref())
+            .await
+        {
+            Err(response) => return response.into_response(),
+            Ok(auth) => auth,
+        };
+    ws.on_upgrade(move |socket| async move { push.serve(auth.user_id, socket).await })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_list_limit")]
+    limit: usize,
+}
+
+fn default_list_limit() -> usize {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    q: String,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+struct SendMessageRequest {
+    text: String,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+struct SendMessageResponse {
+    message_id: i64,
+}
+
+impl From<SendMessageResponse> for serde_json::Value {
+    fn from(message: SendMessageResponse) -> Self {
+        serde_json::to_value(message).unwrap()
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+struct ReadMessagesRequest {
+    message_id: i64,
+}
+
+fn validate_read_messages_request(
+    payload: &serde_json::Value,
+) -> anyhow::Result<ReadMessagesRequest> {
+    schema::validate(payload, &schema::DIALOG_READ)?;
+    let request: ReadMessagesRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(request)
+}
+
+fn validate_send_message_request(
+    payload: &serde_json::Value,
+) -> anyhow::Result<SendMessageRequest> {
+    schema::validate(payload, &schema::DIALOG_SEND)?;
+    let message: SendMessageRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(message)
+}
@@ -0,0 +1,57 @@
+use crate::{controller, controller_auth::AuthUser, db, db_friend, db_user, feed};
+use axum::{extract, http::StatusCode, response::IntoResponse};
+use std::sync::Arc;
+use tokio_postgres::GenericClient;
+
+pub async fn set_friend(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(feed): extract::State<Arc<feed::FeedCache>>,
+    auth: AuthUser,
+    extract::Path(friend_id): extract::Path<String>,
+) -> impl IntoResponse {
+    if friend_id == auth.user_id {
+        return controller::error_response(
+            StatusCode::BAD_REQUEST,
+            "you can't add yourself as a friend",
+        );
+    }
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    match db_user::User::exists(&friend_id, &pg_pool).await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(false) => return (StatusCode::NOT_FOUND, serde_json::json!({}).into()),
+        Ok(true) => {}
+    }
+    match db_friend::Friend::add(&auth.user_id, &friend_id, pg_pool.client()).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(()) => {
+            feed.invalidate(&auth.user_id);
+            tracing::info!("user {} added friend {}", auth.user_id, friend_id);
+            (StatusCode::OK, serde_json::json!({}).into())
+        }
+    }
+}
+
+pub async fn delete_friend(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(feed): extract::State<Arc<feed::FeedCache>>,
+    auth: AuthUser,
+    extract::Path(friend_id): extract::Path<String>,
+) -> impl IntoResponse {
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    match db_friend::Friend::delete(&auth.user_id, &friend_id, pg_pool.client()).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(()) => {
+            feed.invalidate(&auth.user_id);
+            tracing::info!("user {} deleted friend {}", auth.user_id, friend_id);
+            (StatusCode::OK, serde_json::json!({}).into())
+        }
+    }
+}
@@ -0,0 +1,550 @@
+use crate::{
+    controller,
+    controller_auth::AuthUser,
+    db,
+    db_group::{Group, GroupMessage, Member, Role},
+    dialog_push, schema,
+};
+use axum::{extract, http::StatusCode, response::IntoResponse, Json};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio_postgres::GenericClient;
+
+const MAX_LIST_LIMIT: usize = 1000;
+
+type ErrorResponse = (StatusCode, Json<serde_json::Value>);
+
+/// Creates a group administered by the caller, with the given users as its members.
+pub async fn create_group(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_create_group_request(&payload) {
+        Err(err) => return controller::error_response(StatusCode::BAD_REQUEST, err),
+        Ok(request) => request,
+    };
+    let mut pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    let members: Vec<String> = request
+        .members
+        .into_iter()
+        .filter(|user_id| *user_id != auth.user_id)
+        .collect();
+    let group = Group {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request.name,
+        created_by: Some(auth.user_id.clone()),
+    };
+    let result = async {
+        let transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("transaction error: {}", e))?;
+        let group = group.insert_to_db(transaction.client()).await?;
+        Member::add(
+            &group.id,
+            std::slice::from_ref(&auth.user_id),
+            Role::Admin,
+            transaction.client(),
+        )
+        .await?;
+        let found = Member::add(&group.id, &members, Role::Member, transaction.client()).await?;
+        if found < members.len() as u64 {
+            // rolled back on drop
+            return anyhow::Ok(None);
+        }
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))?;
+        anyhow::Ok(Some(group))
+    }
+    .await;
+    match result {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(None) => controller::error_response(StatusCode::NOT_FOUND, "unknown members"),
+        Ok(Some(group)) => {
+            tracing::info!(
+                "a new group with ID {} created by user {}",
+                group.id,
+                auth.user_id
+            );
+            (
+                StatusCode::CREATED,
+                serde_json::json!({ "group_id": group.id }).into(),
+            )
+        }
+    }
+}
+
+/// Groups the caller is a member of.
+pub async fn list_groups(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+) -> impl IntoResponse {
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    match Group::of_user(&auth.user_id, pg_pool.client()).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(groups) => (StatusCode::OK, serde_json::to_value(groups).unwrap().into()),
+    }
+}
+
+pub async fn get_group(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+    extract::Path(group_id): extract::Path<String>,
+) -> impl IntoResponse {
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    let group = match check_member(&group_id, &auth, pg_pool.client()).await {
+        Err(response) => return response,
+        Ok((group, _)) => group,
+    };
+    match Member::list(&group_id, pg_pool.client()).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(members) => (
+            StatusCode::OK,
+            serde_json::json!({
+                "id": group.id,
+                "name": group.name,
+                "created_by": group.created_by,
+                "members": members,
+            })
+            .into(),
+        ),
+    }
+}
+
+pub async fn rename_group(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+    extract::Path(group_id): extract::Path<String>,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_rename_group_request(&payload) {
+        Err(err) => return controller::error_response(StatusCode::BAD_REQUEST, err),
+        Ok(request) => request,
+    };
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    if let Err(response) = check_admin(&group_id, &auth, pg_pool.client()).await {
+        return response;
+    }
+    match Group::rename(&group_id, &request.name, pg_pool.client()).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(()) => {
+            tracing::info!("group {} renamed by user {}", group_id, auth.user_id);
+            (StatusCode::OK, serde_json::json!({}).into())
+        }
+    }
+}
+
+pub async fn add_member(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+    extract::Path((group_id, user_id)): extract::Path<(String, String)>,
+) -> impl IntoResponse {
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    if let Err(response) = check_admin(&group_id, &auth, pg_pool.client()).await {
+        return response;
+    }
+    match Member::add(
+        &group_id,
+        std::slice::from_ref(&user_id),
+        Role::Member,
+        pg_pool.client(),
+    )
+    .await
+    {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(0) => (StatusCode::NOT_FOUND, serde_json::json!({}).into()),
+        Ok(_) => {
+            tracing::info!(
+                "user {} added {} to group {}",
+                auth.user_id,
+                user_id,
+                group_id
+            );
+            (StatusCode::OK, serde_json::json!({}).into())
+        }
+    }
+}
+
+/// Admins may remove anyone, members may only leave. The last admin can't leave while
+/// there are other members; the group is deleted when its last member leaves.
+pub async fn remove_member(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+    extract::Path((group_id, user_id)): extract::Path<(String, String)>,
+) -> impl IntoResponse {
+    let mut pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+    let transaction = match pg_pool.transaction().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(transaction) => transaction,
+    };
+
+    let members = match lock_members(&group_id, &auth, transaction.client()).await {
+        Err(response) => return response,
+        Ok(members) => members,
+    };
+    let caller = members.iter().find(|m| m.user_id == auth.user_id).unwrap();
+    if caller.role != Role::Admin && user_id != auth.user_id {
+        return controller::error_response(
+            StatusCode::FORBIDDEN,
+            "only an admin can remove other members",
+        );
+    }
+    let Some(removed) = members.iter().find(|m| m.user_id == user_id) else {
+        return (StatusCode::NOT_FOUND, serde_json::json!({}).into());
+    };
+    if members.len() > 1 && is_last_admin(&members, removed) {
+        return controller::error_response(
+            StatusCode::CONFLICT,
+            "the last admin can't leave the group, make another member admin first",
+        );
+    }
+
+    let result = async {
+        if members.len() == 1 {
+            Group::delete(&group_id, transaction.client()).await?;
+        } else {
+            Member::remove(&group_id, &user_id, transaction.client()).await?;
+        }
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))
+    }
+    .await;
+    match result {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(()) => {
+            tracing::info!(
+                "user {} removed {} from group {}",
+                auth.user_id,
+                user_id,
+                group_id
+            );
+            (StatusCode::OK, serde_json::json!({}).into())
+        }
+    }
+}
+
+/// Only admins change roles, and a group never stays without an admin.
+pub async fn set_role(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+    extract::Path((group_id, user_id)): extract::Path<(String, String)>,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_set_role_request(&payload) {
+        Err(err) => return controller::error_response(StatusCode::BAD_REQUEST, err),
+        Ok(request) => request,
+    };
+    let mut pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+    let transaction = match pg_pool.transaction().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(transaction) => transaction,
+    };
+
+    let members = match lock_members(&group_id, &auth, transaction.client()).await {
+        Err(response) => return response,
+        Ok(members) => members,
+    };
+    let caller = members.iter().find(|m| m.user_id == auth.user_id).unwrap();
+    if caller.role != Role::Admin {
+        return controller::error_response(StatusCode::FORBIDDEN, "only an admin can change roles");
+    }
+    let Some(member) = members.iter().find(|m| m.user_id == user_id) else {
+        return (StatusCode::NOT_FOUND, serde_json::json!({}).into());
+    };
+    if request.role == Role::Member && is_last_admin(&members, member) {
+        return controller::error_response(
+            StatusCode::CONFLICT,
+            "the group must have an admin, make another member admin first",
+        );
+    }
+
+    let result = async {
+        Member::set_role(&group_id, &user_id, request.role, transaction.client()).await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))
+    }
+    .await;
+    match result {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(()) => {
+            tracing::info!(
+                "user {} made {} {:?} of group {}",
+                auth.user_id,
+                user_id,
+                request.role,
+                group_id
+            );
+            (StatusCode::OK, serde_json::json!({}).into())
+        }
+    }
+}
+
+/// Stores the message and pushes it to the connected members of the group.
+pub async fn send_message(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(push): extract::State<Arc<dialog_push::DialogPush>>,
+    auth: AuthUser,
+    extract::Path(group_id): extract::Path<String>,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_send_message_request(&payload) {
+        Err(err) => return controller::error_response(StatusCode::BAD_REQUEST, err),
+        Ok(request) => request,
+    };
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    if let Err(response) = check_member(&group_id, &auth, pg_pool.client()).await {
+        return response;
+    }
+    let message =
+        match GroupMessage::insert_to_db(&group_id, &auth.user_id, &request.text, pg_pool.client())
+            .await
+        {
+            Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+            Ok(message) => message,
+        };
+    tracing::debug!(
+        "message {} sent from {} to group {}",
+        message.id,
+        auth.user_id,
+        group_id
+    );
+
+    match Member::list(&group_id, pg_pool.client()).await {
+        Ok(members) => push.send(
+            members
+                .iter()
+                .map(|member| &member.user_id)
+                .filter(|user_id| **user_id != auth.user_id),
+            &dialog_push::Event::GroupMessage {
+                group_id: group_id.clone(),
+                user_id: auth.user_id.clone(),
+                message_id: message.id,
+                text: message.text.clone(),
+            },
+        ),
+        Err(err) => tracing::error!(
+            "failed to push message {} of group {}: {err:?}",
+            message.id,
+            group_id
+        ),
+    }
+    (
+        StatusCode::CREATED,
+        serde_json::json!({ "message_id": message.id }).into(),
+    )
+}
+
+/// Lists the messages of the group newest first.
+pub async fn list_messages(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    auth: AuthUser,
+    extract::Path(group_id): extract::Path<String>,
+    extract::Query(params): extract::Query<ListParams>,
+) -> impl IntoResponse {
+    if params.limit > MAX_LIST_LIMIT {
+        return controller::error_response(
+            StatusCode::BAD_REQUEST,
+            format!("limit must not exceed {}", MAX_LIST_LIMIT),
+        );
+    }
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    if let Err(response) = check_member(&group_id, &auth, pg_pool.client()).await {
+        return response;
+    }
+    match GroupMessage::list(&group_id, params.offset, params.limit, pg_pool.client()).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(messages) => (
+            StatusCode::OK,
+            serde_json::to_value(messages).unwrap().into(),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_list_limit")]
+    limit: usize,
+}
+
+fn default_list_limit() -> usize {
+    100
+}
+
+/// Only members may see a group: answers 404 for unknown groups and 403 for foreign ones.
+async fn check_member(
+    group_id: &String,
+    auth: &AuthUser,
+    client: &tokio_postgres::Client,
+) -> Result<(Group, Member), ErrorResponse> {
+    let group = match Group::from_id(group_id, client).await {
+        Err(err) => {
+            return Err(controller::error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err,
+            ))
+        }
+        Ok(None) => return Err((StatusCode::NOT_FOUND, serde_json::json!({}).into())),
+        Ok(Some(group)) => group,
+    };
+    match Member::get(group_id, &auth.user_id, client).await {
+        Err(err) => Err(controller::error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            err,
+        )),
+        Ok(None) => {
+            tracing::error!(
+                "user with id '{}' has been tried to access group '{}'",
+                auth.user_id,
+                group_id
+            );
+            Err(controller::error_response(
+                StatusCode::FORBIDDEN,
+                "only members can access the group",
+            ))
+        }
+        Ok(Some(member)) => Ok((group, member)),
+    }
+}
+
+async fn check_admin(
+    group_id: &String,
+    auth: &AuthUser,
+    client: &tokio_postgres::Client,
+) -> Result<(), ErrorResponse> {
+    match check_member(group_id, auth, client).await? {
+        (_, member) if member.role == Role::Admin => Ok(()),
+        _ => Err(controller::error_response(
+            StatusCode::FORBIDDEN,
+            "only an admin can manage the group",
+        )),
+    }
+}
+
+/// Locks the group for the rest of the transaction and reads its members, checking the
+/// caller is one of them.
+async fn lock_members(
+    group_id: &String,
+    auth: &AuthUser,
+    client: &tokio_postgres::Client,
+) -> Result<Vec<Member>, ErrorResponse> {
+    match Group::lock(group_id, client).await {
+        Err(err) => {
+            return Err(controller::error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err,
+            ))
+        }
+        Ok(false) => return Err((StatusCode::NOT_FOUND, serde_json::json!({}).into())),
+        Ok(true) => {}
+    }
+    let members = Member::list(group_id, client)
+        .await
+        .map_err(|err| controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    if !members.iter().any(|m| m.user_id == auth.user_id) {
+        return Err(controller::error_response(
+            StatusCode::FORBIDDEN,
+            "only members can access the group",
+        ));
+    }
+    Ok(members)
+}
+
+fn is_last_admin(members: &[Member], member: &Member) -> bool {
+    member.role == Role::Admin && members.iter().filter(|m| m.role == Role::Admin).count() == 1
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+struct CreateGroupRequest {
+    name: String,
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+struct RenameGroupRequest {
+    name: String,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+struct SetRoleRequest {
+    role: Role,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+struct SendMessageRequest {
+    text: String,
+}
+
+fn validate_create_group_request(
+    payload: &serde_json::Value,
+) -> anyhow::Result<CreateGroupRequest> {
+    schema::validate(payload, &schema::GROUP_CREATE)?;
+    let request: CreateGroupRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(request)
+}
+
+fn validate_rename_group_request(
+    payload: &serde_json::Value,
+) -> anyhow::Result<RenameGroupRequest> {
+    schema::validate(payload, &schema::GROUP_RENAME)?;
+    let request: RenameGroupRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(request)
+}
+
+fn validate_set_role_request(payload: &serde_json::Value) -> anyhow::Result<SetRoleRequest> {
+    schema::validate(payload, &schema::GROUP_ROLE)?;
+    let request: SetRoleRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(request)
+}
+
+/// Group messages share the limits of dialog messages.
+fn validate_send_message_request(
+    payload: &serde_json::Value,
+) -> anyhow::Result<SendMessageRequest> {
+    schema::validate(payload, &schema::DIALOG_SEND)?;
+    let request: SendMessageRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(request)
+}
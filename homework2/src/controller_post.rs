@@ -0,0 +1,301 @@
+use crate::{
+    controller, controller_auth::AuthUser, db, db_feed_event, db_post, feed, feed_push, feed_queue,
+    schema, user_repository::UserRepository,
+};
+use axum::{
+    extract::{self, ws::WebSocketUpgrade},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio_postgres::GenericClient;
+
+pub async fn create_post(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(queue): extract::State<Arc<feed_queue::FeedQueue>>,
+    auth: AuthUser,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_create_post_request(&payload) {
+        Err(err) => return controller::error_response(StatusCode::BAD_REQUEST, err),
+        Ok(request) => request,
+    };
+    let mut pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    let post = db_post::Post {
+        id: uuid::Uuid::new_v4().to_string(),
+        text: request.text,
+        author_user_id: auth.user_id,
+    };
+    let result = async {
+        let transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("transaction error: {}", e))?;
+        let post = post.insert_to_db(transaction.client()).await?;
+        db_feed_event::FeedEvent::enqueue(
+            db_feed_event::Kind::Created,
+            &post,
+            transaction.client(),
+        )
+        .await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))?;
+        anyhow::Ok(post)
+    }
+    .await;
+    match result {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(post) => {
+            tracing::info!(
+                "a new post with ID {} created by user {}",
+                post.id,
+                post.author_user_id
+            );
+            queue.notify();
+            (
+                StatusCode::CREATED,
+                serde_json::Value::from(CreatePostResponse { post_id: post.id }).into(),
+            )
+        }
+    }
+}
+
+pub async fn update_post(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(queue): extract::State<Arc<feed_queue::FeedQueue>>,
+    auth: AuthUser,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_update_post_request(&payload) {
+        Err(err) => return controller::error_response(StatusCode::BAD_REQUEST, err),
+        Ok(request) => request,
+    };
+    let mut pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    let post = match check_author(&request.id, &auth, pg_pool.client()).await {
+        Err(response) => return response,
+        Ok(post) => post,
+    };
+    let post = db_post::Post {
+        text: request.text,
+        ..post
+    };
+    let result = async {
+        let transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("transaction error: {}", e))?;
+        db_post::Post::update_text(&post.id, &post.text, transaction.client()).await?;
+        db_feed_event::FeedEvent::enqueue(
+            db_feed_event::Kind::Updated,
+            &post,
+            transaction.client(),
+        )
+        .await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))?;
+        anyhow::Ok(())
+    }
+    .await;
+    match result {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(()) => {
+            tracing::info!("post with ID {} updated", post.id);
+            queue.notify();
+            (StatusCode::OK, serde_json::json!({}).into())
+        }
+    }
+}
+
+pub async fn delete_post(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(queue): extract::State<Arc<feed_queue::FeedQueue>>,
+    auth: AuthUser,
+    extract::Path(id): extract::Path<String>,
+) -> impl IntoResponse {
+    let mut pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    let post = match check_author(&id, &auth, pg_pool.client()).await {
+        Err(response) => return response,
+        Ok(post) => post,
+    };
+    let result = async {
+        let transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("transaction error: {}", e))?;
+        db_post::Post::delete(&post.id, transaction.client()).await?;
+        db_feed_event::FeedEvent::enqueue(
+            db_feed_event::Kind::Deleted,
+            &post,
+            transaction.client(),
+        )
+        .await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))?;
+        anyhow::Ok(())
+    }
+    .await;
+    match result {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(()) => {
+            tracing::info!("post with ID {} deleted", id);
+            queue.notify();
+            (StatusCode::OK, serde_json::json!({}).into())
+        }
+    }
+}
+
+pub async fn get_post(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    _auth: AuthUser,
+    extract::Path(id): extract::Path<String>,
+) -> impl IntoResponse {
+    let pg_pool = match db.get().await {
+        Err(err) => return controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(object) => object,
+    };
+
+    match db_post::Post::from_id(&id, pg_pool.client()).await {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(Some(post)) => (StatusCode::OK, serde_json::to_value(post).unwrap().into()),
+        Ok(None) => (StatusCode::NOT_FOUND, serde_json::json!({}).into()),
+    }
+}
+
+pub async fn feed(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    extract::State(feed): extract::State<Arc<feed::FeedCache>>,
+    auth: AuthUser,
+    extract::Query(params): extract::Query<FeedParams>,
+) -> impl IntoResponse {
+    tracing::debug!("feed query: {params:?}");
+    if params.offset.saturating_add(params.limit) > feed::FEED_LIMIT {
+        return controller::error_response(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "only the latest {} posts are in the feed, offset + limit must not exceed it",
+                feed::FEED_LIMIT
+            ),
+        );
+    }
+
+    match feed
+        .get(&auth.user_id, params.offset, params.limit, &db)
+        .await
+    {
+        Err(err) => controller::error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Ok(posts) => (StatusCode::OK, serde_json::to_value(posts).unwrap().into()),
+    }
+}
+
+/// Streams new posts of the caller's friends. Browsers can't set headers on WebSocket
+/// requests, so the login token may also be passed as the `token` query parameter.
+pub async fn feed_posted(
+    extract::State(users): extract::State<Arc<dyn UserRepository>>,
+    extract::State(push): extract::State<Arc<feed_push::FeedPush>>,
+    extract::Query(params): extract::Query<controller::WebSocketParams>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> axum::response::Response {
+    let auth =
+        match AuthUser::from_websocket_request(&headers, params.token.as_deref(), users.as_ref())
+            .await
+        {
+            Err(response) => return response.into_response(),
+            Ok(auth) => auth,
+        };
+    ws.on_upgrade(move |socket| async move { push.serve(auth.user_id, socket).await })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeedParams {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_feed_limit")]
+    limit: usize,
+}
+
+fn default_feed_limit() -> usize {
+    10
+}
+
+/// Only the author may modify a post: answers 404 for unknown posts and 403 for foreign ones.
+async fn check_author(
+    post_id: &String,
+    auth: &AuthUser,
+    client: &tokio_postgres::Client,
+) -> Result<db_post::Post, (StatusCode, Json<serde_json::Value>)> {
+    match db_post::Post::from_id(post_id, client).await {
+        Err(err) => Err(controller::error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            err,
+        )),
+        Ok(None) => Err((StatusCode::NOT_FOUND, serde_json::json!({}).into())),
+        Ok(Some(post)) if post.author_user_id != auth.user_id => {
+            tracing::error!(
+                "user with id '{}' has been tried to modify post '{}' of user '{}'",
+                auth.user_id,
+                post.id,
+                post.author_user_id
+            );
+            Err(controller::error_response(
+                StatusCode::FORBIDDEN,
+                "only the author can modify the post",
+            ))
+        }
+        Ok(Some(post)) => Ok(post),
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+struct CreatePostRequest {
+    text: String,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+struct UpdatePostRequest {
+    id: String,
+    text: String,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+struct CreatePostResponse {
+    post_id: String,
+}
+
+impl From<CreatePostResponse> for serde_json::Value {
+    fn from(post: CreatePostResponse) -> Self {
+        serde_json::to_value(post).unwrap()
+    }
+}
+
+fn validate_create_post_request(payload: &serde_json::Value) -> anyhow::Result<CreatePostRequest> {
+    schema::validate(payload, &schema::POST_CREATE)?;
+    let post: CreatePostRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(post)
+}
+
+fn validate_update_post_request(payload: &serde_json::Value) -> anyhow::Result<UpdatePostRequest> {
+    schema::validate(payload, &schema::POST_UPDATE)?;
+    let post: UpdatePostRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(post)
+}
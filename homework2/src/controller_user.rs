@@ -1,121 +1,131 @@
-use crate::{controller, db, db_user, password::hash_password, schema};
-use axum::{extract, http::StatusCode, response::IntoResponse, Json};
+use crate::{
+    controller, controller_auth::AuthUser, db_user, password::hash_password, presence,
+    read_your_writes::MinLsn, schema, user_repository::UserRepository,
+};
+use axum::{
+    extract,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
 use chrono::Datelike;
 use serde::{Deserialize, Deserializer};
 use std::sync::Arc;
-use tokio_postgres::GenericClient;
 
-pub async fn create_user(
-    extract::State(db): extract::State<Arc<db::DB>>,
+pub async fn create_user<R: UserRepository>(
+    extract::State(users): extract::State<Arc<R>>,
     Json(payload): Json<serde_json::Value>,
 ) -> impl IntoResponse {
-    let response: (StatusCode, Json<serde_json::Value>) =
-        match validate_create_user_request(&payload) {
-            Err(err) => (
-                StatusCode::BAD_REQUEST,
-                serde_json::Value::from(controller::Error {
-                    message: err.to_string(),
-                })
-                .into(),
-            ),
-            Ok(user) => {
-                let user: db_user::User = user.into();
-                match user.insert_to_db(&db).await {
-                    Err(err) => (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        serde_json::Value::from(controller::Error {
-                            message: err.to_string(),
-                        })
-                        .into(),
-                    ),
-                    Ok(user_id) => {
-                        tracing::info!("a new user registered with ID {}", user_id);
-                        (
-                            StatusCode::CREATED,
-                            serde_json::Value::from(CreateUserResponse { user_id }).into(),
-                        )
-                    }
+    let response: Response = match validate_create_user_request(&payload) {
+        Err(err) => controller::error_response(StatusCode::BAD_REQUEST, err).into_response(),
+        Ok(user) => {
+            let user: db_user::User = user.into();
+            match users.insert(user).await {
+                Err(err) => controller::db_error_response(err),
+                Ok(user_id) => {
+                    tracing::info!("a new user registered with ID {}", user_id);
+                    (
+                        StatusCode::CREATED,
+                        Json(serde_json::Value::from(CreateUserResponse { user_id })),
+                    )
+                        .into_response()
                 }
             }
-        };
+        }
+    };
 
     response
 }
 
-pub async fn get_user(
-    extract::State(db): extract::State<Arc<db::DB>>,
+pub async fn get_user<R: UserRepository>(
+    extract::State(users): extract::State<Arc<R>>,
+    extract::State(presence): extract::State<Arc<presence::Presence>>,
     extract::Path(id): extract::Path<String>,
+    min_lsn: MinLsn,
 ) -> impl IntoResponse {
-    let pg_pool = match db.get().await {
-        Err(err) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json::<serde_json::Value>(serde_json::Value::from(controller::Error {
-                    message: err.to_string(),
-                })),
-            )
-        }
-        Ok(object) => object,
+    let response: Response = match users.get(&id, min_lsn.0).await {
+        Err(err) => controller::db_error_response(err),
+        Ok(user) => match user {
+            Some(dbuser) => {
+                let ids = [dbuser.id.clone()];
+                match presence.statuses(&ids, min_lsn.0).await {
+                    Err(err) => controller::db_error_response(err),
+                    Ok(mut statuses) => {
+                        let mut user = GetUser::from(dbuser);
+                        user.presence = statuses.remove(&user.id).unwrap_or_default();
+                        (StatusCode::OK, Json(serde_json::Value::from(user))).into_response()
+                    }
+                }
+            }
+            None => (StatusCode::NOT_FOUND, Json(serde_json::json!({}))).into_response(),
+        },
     };
 
-    let response: (StatusCode, Json<serde_json::Value>) =
-        match db_user::User::from_id(&id, pg_pool.client()).await {
-            Err(err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                serde_json::Value::from(controller::Error {
-                    message: err.to_string(),
-                })
-                .into(),
-            ),
-            Ok(user) => match user {
-                Some(dbuser) => (
-                    StatusCode::OK,
-                    serde_json::Value::from(GetUser::from(dbuser)).into(),
-                ),
-                None => (StatusCode::NOT_FOUND, serde_json::json!({}).into()),
-            },
-        };
-
     response
 }
 
-pub async fn search_user(
-    extract::State(db): extract::State<Arc<db::DB>>,
+pub async fn search_user<R: UserRepository>(
+    extract::State(users): extract::State<Arc<R>>,
+    extract::State(presence): extract::State<Arc<presence::Presence>>,
     extract::Query(params): extract::Query<UserSearchParams>,
+    min_lsn: MinLsn,
 ) -> impl IntoResponse {
     tracing::debug!("search query: {params:?}");
-    let pg_pool = match db.get().await {
-        Err(err) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json::<serde_json::Value>(serde_json::Value::from(controller::Error {
-                    message: err.to_string(),
-                })),
-            )
-        }
-        Ok(object) => object,
-    };
-
-    let response: (StatusCode, Json<serde_json::Value>) = match db_user::User::search(
-        pg_pool.client(),
-        params.first_name.as_ref(),
-        params.second_name.as_ref(),
-    )
-    .await
+    let response: Response = match users
+        .search(
+            params.first_name.as_ref(),
+            params.second_name.as_ref(),
+            min_lsn.0,
+        )
+        .await
     {
-        Ok(result) => (StatusCode::OK, serde_json::to_value(result).unwrap().into()),
-        Err(err) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            serde_json::Value::from(controller::Error {
-                message: err.to_string(),
-            })
-            .into(),
-        ),
+        Ok(profiles) => {
+            let ids: Vec<String> = profiles.iter().map(|user| user.id.clone()).collect();
+            match presence.statuses(&ids, min_lsn.0).await {
+                Err(err) => controller::db_error_response(err),
+                Ok(mut statuses) => {
+                    let mut result: Vec<db_user::SearchResult> = profiles
+                        .into_iter()
+                        .map(db_user::SearchResult::from)
+                        .collect();
+                    for user in result.iter_mut() {
+                        user.presence = statuses.remove(&user.id).unwrap_or_default();
+                    }
+                    (StatusCode::OK, Json(serde_json::to_value(result).unwrap())).into_response()
+                }
+            }
+        }
+        Err(err) => controller::db_error_response(err),
     };
 
     response
 }
 
+/// Shows or hides the caller's online status and last-seen time from the others.
+pub async fn set_presence<R: UserRepository>(
+    extract::State(users): extract::State<Arc<R>>,
+    auth: AuthUser,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let request = match validate_presence_request(&payload) {
+        Err(err) => {
+            return controller::error_response(StatusCode::BAD_REQUEST, err).into_response()
+        }
+        Ok(request) => request,
+    };
+    match users
+        .set_presence_hidden(&auth.user_id, !request.visible)
+        .await
+    {
+        Err(err) => controller::db_error_response(err),
+        Ok(()) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "visible": request.visible })),
+        )
+            .into_response(),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UserSearchParams {
     #[serde(default, deserialize_with = "empty_string_as_none")]
@@ -149,6 +159,11 @@ struct CreateUserRequest {
     password: String,
 }
 
+#[derive(Debug, serde::Deserialize, Clone)]
+struct PresenceRequest {
+    visible: bool,
+}
+
 #[derive(Debug, serde::Serialize, Clone)]
 struct CreateUserResponse {
     user_id: String,
@@ -161,6 +176,12 @@ fn validate_create_user_request(payload: &serde_json::Value) -> anyhow::Result<C
     Ok(user)
 }
 
+fn validate_presence_request(payload: &serde_json::Value) -> anyhow::Result<PresenceRequest> {
+    schema::validate(payload, &schema::USER_PRESENCE)?;
+    let request: PresenceRequest = serde_json::from_value(payload.clone()).unwrap();
+    Ok(request)
+}
+
 impl CreateUserRequest {
     fn validate(&self) -> anyhow::Result<()> {
         let date_18_old = chrono::Local::now() - chrono::Months::new(12 * 18);
@@ -192,10 +213,12 @@ struct GetUser {
     biography: String,
     birthdate: String,
     city: String,
+    #[serde(flatten)]
+    presence: presence::Status,
 }
 
-impl From<db_user::User> for GetUser {
-    fn from(user: db_user::User) -> Self {
+impl From<db_user::Profile> for GetUser {
+    fn from(user: db_user::Profile) -> Self {
         Self {
             id: user.id,
             first_name: user.first_name,
@@ -203,6 +226,7 @@ impl From<db_user::User> for GetUser {
             birthdate: user.birthdate,
             biography: user.biography,
             city: user.city,
+            presence: presence::Status::default(),
         }
     }
 }
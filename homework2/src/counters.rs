@@ -0,0 +1,463 @@
+use crate::{
+    db,
+    db_counter::Counter,
+    db_message,
+    db_outbox::{Kind, OutboxEntry},
+    db_user, dialog, memory_store, shard,
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Notify;
+use tokio_postgres::GenericClient;
+
+/// Outbox entries relayed per transaction; also the size of reconciliation batches.
+const BATCH_SIZE: usize = 100;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Delay before the first retry of a failed outbox entry, doubled with every next one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+/// Failed deliveries of an outbox entry before it is parked, unless the failures are transient.
+const MAX_ATTEMPTS: i32 = 10;
+
+enum Delivery {
+    Applied,
+    /// Redelivery of an entry whose change has been counted already.
+    Duplicate,
+    /// The change must never be counted, the reason is given.
+    Rejected(String),
+}
+
+/// Keeps the unread message counters in the main database up to date.
+///
+/// Sending a message or reading a dialog is a saga: the message change and an outbox entry
+/// are committed together on the message shard, the relay then applies the entry to the
+/// counters under its idempotency key, so a redelivered entry is never counted twice.
+/// An entry the counters reject is compensated by rolling the message change back, an entry
+/// failing for any other reason is retried and, unless the failures are transient, parked
+/// after a while. The periodic reconciliation recomputes the counters from the messages and fixes
+/// whatever drift remains. Messages kept in memory update the counters themselves
+/// and are only reconciled.
+pub struct Counters {
+    db: Arc<db::DB>,
+    store: Arc<dialog::Store>,
+    reconcile_interval: Duration,
+    notify: Notify,
+}
+
+impl Counters {
+    pub fn new(db: Arc<db::DB>, store: Arc<dialog::Store>, reconcile_interval: Duration) -> Self {
+        Self {
+            db,
+            store,
+            reconcile_interval,
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn spawn_workers(self: &Arc<Self>) {
+        if let dialog::Store::Postgres(shards) = self.store.as_ref() {
+            let counters = self.clone();
+            let shards = shards.clone();
+            tokio::spawn(async move { counters.run_worker(&shards).await });
+        }
+        let counters = self.clone();
+        tokio::spawn(async move { counters.run_reconciliation().await });
+    }
+
+    /// Wakes the relay up after an outbox entry has been committed.
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+
+    async fn run_worker(&self, shards: &shard::Shards) {
+        loop {
+            let mut more = false;
+            for (shard_name, shard_db) in shards.all() {
+                match self.relay_batch(shards, shard_name, shard_db).await {
+                    // there may be more entries waiting, so don't sleep
+                    Ok(processed) if processed == BATCH_SIZE => more = true,
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::error!(
+                            "outbox relay of message shard '{shard_name}' failed: {err:?}"
+                        )
+                    }
+                }
+            }
+            if more {
+                continue;
+            }
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Relays a batch of outbox entries of the shard to the counters. An entry that can't be
+    /// applied is retried with a growing delay. Only an entry the counters refuse for good is
+    /// rolled back along with the message change it belongs to.
+    async fn relay_batch(
+        &self,
+        shards: &shard::Shards,
+        shard_name: &str,
+        shard_db: &Arc<db::DB>,
+    ) -> anyhow::Result<usize> {
+        let mut pg_pool = shard_db.get().await?;
+        let transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to start outbox transaction: {}", e))?;
+        let entries = OutboxEntry::lock_batch(BATCH_SIZE, transaction.client()).await?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        let mut compensated = Vec::new();
+        for entry in &entries {
+            let rejected = match self.apply(entry).await {
+                Ok(Delivery::Applied) => None,
+                Ok(Delivery::Duplicate) => {
+                    tracing::debug!(
+                        "outbox entry {} has been applied already",
+                        entry.idempotency_key()
+                    );
+                    None
+                }
+                Ok(Delivery::Rejected(reason)) => Some(reason),
+                Err(err) if db::is_constraint_violation(&err) => Some(format!("{err:#}")),
+                Err(err) if entry.attempts + 1 >= MAX_ATTEMPTS && !db::is_transient(&err) => {
+                    tracing::error!(
+                        "outbox entry {} failed {} times, parking it: {err:?}",
+                        entry.idempotency_key(),
+                        MAX_ATTEMPTS
+                    );
+                    entry.park(&err.to_string(), transaction.client()).await?;
+                    continue;
+                }
+                Err(err) => {
+                    let delay = RETRY_DELAY
+                        .saturating_mul(2u32.saturating_pow(entry.attempts as u32))
+                        .min(MAX_RETRY_DELAY);
+                    tracing::warn!(
+                        "outbox entry {} failed, retrying in {:?}: {err:?}",
+                        entry.idempotency_key(),
+                        delay
+                    );
+                    entry
+                        .retry_later(&err.to_string(), delay, transaction.client())
+                        .await?;
+                    continue;
+                }
+            };
+            if let Some(reason) = rejected {
+                tracing::warn!(
+                    "outbox entry {} rejected, rolling it back: {}",
+                    entry.idempotency_key(),
+                    reason
+                );
+                compensate(entry, transaction.client()).await?;
+                compensated.push(entry);
+            }
+            entry.delete(transaction.client()).await?;
+        }
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to commit outbox: {}", e))?;
+
+        // copies made while resharding are rolled back too
+        for entry in compensated {
+            let route = shards.route(&entry.conversation_id);
+            for (name, db) in std::iter::once(route.primary).chain(route.secondary) {
+                if name == shard_name {
+                    continue;
+                }
+                let result = match db.get().await {
+                    Ok(pg_pool) => compensate(entry, pg_pool.client()).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    tracing::error!(
+                        "failed to roll back outbox entry {} on shard '{}': {err:?}",
+                        entry.idempotency_key(),
+                        name
+                    );
+                }
+            }
+        }
+        tracing::debug!(
+            "{} outbox entries of shard '{}' relayed",
+            entries.len(),
+            shard_name
+        );
+        Ok(entries.len())
+    }
+
+    async fn apply(&self, entry: &OutboxEntry) -> anyhow::Result<Delivery> {
+        let mut pg_pool = self.db.get().await?;
+        if entry.kind == Kind::Sent && !db_user::User::exists(&entry.user_id, &pg_pool).await? {
+            return Ok(Delivery::Rejected(format!(
+                "recipient {} doesn't exist",
+                entry.user_id
+            )));
+        }
+        let transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("transaction error: {}", e))?;
+        let applied = Counter::apply(
+            &entry.user_id,
+            &entry.other_user_id,
+            entry.delta,
+            &entry.idempotency_key(),
+            transaction.client(),
+        )
+        .await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))?;
+        Ok(if applied {
+            Delivery::Applied
+        } else {
+            Delivery::Duplicate
+        })
+    }
+
+    async fn run_reconciliation(&self) {
+        let mut interval = tokio::time::interval(self.reconcile_interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.reconcile().await {
+                tracing::error!("unread counters reconciliation failed: {err:?}");
+            }
+        }
+    }
+
+    /// Recomputes the counters from the messages and fixes the ones that drifted, both
+    /// missing or too low counters of the unread dialogs and too high counters of the dialogs
+    /// read since. Counters updated by the worker in the meantime are left alone.
+    pub async fn reconcile(&self) -> anyhow::Result<()> {
+        let started = std::time::Instant::now();
+        let main_db = self.db.get().await?;
+        let (checked, fixed) = match self.store.as_ref() {
+            dialog::Store::Postgres(shards) => reconcile_shards(shards, main_db.client()).await?,
+            dialog::Store::Memory(store) => reconcile_memory(store, main_db.client()).await?,
+        };
+
+        let pruned = Counter::prune_deliveries(main_db.client()).await?;
+        tracing::info!(
+            "unread counters reconciled in {:?}: {} checked, {} fixed, {} delivery keys pruned",
+            started.elapsed(),
+            checked,
+            fixed,
+            pruned
+        );
+        Ok(())
+    }
+}
+
+/// Returns the number of counters checked and fixed.
+async fn reconcile_shards(
+    shards: &shard::Shards,
+    main_db: &tokio_postgres::Client,
+) -> anyhow::Result<(usize, usize)> {
+    let mut checked = 0;
+    let mut fixed = 0;
+
+    for (shard_name, shard_db) in shards.primaries() {
+        let mut after = (String::new(), String::new());
+        loop {
+            let computed_at = Counter::now(main_db).await?;
+            let unread = {
+                let pg_pool = shard_db.get().await?;
+                db_message::Message::unread_after(&after.0, &after.1, BATCH_SIZE, pg_pool.client())
+                    .await?
+            };
+            let counters: Vec<&Counter> = unread
+                .iter()
+                .filter(|(conversation_id, _)| shards.owns(shard_name, conversation_id))
+                .map(|(_, counter)| counter)
+                .collect();
+            let dialogs: Vec<(String, String)> = counters
+                .iter()
+                .map(|counter| (counter.user_id.clone(), counter.other_user_id.clone()))
+                .collect();
+            let stored: HashMap<(String, String), i64> = Counter::of_dialogs(&dialogs, main_db)
+                .await?
+                .into_iter()
+                .map(|counter| ((counter.user_id, counter.other_user_id), counter.unread))
+                .collect();
+            for (counter, dialog) in counters.into_iter().zip(dialogs) {
+                checked += 1;
+                if stored.get(&dialog) != Some(&counter.unread)
+                    && counter.fix(&computed_at, main_db).await?
+                {
+                    fixed += 1;
+                    tracing::warn!(
+                        "unread counter of user {} in dialog with {} fixed from {:?} to {}",
+                        counter.user_id,
+                        counter.other_user_id,
+                        stored.get(&dialog),
+                        counter.unread
+                    );
+                }
+            }
+            match unread.last() {
+                Some((conversation_id, counter)) if unread.len() == BATCH_SIZE => {
+                    after = (conversation_id.clone(), counter.user_id.clone())
+                }
+                _ => break,
+            }
+        }
+    }
+
+    let mut after = (String::new(), String::new());
+    loop {
+        let computed_at = Counter::now(main_db).await?;
+        let stored = Counter::batch_after(&after.0, &after.1, BATCH_SIZE, main_db).await?;
+        let mut conversations: HashMap<&str, (&Arc<db::DB>, Vec<String>)> = HashMap::new();
+        for counter in &stored {
+            let conversation_id =
+                db_message::conversation_id(&counter.user_id, &counter.other_user_id);
+            let (shard_name, shard_db) = shards.route(&conversation_id).primary;
+            conversations
+                .entry(shard_name)
+                .or_insert_with(|| (shard_db, Vec::new()))
+                .1
+                .push(conversation_id);
+        }
+        let mut actual = HashMap::new();
+        for (shard_db, conversation_ids) in conversations.into_values() {
+            let pg_pool = shard_db.get().await?;
+            for counter in db_message::Message::unread(&conversation_ids, pg_pool.client()).await? {
+                actual.insert((counter.user_id, counter.other_user_id), counter.unread);
+            }
+        }
+        for counter in &stored {
+            checked += 1;
+            let unread = actual
+                .get(&(counter.user_id.clone(), counter.other_user_id.clone()))
+                .copied()
+                .unwrap_or(0);
+            if unread == counter.unread {
+                continue;
+            }
+            let actual = Counter {
+                unread,
+                ..counter.clone()
+            };
+            if actual.fix(&computed_at, main_db).await? {
+                fixed += 1;
+                tracing::warn!(
+                    "unread counter of user {} in dialog with {} fixed from {} to {}",
+                    counter.user_id,
+                    counter.other_user_id,
+                    counter.unread,
+                    unread
+                );
+            }
+        }
+        match stored.last() {
+            Some(last) if stored.len() == BATCH_SIZE => {
+                after = (last.user_id.clone(), last.other_user_id.clone())
+            }
+            _ => break,
+        }
+    }
+
+    Ok((checked, fixed))
+}
+
+/// Same passes as `reconcile_shards` over the messages kept in memory.
+async fn reconcile_memory(
+    store: &memory_store::MemoryStore,
+    main_db: &tokio_postgres::Client,
+) -> anyhow::Result<(usize, usize)> {
+    let mut checked = 0;
+    let mut fixed = 0;
+
+    let computed_at = Counter::now(main_db).await?;
+    let unread = store.unread();
+    for counters in unread.chunks(BATCH_SIZE) {
+        let dialogs: Vec<(String, String)> = counters
+            .iter()
+            .map(|counter| (counter.user_id.clone(), counter.other_user_id.clone()))
+            .collect();
+        let stored: HashMap<(String, String), i64> = Counter::of_dialogs(&dialogs, main_db)
+            .await?
+            .into_iter()
+            .map(|counter| ((counter.user_id, counter.other_user_id), counter.unread))
+            .collect();
+        for (counter, dialog) in counters.iter().zip(dialogs) {
+            checked += 1;
+            if stored.get(&dialog) != Some(&counter.unread)
+                && counter.fix(&computed_at, main_db).await?
+            {
+                fixed += 1;
+                tracing::warn!(
+                    "unread counter of user {} in dialog with {} fixed from {:?} to {}",
+                    counter.user_id,
+                    counter.other_user_id,
+                    stored.get(&dialog),
+                    counter.unread
+                );
+            }
+        }
+    }
+
+    let unread: HashMap<(String, String), i64> = unread
+        .into_iter()
+        .map(|counter| ((counter.user_id, counter.other_user_id), counter.unread))
+        .collect();
+    let mut after = (String::new(), String::new());
+    loop {
+        let stored = Counter::batch_after(&after.0, &after.1, BATCH_SIZE, main_db).await?;
+        for counter in &stored {
+            let dialog = (counter.user_id.clone(), counter.other_user_id.clone());
+            if unread.contains_key(&dialog) {
+                continue;
+            }
+            checked += 1;
+            let actual = Counter {
+                unread: 0,
+                ..counter.clone()
+            };
+            if actual.fix(&computed_at, main_db).await? {
+                fixed += 1;
+                tracing::warn!(
+                    "unread counter of user {} in dialog with {} fixed from {} to 0",
+                    counter.user_id,
+                    counter.other_user_id,
+                    counter.unread
+                );
+            }
+        }
+        match stored.last() {
+            Some(last) if stored.len() == BATCH_SIZE => {
+                after = (last.user_id.clone(), last.other_user_id.clone())
+            }
+            _ => break,
+        }
+    }
+    Ok((checked, fixed))
+}
+
+/// Rolls back the message change the outbox entry was written along with.
+async fn compensate(entry: &OutboxEntry, client: &tokio_postgres::Client) -> anyhow::Result<()> {
+    match entry.kind {
+        Kind::Sent => {
+            db_message::Message::delete(&entry.conversation_id, entry.message_id, client).await
+        }
+        Kind::Read => {
+            db_message::ReadMarker {
+                conversation_id: entry.conversation_id.clone(),
+                user_id: entry.user_id.clone(),
+                last_read_id: entry.message_id,
+                last_delivered_id: 0,
+            }
+            .reset(entry.previous_id.unwrap_or(0), client)
+            .await
+        }
+    }
+}
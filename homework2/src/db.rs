@@ -1,96 +1,803 @@
+use crate::pg_config::{PgConfig, SslMode};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Connection settings of the main database, connection strings are in any form libpq
+/// accepts.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub conn_string: String,
+    /// Standbys of the main database, one of them takes the writes once it is promoted.
+    pub failover_candidates: Vec<String>,
+    /// Streaming replicas serving read-only queries.
+    pub replicas: Vec<String>,
+    pub pool_size: usize,
+    pub tls: TlsConfig,
+    pub timeouts: Timeouts,
+    pub retry: RetryPolicy,
+}
+
+/// Limits of the queries to the main database and its replicas, none by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// Longest a statement may run, postgres cancels it after that.
+    pub statement: Option<Duration>,
+    /// Longest a query waits for a free connection of the pool, the database is saturated
+    /// after that.
+    pub pool_wait: Option<Duration>,
+}
+
+/// How the idempotent queries are made again after a transient error, nothing is retried
+/// by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    /// Attempts after the first one.
+    pub retries: u32,
+    /// Wait before the first retry, doubled before each next one.
+    pub backoff: Duration,
+}
+
+/// Every connection of the pool stayed busy for the whole wait timeout, or the server
+/// refused a new one, so there is no point in retrying right away.
+#[derive(Debug)]
+pub struct Saturated {
+    server: String,
+    retry_after: Duration,
+}
+
+impl Saturated {
+    /// How long the client should wait before trying again.
+    pub fn retry_after(&self) -> Duration {
+        self.retry_after
+    }
+}
+
+impl std::fmt::Display for Saturated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "postgres {} is saturated, no free connection",
+            self.server
+        )
+    }
+}
+
+impl std::error::Error for Saturated {}
+
+/// Error of a query keeping the postgres error as the source, so that the transient
+/// ones can be told apart.
+pub fn query_error(context: &str, e: tokio_postgres::Error) -> anyhow::Error {
+    let message = match e.as_db_error() {
+        Some(db_error) => format!("{}: {}", context, db_error),
+        None => format!("{}: {}", context, e),
+    };
+    anyhow::Error::new(e).context(message)
+}
+
+/// Whether postgres refused the change for breaking a constraint, so it never succeeds.
+pub fn is_constraint_violation(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<tokio_postgres::Error>()
+            .and_then(|e| e.code())
+            .is_some_and(|code| code.code().starts_with("23"))
+    })
+}
+
+/// Whether the failed query may succeed when made again: the connection broke, or postgres
+/// aborted the transaction for conflicting with another one.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    use tokio_postgres::error::SqlState;
+
+    err.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<tokio_postgres::Error>() {
+            e.is_closed()
+                || e.code().is_some_and(|code| {
+                    *code == SqlState::T_R_SERIALIZATION_FAILURE
+                        || *code == SqlState::T_R_DEADLOCK_DETECTED
+                        || *code == SqlState::ADMIN_SHUTDOWN
+                })
+        } else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            matches!(
+                e.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+            )
+        } else {
+            false
+        }
+    })
+}
+
+/// Certificates of TLS connections, used by every server of the main database and by
+/// the message shards unless their connection strings give others. Whether TLS is used
+/// is up to `sslmode` of the connection string.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// CA bundle verifying the servers instead of the system one.
+    pub root_cert: Option<PathBuf>,
+    /// Client certificate chain and its private key, both PEM.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
 pub struct DB {
-    pool: deadpool_postgres::Pool,
+    tls: TlsConfig,
+    /// The main database followed by the failover candidates.
+    nodes: Vec<Node>,
+    /// Node writes go to, the writable one when last checked.
+    primary: AtomicUsize,
+    failovers: AtomicU64,
+    replicas: Vec<Replica>,
+    next_replica: AtomicUsize,
+    retry: RetryPolicy,
+}
+
+/// Server that is or may become the primary.
+struct Node {
+    /// Hosts and ports of the server, for the logs.
+    name: String,
+    pool: Pool,
+    status: Mutex<NodeStatus>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct NodeStatus {
+    pub name: String,
+    pub role: Role,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checked_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Accepts writes.
+    Primary,
+    /// In recovery, following a primary.
+    Standby,
+    Down,
 }
 
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::Primary => "primary",
+            Role::Standby => "standby",
+            Role::Down => "down",
+        }
+    }
+}
+
+/// Servers of the main database as seen by the last checks.
+#[derive(Debug, serde::Serialize)]
+pub struct Topology {
+    pub primary: String,
+    /// Times the writes have been switched to another server since the start.
+    pub failovers: u64,
+    pub nodes: Vec<NodeStatus>,
+    pub replicas: Vec<ReplicaStatus>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ReplicaStatus {
+    pub name: String,
+    pub healthy: bool,
+    pub replayed_lsn: String,
+}
+
+/// Streaming replica of the main database serving read-only queries.
+struct Replica {
+    /// Hosts and ports of the replica, for the logs.
+    name: String,
+    pool: Pool,
+    healthy: AtomicBool,
+    /// WAL position replayed by the replica when it was last asked.
+    replayed: AtomicU64,
+}
+
+/// Connections to one server, remembering the last error met on it.
+struct Pool {
+    /// Hosts and ports of the server, for the errors.
+    server: String,
+    inner: deadpool_postgres::Pool,
+    last_error: Mutex<Option<PoolError>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PoolError {
+    pub message: String,
+    pub at: String,
+}
+
+/// Connections to a server and how it is doing.
+#[derive(Debug, serde::Serialize)]
+pub struct PoolStats {
+    pub name: String,
+    /// `primary`, `standby` or `down` for the main database and the failover candidates,
+    /// `replica` for the replicas.
+    pub role: &'static str,
+    pub max_size: usize,
+    /// Open connections.
+    pub size: usize,
+    /// Idle connections.
+    pub available: usize,
+    pub waiting: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag: Option<Lag>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<PoolError>,
+}
+
+/// How far a replica is behind the main database.
+#[derive(Debug, serde::Serialize)]
+pub struct Lag {
+    /// Unknown when the main database isn't reachable.
+    pub bytes: Option<u64>,
+    /// Since the last transaction replayed, unknown before any.
+    pub seconds: Option<f64>,
+}
+
+/// Position in the write-ahead log of the main database, written as Postgres does,
+/// for example `16/B374D848`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Lsn(u64);
+
+impl std::fmt::Display for Lsn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:X}/{:X}", self.0 >> 32, self.0 & 0xFFFF_FFFF)
+    }
+}
+
+impl std::str::FromStr for Lsn {
+    type Err = anyhow::Error;
+
+    fn from_str(lsn: &str) -> anyhow::Result<Self> {
+        // from_str_radix would take a sign as well
+        let parse = |part: &str| {
+            part.bytes()
+                .all(|byte| byte.is_ascii_hexdigit())
+                .then(|| u32::from_str_radix(part, 16).ok())
+                .flatten()
+        };
+        match lsn.split_once('/') {
+            Some((high, low)) => match (parse(high), parse(low)) {
+                (Some(high), Some(low)) => Ok(Lsn((high as u64) << 32 | low as u64)),
+                _ => anyhow::bail!("invalid WAL position '{}'", lsn),
+            },
+            None => anyhow::bail!("invalid WAL position '{}'", lsn),
+        }
+    }
+}
+
+/// How often replicas are checked, an unhealthy replica serves nothing until it passes.
+const REPLICA_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the failover candidates are asked whether they are in recovery.
+const FAILOVER_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// A replica or a failover candidate that can't be connected to within that time is down.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Retry-After of a saturated database without a pool wait timeout.
+const SATURATED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
 const DBNAME: &str = "highload_alexander_bubnov";
-const TABLE_USERS: &str = "users";
+/// Database every server has, the default database is looked for and created from it.
+const MAINTENANCE_DBNAME: &str = "postgres";
+pub const TABLE_USERS: &str = "users";
+const TABLE_POSTS: &str = "posts";
+const TABLE_FRIENDS: &str = "friends";
+const TABLE_FEED_EVENTS: &str = "feed_events";
+const TABLE_MESSAGES: &str = "messages";
+/// Ids of the dialog messages, kept in the main database so they are unique across shards.
+const SEQUENCE_MESSAGE_IDS: &str = "message_ids";
+const TABLE_MESSAGE_READS: &str = "message_reads";
+const TABLE_MESSAGE_OUTBOX: &str = "message_outbox";
+const TABLE_COUNTER_DELIVERIES: &str = "counter_deliveries";
+const TABLE_UNREAD_COUNTERS: &str = "unread_counters";
+const TABLE_GROUPS: &str = "groups";
+const TABLE_GROUP_MEMBERS: &str = "group_members";
+const TABLE_GROUP_MESSAGES: &str = "group_messages";
+const TABLE_RESHARDS: &str = "reshards";
+const TABLE_RESHARD_CHECKPOINTS: &str = "reshard_checkpoints";
+/// Stems both Cyrillic and Latin words, must match the one of `db_message::Message::search`.
+const MESSAGES_TEXT_SEARCH_CONFIG: &str = "russian";
+enum Schema {
+    Full,
+    Messages,
+}
+
+/// Step of the users table schema. The SQL is understood by postgres and SQLite alike,
+/// so the user repositories on both of them share the schema.
+pub enum UsersMigration {
+    Statement {
+        statement: String,
+        /// Start of the error message, followed by the table name.
+        failure: &'static str,
+    },
+    /// SQLite can't add a column only if it's missing, so each database checks for it its own way.
+    AddColumn {
+        name: &'static str,
+        definition: &'static str,
+    },
+    /// SQLite has no NULLS FIRST/LAST in indexes, it sorts nulls as the smallest values.
+    Index {
+        name: &'static str,
+        columns: &'static str,
+        sqlite_columns: &'static str,
+    },
+}
+
+pub fn users_migrations() -> Vec<UsersMigration> {
+    vec![
+        UsersMigration::Statement {
+            statement: format!("create table if not exists {TABLE_USERS} (id text PRIMARY KEY, first_name text, second_name text, birthdate text, biography text, city text, password_hash text, token text)"),
+            failure: "failed to create table",
+        },
+        UsersMigration::Statement {
+            statement: format!("create index if not exists {TABLE_USERS}_token_idx on {TABLE_USERS} (token)"),
+            failure: "failed to create token index on",
+        },
+        UsersMigration::AddColumn {
+            name: "last_login_at",
+            definition: "timestamptz",
+        },
+        UsersMigration::AddColumn {
+            name: "last_seen_at",
+            definition: "timestamptz",
+        },
+        UsersMigration::AddColumn {
+            name: "presence_hidden",
+            definition: "boolean NOT NULL DEFAULT false",
+        },
+        UsersMigration::Index {
+            name: "last_login",
+            columns: "last_login_at DESC NULLS LAST",
+            sqlite_columns: "last_login_at DESC",
+        },
+    ]
+}
+
 impl DB {
-    pub async fn new(conn_string: &str, conn_pool_size: usize) -> anyhow::Result<Self> {
-        let conn_string = DB::prepare_db(conn_string).await?;
-        let pool = DB::create_pool(conn_string.as_str(), conn_pool_size).await?;
-        Ok(Self { pool })
+    /// Connects to the main database and to its replicas, if any. A replica that is down
+    /// at startup is only used once it comes up. With failover candidates, the writable one
+    /// of the main database and the candidates becomes the primary.
+    pub async fn new(config: &Config) -> anyhow::Result<Self> {
+        let (nodes, primary) = if config.failover_candidates.is_empty() {
+            let pg_config = PgConfig::parse(&config.conn_string)?;
+            let pg_config = DB::prepare_db(&pg_config, Schema::Full, &config.tls).await?;
+            let pool = DB::create_pool(
+                &pg_config,
+                &config.tls,
+                config.pool_size,
+                None,
+                config.timeouts,
+            )?;
+            (
+                vec![Node::new(
+                    &pg_config,
+                    Pool::new(&pg_config, pool),
+                    Role::Primary,
+                )],
+                0,
+            )
+        } else {
+            let pg_configs = std::iter::once(&config.conn_string)
+                .chain(&config.failover_candidates)
+                .map(|conn_string| PgConfig::parse(conn_string))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let primary = DB::find_writable(&pg_configs, &config.tls).await?;
+            let mut nodes = Vec::with_capacity(pg_configs.len());
+            for (i, pg_config) in pg_configs.into_iter().enumerate() {
+                let pg_config = if i == primary {
+                    DB::prepare_db(&pg_config, Schema::Full, &config.tls).await?
+                } else {
+                    DB::with_dbname(&pg_config)
+                };
+                let pool = DB::create_pool(
+                    &pg_config,
+                    &config.tls,
+                    config.pool_size,
+                    Some(CONNECT_TIMEOUT),
+                    config.timeouts,
+                )?;
+                let role = if i == primary {
+                    Role::Primary
+                } else {
+                    Role::Standby
+                };
+                nodes.push(Node::new(&pg_config, Pool::new(&pg_config, pool), role));
+            }
+            (nodes, primary)
+        };
+        tracing::info!("writes go to {}", nodes[primary].name);
+
+        let mut replicas = Vec::with_capacity(config.replicas.len());
+        for replica_conn_string in &config.replicas {
+            let replica = Replica::new(replica_conn_string, config)?;
+            replica.check().await;
+            if !replica.healthy.load(Ordering::Relaxed) {
+                tracing::warn!(
+                    "replica {} is down, it is read from once it is up",
+                    replica.name
+                );
+            }
+            replicas.push(replica);
+        }
+        Ok(Self {
+            tls: config.tls.clone(),
+            nodes,
+            primary: AtomicUsize::new(primary),
+            failovers: AtomicU64::new(0),
+            replicas,
+            next_replica: AtomicUsize::new(0),
+            retry: config.retry,
+        })
+    }
+
+    /// Connects to a database holding only a shard of the messages.
+    pub async fn new_messages_shard(
+        conn_string: &str,
+        tls: &TlsConfig,
+        conn_pool_size: usize,
+    ) -> anyhow::Result<Self> {
+        let pg_config = PgConfig::parse(conn_string)?;
+        let pg_config = DB::prepare_db(&pg_config, Schema::Messages, tls).await?;
+        let pool = DB::create_pool(&pg_config, tls, conn_pool_size, None, Timeouts::default())?;
+        Ok(Self {
+            tls: tls.clone(),
+            nodes: vec![Node::new(
+                &pg_config,
+                Pool::new(&pg_config, pool),
+                Role::Primary,
+            )],
+            primary: AtomicUsize::new(0),
+            failovers: AtomicU64::new(0),
+            replicas: Vec::new(),
+            next_replica: AtomicUsize::new(0),
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    pub fn spawn_workers(self: &Arc<Self>) {
+        if !self.replicas.is_empty() {
+            let db = self.clone();
+            tokio::spawn(async move { db.run_replica_checks().await });
+        }
+        if self.nodes.len() > 1 {
+            let db = self.clone();
+            tokio::spawn(async move { db.run_failover_checks().await });
+        }
     }
 
     pub async fn get(&self) -> anyhow::Result<deadpool_postgres::Object> {
-        let object =
-            self.pool.get().await.map_err(|e| {
-                anyhow::anyhow!("failed to get object from pg pool: {}", e.to_string())
+        self.primary().pool.get().await
+    }
+
+    /// Runs an idempotent query again after a transient error, as many times as the retry
+    /// policy allows, waiting longer before each attempt.
+    pub async fn retry<T, F, Fut>(&self, mut query: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<T>>,
+    {
+        let mut backoff = self.retry.backoff;
+        for _ in 0..self.retry.retries {
+            match query().await {
+                Err(err) if is_transient(&err) => {
+                    tracing::warn!("retrying in {:?} after a transient error: {}", backoff, err);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        query().await
+    }
+
+    pub fn tls(&self) -> &TlsConfig {
+        &self.tls
+    }
+
+    /// Connections each pool of the main database may open.
+    pub fn pool_size(&self) -> usize {
+        self.nodes[0].pool.inner.status().max_size
+    }
+
+    pub fn has_replicas(&self) -> bool {
+        !self.replicas.is_empty()
+    }
+
+    /// Current end of the write-ahead log of the main database, a replica that has replayed
+    /// that far sees every write committed so far.
+    pub async fn current_lsn(&self) -> anyhow::Result<Lsn> {
+        let primary = self.primary();
+        let row = primary
+            .pool
+            .get()
+            .await?
+            .query_one("select (pg_current_wal_lsn() - '0/0')::bigint", &[])
+            .await
+            .map_err(|e| {
+                primary.pool.failed(anyhow::anyhow!(
+                    "failed to read current WAL position: {}",
+                    e
+                ))
             })?;
+        Ok(Lsn(row.get::<_, i64>(0) as u64))
+    }
 
-        Ok(object)
+    /// Connection for read-only queries: from the healthy replicas in turn, or from the main
+    /// database when none is healthy. Given `min_lsn`, only the replicas that have replayed
+    /// the log that far are used, so the caller reads its own writes.
+    pub async fn get_read(
+        &self,
+        min_lsn: Option<Lsn>,
+    ) -> anyhow::Result<deadpool_postgres::Object> {
+        let count = self.replicas.len();
+        let first = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        for i in 0..count {
+            let replica = &self.replicas[(first + i) % count];
+            if !replica.healthy.load(Ordering::Relaxed) {
+                continue;
+            }
+            let object = match replica.pool.get().await {
+                Ok(object) => object,
+                // a busy replica is up, the next one may have a free connection
+                Err(err) if err.is::<Saturated>() => {
+                    tracing::debug!("{}", err);
+                    continue;
+                }
+                Err(err) => {
+                    if replica.healthy.swap(false, Ordering::Relaxed) {
+                        tracing::warn!("replica {} is down: {}", replica.name, err);
+                    }
+                    continue;
+                }
+            };
+            match min_lsn {
+                None => return Ok(object),
+                Some(min_lsn) if replica.replayed() >= min_lsn => return Ok(object),
+                Some(min_lsn) => match replica.replay_lsn(&object).await {
+                    Ok(replayed) if replayed >= min_lsn => return Ok(object),
+                    Ok(replayed) => tracing::debug!(
+                        "replica {} has replayed up to {}, {} is needed",
+                        replica.name,
+                        replayed,
+                        min_lsn
+                    ),
+                    Err(err) => tracing::warn!("{}", err),
+                },
+            }
+        }
+        self.get().await
     }
 
-    async fn prepare_db(conn_string: &str) -> anyhow::Result<String> {
-        let client = DB::create_client(conn_string).await?;
-        let need_create_db = !conn_string.to_lowercase().contains("dbname=");
-        let (conn_string, dbname) = if need_create_db {
-            tracing::info!(
-                "database has not been specifed so let's create it with name '{}'",
-                DBNAME
-            );
-            if DB::is_db_exists(&client).await? {
+    /// Stats of the connection pools, asking the replicas for their lag.
+    pub async fn stats(&self) -> Vec<PoolStats> {
+        let mut stats = Vec::with_capacity(self.nodes.len() + self.replicas.len());
+        if !self.replicas.is_empty() {
+            let primary_lsn = self.current_lsn().await.ok();
+            for replica in &self.replicas {
+                let lag = replica.lag(primary_lsn).await.ok();
+                stats.push(replica.pool.stats(&replica.name, "replica", lag));
+            }
+        }
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| node.pool.stats(&node.name, node.role().as_str(), None));
+        stats.splice(0..0, nodes);
+        stats
+    }
+
+    pub fn topology(&self) -> Topology {
+        Topology {
+            primary: self.primary().name.clone(),
+            failovers: self.failovers.load(Ordering::Relaxed),
+            nodes: self
+                .nodes
+                .iter()
+                .map(|node| node.status.lock().unwrap().clone())
+                .collect(),
+            replicas: self
+                .replicas
+                .iter()
+                .map(|replica| ReplicaStatus {
+                    name: replica.name.clone(),
+                    healthy: replica.healthy.load(Ordering::Relaxed),
+                    replayed_lsn: replica.replayed().to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn primary(&self) -> &Node {
+        &self.nodes[self.primary.load(Ordering::Relaxed)]
+    }
+
+    /// Index of the first server that isn't in recovery.
+    async fn find_writable(pg_configs: &[PgConfig], tls: &TlsConfig) -> anyhow::Result<usize> {
+        for (i, pg_config) in pg_configs.iter().enumerate() {
+            let result = async {
+                // the default database may not be created yet
+                let pg_config = match pg_config.dbname() {
+                    Some(_) => pg_config.clone(),
+                    None => pg_config.with_dbname(MAINTENANCE_DBNAME),
+                };
+                let client = DB::create_client(&pg_config, tls).await?;
+                let row = client
+                    .query_one("select pg_is_in_recovery()", &[])
+                    .await
+                    .map_err(|e| anyhow::anyhow!("failed to check recovery: {}", e))?;
+                anyhow::Ok(row.get::<_, bool>(0))
+            }
+            .await;
+            match result {
+                Ok(false) => return Ok(i),
+                Ok(true) => tracing::info!("postgres #{} is a standby", i),
+                Err(err) => tracing::warn!("postgres #{} is down: {:?}", i, err),
+            }
+        }
+        anyhow::bail!("none of the main postgres and its failover candidates accepts writes")
+    }
+
+    async fn run_failover_checks(&self) {
+        let mut interval = tokio::time::interval(FAILOVER_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for node in &self.nodes {
+                node.check().await;
+            }
+            let current = self.primary.load(Ordering::Relaxed);
+            if self.nodes[current].role() == Role::Primary {
+                continue;
+            }
+            match self
+                .nodes
+                .iter()
+                .position(|node| node.role() == Role::Primary)
+            {
+                Some(promoted) => {
+                    self.primary.store(promoted, Ordering::Relaxed);
+                    self.failovers.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        "failover: writes go to {} instead of {}",
+                        self.nodes[promoted].name,
+                        self.nodes[current].name
+                    );
+                }
+                None => tracing::debug!("no postgres accepts writes"),
+            }
+        }
+    }
+
+    async fn run_replica_checks(&self) {
+        let mut interval = tokio::time::interval(REPLICA_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for replica in &self.replicas {
+                replica.check().await;
+            }
+        }
+    }
+
+    /// Connects to the database of the connection string. Without one, the default database
+    /// is used and it is created first if the server doesn't have it yet.
+    async fn prepare_db(
+        pg_config: &PgConfig,
+        schema: Schema,
+        tls: &TlsConfig,
+    ) -> anyhow::Result<PgConfig> {
+        let pg_config = match pg_config.dbname() {
+            Some(_) => pg_config.clone(),
+            None => {
                 tracing::info!(
-                    "db with name '{}' already exists, so let's using it",
+                    "database has not been specifed so let's use the one with name '{}'",
                     DBNAME
                 );
-            } else {
-                DB::create_db(&client).await?;
-                tracing::info!("db with name '{}' created", DBNAME);
+                let client =
+                    DB::create_client(&pg_config.with_dbname(MAINTENANCE_DBNAME), tls).await?;
+                if DB::is_db_exists(&client).await? {
+                    tracing::info!(
+                        "db with name '{}' already exists, so let's using it",
+                        DBNAME
+                    );
+                } else if DB::create_db(&client).await? {
+                    tracing::info!("db with name '{}' created", DBNAME);
+                } else {
+                    tracing::info!(
+                        "db with name '{}' has just been created by another server",
+                        DBNAME
+                    );
+                }
+                pg_config.with_dbname(DBNAME)
             }
-            (
-                conn_string.to_owned() + " dbname=" + DBNAME,
-                DBNAME.to_string(),
-            )
-        } else {
-            (
-                conn_string.to_string(),
-                conn_string
-                    .split_whitespace()
-                    .find(|param| param.to_lowercase().starts_with("dbname="))
-                    .unwrap()
-                    .split('=')
-                    .last()
-                    .unwrap()
-                    .to_string(),
-            )
         };
-        let client = DB::create_client(conn_string.as_str()).await?;
-        tracing::info!("connected to DB with name '{}'", dbname);
-        DB::apply_migrations(&client).await?;
-        Ok(conn_string)
+        let client = DB::create_client(&pg_config, tls).await?;
+        tracing::info!(
+            "connected to DB with name '{}'",
+            pg_config.dbname().unwrap_or_default()
+        );
+        match schema {
+            Schema::Full => DB::apply_migrations(&client).await?,
+            Schema::Messages => DB::apply_messages_migrations(&client).await?,
+        }
+        tracing::info!("migrations applied");
+        Ok(pg_config)
     }
 
-    async fn create_pool(
-        conn_string: &str,
+    /// Standbys hold a copy of the main database, so they get its name unless told otherwise.
+    /// The schema comes from the main database too.
+    fn with_dbname(pg_config: &PgConfig) -> PgConfig {
+        match pg_config.dbname() {
+            Some(_) => pg_config.clone(),
+            None => pg_config.with_dbname(DBNAME),
+        }
+    }
+
+    fn create_pool(
+        pg_config: &PgConfig,
+        tls: &TlsConfig,
         size: usize,
+        connect_timeout: Option<Duration>,
+        timeouts: Timeouts,
     ) -> anyhow::Result<deadpool_postgres::Pool> {
-        use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
-
-        let pg_config = conn_string.parse::<tokio_postgres::Config>().map_err(|e| {
-            anyhow::anyhow!(
-                "failed to parse postgresql connection string '{}': {}",
-                conn_string,
-                e.to_string()
-            )
-        })?;
+        use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Runtime};
 
+        let connector = DB::tls_connector(pg_config, tls)?;
         let mgr_config = ManagerConfig {
             recycling_method: RecyclingMethod::Fast,
         };
 
-        let mgr = Manager::from_config(pg_config, tokio_postgres::NoTls, mgr_config);
+        let mut config = pg_config.tokio_config();
+        if let Some(statement_timeout) = timeouts.statement {
+            let options = format!(
+                "{} -c statement_timeout={}",
+                config.get_options().unwrap_or_default(),
+                statement_timeout.as_millis()
+            );
+            config.options(options.trim_start());
+        }
+        let mgr = Manager::from_config(config, connector, mgr_config);
         let pool = Pool::builder(mgr)
             .max_size(size)
+            .runtime(Runtime::Tokio1)
+            .create_timeout(connect_timeout)
+            .wait_timeout(timeouts.pool_wait)
             .build()
-            .map_err(|e| anyhow::anyhow!("failed to build postgre config: {}", e.to_string()))?;
+            .map_err(|e| anyhow::anyhow!("failed to build postgre config: {}", e))?;
 
         Ok(pool)
     }
 
-    async fn create_client(conn_string: &str) -> anyhow::Result<tokio_postgres::Client> {
-        let (client, connection) = tokio_postgres::connect(conn_string, tokio_postgres::NoTls)
-            .await
-            .map_err(|e| anyhow::anyhow!("failed to connect with params {}: {}", conn_string, e))?;
+    async fn create_client(
+        pg_config: &PgConfig,
+        tls: &TlsConfig,
+    ) -> anyhow::Result<tokio_postgres::Client> {
+        let connector = DB::tls_connector(pg_config, tls)?;
+        let (client, connection) =
+            pg_config
+                .tokio_config()
+                .connect(connector)
+                .await
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "failed to connect to {} database '{}': {}",
+                        pg_config.server_name(),
+                        pg_config.dbname().unwrap_or_default(),
+                        e
+                    )
+                })?;
 
         tokio::spawn(async move {
             if let Err(e) = connection.await {
@@ -101,13 +808,88 @@ impl DB {
         Ok(client)
     }
 
-    async fn create_db(client: &tokio_postgres::Client) -> anyhow::Result<()> {
-        client
+    /// Connector verifying the server certificate as `sslmode` of the connection string asks,
+    /// the same way libpq does. Certificates of the connection string take precedence.
+    fn tls_connector(
+        pg_config: &PgConfig,
+        tls: &TlsConfig,
+    ) -> anyhow::Result<postgres_openssl::MakeTlsConnector> {
+        use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+
+        let root_cert = pg_config.ssl_root_cert().or(tls.root_cert.as_deref());
+        let (cert, key) = match pg_config.ssl_cert() {
+            Some(cert) => (Some(cert), pg_config.ssl_key()),
+            None => (
+                tls.cert.as_deref(),
+                pg_config.ssl_key().or(tls.key.as_deref()),
+            ),
+        };
+        let context = |e: openssl::error::ErrorStack| anyhow::anyhow!("TLS setup failed: {}", e);
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(context)?;
+        if let Some(root_cert) = root_cert {
+            builder.set_ca_file(root_cert).map_err(|e| {
+                anyhow::anyhow!("failed to load CA bundle {}: {}", root_cert.display(), e)
+            })?;
+        }
+        if let Some(cert) = cert {
+            builder.set_certificate_chain_file(cert).map_err(|e| {
+                anyhow::anyhow!(
+                    "failed to load client certificate {}: {}",
+                    cert.display(),
+                    e
+                )
+            })?;
+        }
+        if let Some(key) = key {
+            builder
+                .set_private_key_file(key, SslFiletype::PEM)
+                .map_err(|e| {
+                    anyhow::anyhow!("failed to load client key {}: {}", key.display(), e)
+                })?;
+            builder.check_private_key().map_err(|e| {
+                anyhow::anyhow!(
+                    "client key {} doesn't match the certificate: {}",
+                    key.display(),
+                    e
+                )
+            })?;
+        }
+        // like libpq, `require` verifies the CA once a CA bundle is given
+        let (verify_ca, verify_host) = match pg_config.ssl_mode() {
+            SslMode::VerifyFull => (true, true),
+            SslMode::VerifyCa => (true, false),
+            SslMode::Require => (root_cert.is_some(), false),
+            SslMode::Disable | SslMode::Allow | SslMode::Prefer => (false, false),
+        };
+        if !verify_ca {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+        let mut connector = postgres_openssl::MakeTlsConnector::new(builder.build());
+        if !verify_host {
+            connector.set_callback(|config, _| {
+                config.set_verify_hostname(false);
+                Ok(())
+            });
+        }
+        Ok(connector)
+    }
+
+    /// False when another server has created the database in the meantime.
+    async fn create_db(client: &tokio_postgres::Client) -> anyhow::Result<bool> {
+        match client
             .query(&format!("create database {DBNAME}"), &[])
             .await
-            .map_err(|e| anyhow::anyhow!("failed to create db with name {}: {}", DBNAME, e))?;
-
-        Ok(())
+        {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == Some(&tokio_postgres::error::SqlState::DUPLICATE_DATABASE) => {
+                Ok(false)
+            }
+            Err(e) => Err(anyhow::anyhow!(
+                "failed to create db with name {}: {}",
+                DBNAME,
+                e
+            )),
+        }
     }
 
     async fn is_db_exists(client: &tokio_postgres::Client) -> anyhow::Result<bool> {
@@ -127,11 +909,473 @@ impl DB {
     }
 
     async fn apply_migrations(client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        for migration in users_migrations() {
+            let (statement, failure) = match migration {
+                UsersMigration::Statement { statement, failure } => (statement, failure.to_owned()),
+                UsersMigration::AddColumn { name, definition } => (
+                    format!(
+                        "alter table {TABLE_USERS} add column if not exists {name} {definition}"
+                    ),
+                    format!("failed to add {name} to"),
+                ),
+                UsersMigration::Index { name, columns, .. } => (
+                    format!("create index if not exists {TABLE_USERS}_{name}_idx on {TABLE_USERS} ({columns})"),
+                    format!("failed to create {} index on", name.replace('_', " ")),
+                ),
+            };
+            client
+                .query(&statement, &[])
+                .await
+                .map_err(|e| anyhow::anyhow!("{} '{}': {}", failure, TABLE_USERS, e))?;
+        }
         client
-            .query(&format!("create table if not exists {TABLE_USERS} (id text PRIMARY KEY, first_name text, second_name text, birthdate text, biography text, city text, password_hash text, token text)"), &[])
+            .query(&format!("create table if not exists {TABLE_POSTS} (id text PRIMARY KEY, author_user_id text NOT NULL REFERENCES {TABLE_USERS} (id) ON DELETE CASCADE, text text NOT NULL, created_at timestamptz NOT NULL DEFAULT now(), updated_at timestamptz NOT NULL DEFAULT now())"), &[])
             .await
-            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_USERS, e))?;
-        tracing::info!("migrations applied");
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_POSTS, e))?;
+        client
+            .query(&format!("create index if not exists {TABLE_POSTS}_author_created_idx on {TABLE_POSTS} (author_user_id, created_at DESC)"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create author index on '{}': {}", TABLE_POSTS, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_FRIENDS} (user_id text NOT NULL REFERENCES {TABLE_USERS} (id) ON DELETE CASCADE, friend_id text NOT NULL REFERENCES {TABLE_USERS} (id) ON DELETE CASCADE, PRIMARY KEY (user_id, friend_id))"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_FRIENDS, e))?;
+        client
+            .query(&format!("create index if not exists {TABLE_FRIENDS}_friend_idx on {TABLE_FRIENDS} (friend_id)"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create friend index on '{}': {}", TABLE_FRIENDS, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_FEED_EVENTS} (id bigserial PRIMARY KEY, kind text NOT NULL, post_id text NOT NULL, author_user_id text NOT NULL, text text NOT NULL, attempts integer NOT NULL DEFAULT 0, last_error text, next_attempt_at timestamptz NOT NULL DEFAULT now(), dead_at timestamptz, created_at timestamptz NOT NULL DEFAULT now())"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_FEED_EVENTS, e))?;
+        client
+            .query(&format!("create index if not exists {TABLE_FEED_EVENTS}_author_idx on {TABLE_FEED_EVENTS} (author_user_id, id)"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create author index on '{}': {}", TABLE_FEED_EVENTS, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_RESHARDS} (fingerprint text PRIMARY KEY, cut_over boolean NOT NULL DEFAULT false, verified_at timestamptz, updated_at timestamptz NOT NULL DEFAULT now())"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_RESHARDS, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_RESHARD_CHECKPOINTS} (fingerprint text NOT NULL, source_shard text NOT NULL, conversation_id text NOT NULL, message_id bigint NOT NULL, copied bigint NOT NULL, finished boolean NOT NULL, updated_at timestamptz NOT NULL DEFAULT now(), PRIMARY KEY (fingerprint, source_shard))"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_RESHARD_CHECKPOINTS, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_COUNTER_DELIVERIES} (idempotency_key text PRIMARY KEY, applied_at timestamptz NOT NULL DEFAULT now())"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_COUNTER_DELIVERIES, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_UNREAD_COUNTERS} (user_id text NOT NULL, other_user_id text NOT NULL, unread bigint NOT NULL, updated_at timestamptz NOT NULL DEFAULT clock_timestamp(), PRIMARY KEY (user_id, other_user_id))"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_UNREAD_COUNTERS, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_GROUPS} (id text PRIMARY KEY, name text NOT NULL, created_by text REFERENCES {TABLE_USERS} (id) ON DELETE SET NULL, created_at timestamptz NOT NULL DEFAULT now())"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_GROUPS, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_GROUP_MEMBERS} (group_id text NOT NULL REFERENCES {TABLE_GROUPS} (id) ON DELETE CASCADE, user_id text NOT NULL REFERENCES {TABLE_USERS} (id) ON DELETE CASCADE, role text NOT NULL, joined_at timestamptz NOT NULL DEFAULT now(), PRIMARY KEY (group_id, user_id))"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_GROUP_MEMBERS, e))?;
+        client
+            .query(&format!("create index if not exists {TABLE_GROUP_MEMBERS}_user_idx on {TABLE_GROUP_MEMBERS} (user_id)"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create user index on '{}': {}", TABLE_GROUP_MEMBERS, e))?;
+        client
+            .query(&format!("create table if not exists {TABLE_GROUP_MESSAGES} (id bigserial PRIMARY KEY, group_id text NOT NULL REFERENCES {TABLE_GROUPS} (id) ON DELETE CASCADE, from_user_id text NOT NULL, text text NOT NULL, created_at timestamptz NOT NULL DEFAULT now())"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_GROUP_MESSAGES, e))?;
+        client
+            .query(&format!("create index if not exists {TABLE_GROUP_MESSAGES}_group_idx on {TABLE_GROUP_MESSAGES} (group_id, id DESC)"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create group index on '{}': {}", TABLE_GROUP_MESSAGES, e))?;
+        DB::create_btree_gin(client).await?;
+        client
+            .query(&format!("create index if not exists {TABLE_GROUP_MESSAGES}_text_idx on {TABLE_GROUP_MESSAGES} using gin (group_id, to_tsvector('{MESSAGES_TEXT_SEARCH_CONFIG}', text))"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create text index on '{}': {}", TABLE_GROUP_MESSAGES, e))?;
+        client
+            .query(
+                &format!("create sequence if not exists {SEQUENCE_MESSAGE_IDS}"),
+                &[],
+            )
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "failed to create sequence '{}': {}",
+                    SEQUENCE_MESSAGE_IDS,
+                    e
+                )
+            })?;
+        DB::apply_messages_migrations(client).await
+    }
+
+    /// Lets a text index lead with a plain column, so it's searched within the column value.
+    async fn create_btree_gin(client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        client
+            .query("create extension if not exists btree_gin", &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create extension 'btree_gin': {}", e))?;
         Ok(())
     }
+
+    async fn apply_messages_migrations(client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        client
+            .query(&format!("create table if not exists {TABLE_MESSAGES} (id bigint NOT NULL, conversation_id text NOT NULL, from_user_id text NOT NULL, to_user_id text NOT NULL, text text NOT NULL, created_at timestamptz NOT NULL DEFAULT now(), PRIMARY KEY (conversation_id, id))"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_MESSAGES, e))?;
+        // messages of a database created before sharding are keyed by id alone, the primary
+        // key covers the conversation index they had then
+        client
+            .batch_execute(&format!(
+                "do $$ begin
+                    if exists (select 1 from pg_index where indrelid = '{TABLE_MESSAGES}'::regclass and indisprimary and indnatts = 1) then
+                        alter table {TABLE_MESSAGES} drop constraint {TABLE_MESSAGES}_pkey, add primary key (conversation_id, id);
+                    end if;
+                end $$;
+                drop index if exists {TABLE_MESSAGES}_conversation_idx;"
+            ))
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to key '{}' by conversation: {}", TABLE_MESSAGES, e))?;
+        // a search only reads the index entries of the user, as the sender or as the recipient
+        DB::create_btree_gin(client).await?;
+        for column in ["from_user_id", "to_user_id"] {
+            client
+                .query(&format!("create index if not exists {TABLE_MESSAGES}_{column}_text_idx on {TABLE_MESSAGES} using gin ({column}, to_tsvector('{MESSAGES_TEXT_SEARCH_CONFIG}', text))"), &[])
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to create text index by {} on '{}': {}", column, TABLE_MESSAGES, e))?;
+        }
+        client
+            .query(
+                &format!("drop index if exists {TABLE_MESSAGES}_text_idx"),
+                &[],
+            )
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!("failed to drop text index on '{}': {}", TABLE_MESSAGES, e)
+            })?;
+        client
+            .query(&format!("create table if not exists {TABLE_MESSAGE_READS} (conversation_id text NOT NULL, user_id text NOT NULL, last_read_id bigint NOT NULL, PRIMARY KEY (conversation_id, user_id))"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_MESSAGE_READS, e))?;
+        client
+            .query(
+                &format!("alter table {TABLE_MESSAGE_READS} add column if not exists last_delivered_id bigint NOT NULL DEFAULT 0"),
+                &[],
+            )
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!("failed to add last_delivered_id to '{}': {}", TABLE_MESSAGE_READS, e)
+            })?;
+        client
+            .query(&format!("create table if not exists {TABLE_MESSAGE_OUTBOX} (id bigserial PRIMARY KEY, idempotency_key text NOT NULL UNIQUE, kind text NOT NULL, conversation_id text NOT NULL, user_id text NOT NULL, other_user_id text NOT NULL, message_id bigint NOT NULL, previous_id bigint, delta bigint NOT NULL, attempts integer NOT NULL DEFAULT 0, last_error text, next_attempt_at timestamptz NOT NULL DEFAULT now(), created_at timestamptz NOT NULL DEFAULT now())"), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to create table '{}': {}", TABLE_MESSAGE_OUTBOX, e))?;
+        client
+            .query(
+                &format!("alter table {TABLE_MESSAGE_OUTBOX} add column if not exists dead_at timestamptz"),
+                &[],
+            )
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!("failed to add dead_at to '{}': {}", TABLE_MESSAGE_OUTBOX, e)
+            })?;
+        Ok(())
+    }
+}
+
+impl Node {
+    fn new(pg_config: &PgConfig, pool: Pool, role: Role) -> Self {
+        let name = pg_config.server_name();
+        Self {
+            status: Mutex::new(NodeStatus {
+                name: name.clone(),
+                role,
+                checked_at: None,
+                error: None,
+            }),
+            name,
+            pool,
+        }
+    }
+
+    fn role(&self) -> Role {
+        self.status.lock().unwrap().role
+    }
+
+    async fn check(&self) {
+        let result = async {
+            let client = self.pool.get().await?;
+            let row = client
+                .query_one("select pg_is_in_recovery()", &[])
+                .await
+                .map_err(|e| {
+                    self.pool
+                        .failed(anyhow::anyhow!("failed to check recovery: {}", e))
+                })?;
+            anyhow::Ok(row.get::<_, bool>(0))
+        }
+        .await;
+        let (role, error) = match result {
+            Ok(false) => (Role::Primary, None),
+            Ok(true) => (Role::Standby, None),
+            Err(err) => (Role::Down, Some(err.to_string())),
+        };
+        let mut status = self.status.lock().unwrap();
+        if status.role != role {
+            match &error {
+                None => tracing::info!("postgres {} is {} now", self.name, role.as_str()),
+                Some(err) => tracing::warn!("postgres {} is down: {}", self.name, err),
+            }
+        }
+        status.role = role;
+        status.error = error;
+        status.checked_at =
+            Some(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    }
+}
+
+impl Replica {
+    fn new(conn_string: &str, config: &Config) -> anyhow::Result<Self> {
+        let pg_config = DB::with_dbname(&PgConfig::parse(conn_string)?);
+        let pool = DB::create_pool(
+            &pg_config,
+            &config.tls,
+            config.pool_size,
+            Some(CONNECT_TIMEOUT),
+            config.timeouts,
+        )?;
+        Ok(Self {
+            name: pg_config.server_name(),
+            pool: Pool::new(&pg_config, pool),
+            healthy: AtomicBool::new(false),
+            replayed: AtomicU64::new(0),
+        })
+    }
+
+    async fn check(&self) {
+        let result = async {
+            let client = self.pool.get().await?;
+            self.replay_lsn(&client).await
+        }
+        .await;
+        let healthy = result.is_ok();
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            match result {
+                Ok(_) => tracing::info!("replica {} is up", self.name),
+                Err(err) => tracing::warn!("replica {} is down: {}", self.name, err),
+            }
+        } else if let Err(err) = result {
+            tracing::debug!("replica {} is still down: {}", self.name, err);
+        }
+    }
+
+    fn replayed(&self) -> Lsn {
+        Lsn(self.replayed.load(Ordering::Relaxed))
+    }
+
+    /// Asks the replica how far it has replayed the log of the main database. A server that
+    /// isn't in recovery is up to date with itself.
+    async fn replay_lsn(&self, client: &deadpool_postgres::Object) -> anyhow::Result<Lsn> {
+        let statement = "select (case when pg_is_in_recovery() then pg_last_wal_replay_lsn() else pg_current_wal_lsn() end - '0/0')::bigint";
+        let row = client.query_one(statement, &[]).await.map_err(|e| {
+            self.pool.failed(anyhow::anyhow!(
+                "failed to read WAL position of replica {}: {}",
+                self.name,
+                e
+            ))
+        })?;
+        let replayed = row.get::<_, Option<i64>>(0).unwrap_or(0) as u64;
+        self.replayed.fetch_max(replayed, Ordering::Relaxed);
+        Ok(Lsn(replayed))
+    }
+
+    /// A replica that has replayed everything doesn't lag, however long ago the last
+    /// transaction was.
+    async fn lag(&self, primary_lsn: Option<Lsn>) -> anyhow::Result<Lag> {
+        let client = self.pool.get().await?;
+        let replayed = self.replay_lsn(&client).await?;
+        let statement =
+            "select extract(epoch from now() - pg_last_xact_replay_timestamp())::float8";
+        let row = client.query_one(statement, &[]).await.map_err(|e| {
+            self.pool.failed(anyhow::anyhow!(
+                "failed to read replay delay of replica {}: {}",
+                self.name,
+                e
+            ))
+        })?;
+        let caught_up = primary_lsn.is_some_and(|primary_lsn| replayed >= primary_lsn);
+        Ok(Lag {
+            bytes: primary_lsn.map(|primary_lsn| primary_lsn.0.saturating_sub(replayed.0)),
+            seconds: if caught_up { Some(0.0) } else { row.get(0) },
+        })
+    }
+}
+
+impl Pool {
+    fn new(pg_config: &PgConfig, inner: deadpool_postgres::Pool) -> Self {
+        Self {
+            server: pg_config.server_name(),
+            inner,
+            last_error: Mutex::new(None),
+        }
+    }
+
+    async fn get(&self) -> anyhow::Result<deadpool_postgres::Object> {
+        use deadpool::managed::{PoolError, TimeoutType};
+        use tokio_postgres::error::SqlState;
+
+        self.inner.get().await.map_err(|e| {
+            let err = match e {
+                PoolError::Timeout(TimeoutType::Wait) => anyhow::Error::new(Saturated {
+                    server: self.server.clone(),
+                    retry_after: self.saturated_retry_after(),
+                }),
+                PoolError::Backend(e) if e.code() == Some(&SqlState::TOO_MANY_CONNECTIONS) => {
+                    anyhow::Error::new(e).context(Saturated {
+                        server: self.server.clone(),
+                        retry_after: self.saturated_retry_after(),
+                    })
+                }
+                PoolError::Backend(e) => query_error("failed to get object from pg pool", e),
+                e => anyhow::anyhow!("failed to get object from pg pool: {}", e),
+            };
+            self.failed(err)
+        })
+    }
+
+    /// About as long as a connection has just been waited for, whole seconds as
+    /// Retry-After has them.
+    fn saturated_retry_after(&self) -> Duration {
+        match self.inner.timeouts().wait {
+            Some(wait) => Duration::from_secs(wait.as_secs_f64().ceil().max(1.0) as u64),
+            None => SATURATED_RETRY_AFTER,
+        }
+    }
+
+    /// Remembers the error as the last one of the server.
+    fn failed(&self, err: anyhow::Error) -> anyhow::Error {
+        *self.last_error.lock().unwrap() = Some(PoolError {
+            message: err.to_string(),
+            at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        });
+        err
+    }
+
+    fn stats(&self, name: &str, role: &'static str, lag: Option<Lag>) -> PoolStats {
+        let status = self.inner.status();
+        PoolStats {
+            name: name.to_owned(),
+            role,
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+            waiting: status.waiting,
+            lag,
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Two writable servers trusting user postgres, the main one is reached through a proxy
+    /// the test can cut off.
+    const PRIMARY_ADDR: &str = "127.0.0.1:5432";
+    const CANDIDATE: &str = "host=127.0.0.1 port=5435 user=postgres";
+
+    /// Forwards the connections to `upstream` until the returned task is aborted, which
+    /// drops them all.
+    async fn proxy(upstream: &'static str) -> (u16, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proxy = tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                connections.spawn(async move {
+                    let mut server = TcpStream::connect(upstream).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut server).await
+                });
+            }
+        });
+        (port, proxy)
+    }
+
+    #[test]
+    fn lsn_round_trips_through_its_text() {
+        for text in ["0/0", "16/B374D848", "FFFFFFFF/FFFFFFFF", "0/1"] {
+            let lsn: Lsn = text.parse().unwrap();
+            assert_eq!(lsn.to_string(), text);
+        }
+        assert_eq!(
+            "16/b374d848".parse::<Lsn>().unwrap().to_string(),
+            "16/B374D848"
+        );
+        assert_eq!(
+            "16/B374D848".parse::<Lsn>().unwrap(),
+            Lsn(0x16 << 32 | 0xB374D848)
+        );
+        assert!("1/0".parse::<Lsn>().unwrap() > "0/FFFFFFFF".parse::<Lsn>().unwrap());
+    }
+
+    #[test]
+    fn malformed_lsn_is_rejected() {
+        for text in [
+            "",
+            "16",
+            "16/",
+            "/B3",
+            "16/B3/0",
+            "G/0",
+            "+1/0",
+            "1/-0",
+            "100000000/0",
+            " 1/0",
+        ] {
+            assert!(text.parse::<Lsn>().is_err(), "{text:?} parsed");
+        }
+    }
+
+    async fn write_port(db: &DB) -> i32 {
+        db.get()
+            .await
+            .unwrap()
+            .query_one("select current_setting('port')::int", &[])
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs postgres on ports 5432 and 5435, run with --ignored"]
+    async fn writes_fail_over_to_candidate_once_primary_is_down() {
+        let (port, primary) = proxy(PRIMARY_ADDR).await;
+        let config = Config {
+            conn_string: format!("host=127.0.0.1 port={port} user=postgres"),
+            failover_candidates: vec![CANDIDATE.to_owned()],
+            replicas: Vec::new(),
+            pool_size: 2,
+            tls: TlsConfig::default(),
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default(),
+        };
+        let db = Arc::new(DB::new(&config).await.unwrap());
+        assert_eq!(write_port(&db).await, 5432);
+        db.spawn_workers();
+
+        primary.abort();
+        for _ in 0..5 {
+            tokio::time::sleep(FAILOVER_CHECK_INTERVAL).await;
+            if db.topology().failovers > 0 {
+                break;
+            }
+        }
+        let topology = db.topology();
+        assert_eq!(topology.failovers, 1);
+        assert_eq!(topology.primary, db.nodes[1].name);
+        assert_eq!(write_port(&db).await, 5435);
+    }
 }
@@ -0,0 +1,130 @@
+use crate::db;
+
+/// Unread messages of a user in the dialog with another user.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Counter {
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(rename = "user_id")]
+    pub other_user_id: String,
+    pub unread: i64,
+}
+
+impl Counter {
+    /// Dialogs of the user having unread messages.
+    pub async fn list(user_id: &str, client: &tokio_postgres::Client) -> anyhow::Result<Vec<Self>> {
+        let statement = "select user_id, other_user_id, unread from unread_counters where user_id = $1 and unread > 0 order by other_user_id";
+        let rows = client
+            .query(statement, &[&user_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read unread counters: {}", e))?;
+        Ok(rows.iter().map(Counter::from_row).collect())
+    }
+
+    /// Adds `delta` to the counter unless the change with that idempotency key has been
+    /// applied already. Must be called within a transaction. Returns whether it was applied.
+    pub async fn apply(
+        user_id: &str,
+        other_user_id: &str,
+        delta: i64,
+        idempotency_key: &str,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<bool> {
+        let statement =
+            "INSERT INTO counter_deliveries (idempotency_key) VALUES ($1) ON CONFLICT DO NOTHING";
+        let inserted = client
+            .execute(statement, &[&idempotency_key])
+            .await
+            .map_err(|e| db::query_error("failed to record counter delivery", e))?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        let statement = "INSERT INTO unread_counters (user_id, other_user_id, unread) VALUES ($1, $2, greatest($3::bigint, 0)) ON CONFLICT (user_id, other_user_id) DO UPDATE SET unread = greatest(unread_counters.unread + $3::bigint, 0), updated_at = clock_timestamp()";
+        client
+            .execute(statement, &[&user_id, &other_user_id, &delta])
+            .await
+            .map_err(|e| db::query_error("failed to update unread counter", e))?;
+        Ok(true)
+    }
+
+    /// Forgets the idempotency keys of the changes applied long ago, an outbox entry
+    /// is either delivered or rolled back well before that.
+    pub async fn prune_deliveries(client: &tokio_postgres::Client) -> anyhow::Result<u64> {
+        let statement =
+            "DELETE FROM counter_deliveries WHERE applied_at < now() - interval '1 day'";
+        client
+            .execute(statement, &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to prune counter deliveries: {}", e))
+    }
+
+    /// Sets the counter to a value computed from the messages at `computed_at`, unless it
+    /// has been updated since then. Returns whether the counter has been changed.
+    pub async fn fix(
+        &self,
+        computed_at: &str,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<bool> {
+        let statement = "INSERT INTO unread_counters (user_id, other_user_id, unread) VALUES ($1, $2, $3) ON CONFLICT (user_id, other_user_id) DO UPDATE SET unread = $3, updated_at = clock_timestamp() WHERE unread_counters.unread <> $3 AND unread_counters.updated_at < $4::text::timestamptz";
+        let changed = client
+            .execute(
+                statement,
+                &[
+                    &self.user_id,
+                    &self.other_user_id,
+                    &self.unread,
+                    &computed_at,
+                ],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to fix unread counter: {}", e))?;
+        Ok(changed == 1)
+    }
+
+    /// Stored counters of the given dialogs, missing ones are not returned.
+    pub async fn of_dialogs(
+        dialogs: &[(String, String)],
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let (user_ids, other_user_ids): (Vec<&String>, Vec<&String>) =
+            dialogs.iter().map(|(user, other)| (user, other)).unzip();
+        let statement = "select user_id, other_user_id, unread from unread_counters where (user_id, other_user_id) in (select * from unnest($1::text[], $2::text[]))";
+        let rows = client
+            .query(statement, &[&user_ids, &other_user_ids])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read unread counters: {}", e))?;
+        Ok(rows.iter().map(Counter::from_row).collect())
+    }
+
+    /// Non-zero counters in `(user_id, other_user_id)` order, starting right after the given one.
+    pub async fn batch_after(
+        user_id: &str,
+        other_user_id: &str,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select user_id, other_user_id, unread from unread_counters where unread > 0 and (user_id, other_user_id) > ($1, $2) order by user_id, other_user_id limit $3";
+        let rows = client
+            .query(statement, &[&user_id, &other_user_id, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read unread counters: {}", e))?;
+        Ok(rows.iter().map(Counter::from_row).collect())
+    }
+
+    /// Current time of the database the counters are kept in, to be passed to `fix`.
+    pub async fn now(client: &tokio_postgres::Client) -> anyhow::Result<String> {
+        let row = client
+            .query_one("select clock_timestamp()::text", &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read time: {}", e))?;
+        Ok(row.get(0))
+    }
+
+    pub fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            user_id: row.get(0),
+            other_user_id: row.get(1),
+            unread: row.get(2),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::db_post;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Created => "created",
+            Kind::Updated => "updated",
+            Kind::Deleted => "deleted",
+        }
+    }
+
+    fn parse(kind: &str) -> anyhow::Result<Self> {
+        match kind {
+            "created" => Ok(Kind::Created),
+            "updated" => Ok(Kind::Updated),
+            "deleted" => Ok(Kind::Deleted),
+            _ => anyhow::bail!("unknown feed event kind '{}'", kind),
+        }
+    }
+}
+
+/// A post change waiting to be fanned out to the followers' feeds.
+#[derive(Debug, Clone)]
+pub struct FeedEvent {
+    pub id: i64,
+    pub kind: Kind,
+    pub post: db_post::Post,
+    /// Failed fan-outs so far.
+    pub attempts: i32,
+}
+
+impl FeedEvent {
+    /// Must be called within the transaction changing the post, so the event is
+    /// queued if and only if the change is committed.
+    pub async fn enqueue(
+        kind: Kind,
+        post: &db_post::Post,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement =
+            "INSERT INTO feed_events (kind, post_id, author_user_id, text) VALUES ($1, $2, $3, $4)";
+        client
+            .execute(
+                statement,
+                &[&kind.as_str(), &post.id, &post.author_user_id, &post.text],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to enqueue feed event: {}", e))?;
+        Ok(())
+    }
+
+    /// Locks up to `limit` of the oldest events due for delivery for the current transaction.
+    /// Events already taken by other workers are skipped, and so are events of authors whose
+    /// earlier events are being processed elsewhere or wait for a retry, which keeps the events
+    /// of one author in order. Dead events are left alone.
+    ///
+    /// The candidates are picked and row locked first, so the author advisory locks are taken
+    /// only for the events actually returned rather than for every row the scan touches.
+    pub async fn lock_batch(
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "with candidates as materialized (
+                select id, kind, post_id, text, author_user_id, attempts from feed_events
+                where dead_at is null and next_attempt_at <= now()
+                order by id limit $1 for update skip locked
+            ), first_of_author as materialized (
+                select * from candidates c where not exists (
+                    select 1 from feed_events earlier
+                    where earlier.author_user_id = c.author_user_id and earlier.id < c.id
+                    and earlier.dead_at is null and earlier.id not in (select id from candidates)
+                )
+            )
+            select id, kind, post_id, text, author_user_id, attempts from first_of_author
+            where pg_try_advisory_xact_lock(hashtext(author_user_id)) order by id";
+        let rows = client
+            .query(statement, &[&(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to lock feed events: {}", e))?;
+        rows.iter().map(FeedEvent::from_row).collect()
+    }
+
+    pub async fn retry_later(
+        &self,
+        error: &str,
+        delay: std::time::Duration,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "UPDATE feed_events SET attempts = attempts + 1, last_error = $2, next_attempt_at = now() + make_interval(secs => $3) WHERE id = $1";
+        client
+            .execute(statement, &[&self.id, &error, &delay.as_secs_f64()])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to postpone feed event {}: {}", self.id, e))?;
+        Ok(())
+    }
+
+    /// Gives up on fanning the event out, it's kept for inspection. The feeds it hasn't
+    /// reached are read from postgres once they're dropped from the cache.
+    pub async fn park(&self, error: &str, client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        let statement = "UPDATE feed_events SET attempts = attempts + 1, last_error = $2, dead_at = now() WHERE id = $1";
+        client
+            .execute(statement, &[&self.id, &error])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to park feed event {}: {}", self.id, e))?;
+        Ok(())
+    }
+
+    pub async fn delete(ids: &[i64], client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        let statement = "DELETE FROM feed_events WHERE id = ANY($1)";
+        client
+            .execute(statement, &[&ids])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to delete feed events: {}", e))?;
+        Ok(())
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: row.get(0),
+            kind: Kind::parse(row.get(1))?,
+            post: db_post::Post {
+                id: row.get(2),
+                text: row.get(3),
+                author_user_id: row.get(4),
+            },
+            attempts: row.get(5),
+        })
+    }
+}
@@ -0,0 +1,98 @@
+pub struct Friend {}
+
+impl Friend {
+    pub async fn add(
+        user_id: &String,
+        friend_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement =
+            "INSERT INTO friends (user_id, friend_id) VALUES ($1, $2) ON CONFLICT DO NOTHING";
+        client
+            .execute(statement, &[user_id, friend_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to add friend {}: {}", friend_id, e))?;
+        Ok(())
+    }
+
+    pub async fn delete(
+        user_id: &String,
+        friend_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "DELETE FROM friends WHERE user_id = $1 AND friend_id = $2";
+        client
+            .execute(statement, &[user_id, friend_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to delete friend {}: {}", friend_id, e))?;
+        Ok(())
+    }
+
+    /// Users who have `user_id` in their friend list, i.e. whose feeds show `user_id` posts.
+    /// Ordered by id and read `limit` at a time, starting after `after`.
+    pub async fn followers_batch(
+        user_id: &String,
+        after: &String,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<String>> {
+        let statement = "select user_id from friends where friend_id = $1 and user_id > $2 order by user_id limit $3";
+        let rows = client
+            .query(statement, &[user_id, after, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read followers of {}: {}", user_id, e))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Whether either of the users has the other one in the friend list.
+    pub async fn either_way(
+        user_id: &str,
+        other_user_id: &str,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<bool> {
+        let statement = "select exists (select 1 from friends where (user_id = $1 and friend_id = $2) or (user_id = $2 and friend_id = $1))";
+        let row = client
+            .query_one(statement, &[&user_id, &other_user_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to check friendship of {}: {}", user_id, e))?;
+        Ok(row.get(0))
+    }
+
+    pub async fn followers_count(
+        user_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<i64> {
+        let statement = "select count(*) from friends where friend_id = $1";
+        let row = client
+            .query_one(statement, &[user_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to count followers of {}: {}", user_id, e))?;
+        Ok(row.get(0))
+    }
+
+    pub async fn friends_of(
+        user_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<String>> {
+        let statement = "select friend_id from friends where user_id = $1";
+        let rows = client
+            .query(statement, &[user_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read friends of {}: {}", user_id, e))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Those of `users` who have `user_id` in their friend list.
+    pub async fn followers_among(
+        user_id: &String,
+        users: &[String],
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<String>> {
+        let statement = "select user_id from friends where friend_id = $1 and user_id = ANY($2)";
+        let rows = client
+            .query(statement, &[user_id, &users])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read followers of {}: {}", user_id, e))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+}
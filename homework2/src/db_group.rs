@@ -0,0 +1,292 @@
+use crate::db_message;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Member,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Member => "member",
+        }
+    }
+
+    fn parse(role: &str) -> anyhow::Result<Self> {
+        match role {
+            "admin" => Ok(Role::Admin),
+            "member" => Ok(Role::Member),
+            _ => anyhow::bail!("unknown group role '{}'", role),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub created_by: Option<String>,
+}
+
+impl Group {
+    pub async fn insert_to_db(self, client: &tokio_postgres::Client) -> anyhow::Result<Self> {
+        let statement = "INSERT INTO groups (id, name, created_by) VALUES ($1, $2, $3)";
+        client
+            .execute(statement, &[&self.id, &self.name, &self.created_by])
+            .await
+            .map_err(|e| anyhow::anyhow!("insert group failed: {}", e))?;
+        Ok(self)
+    }
+
+    pub async fn from_id(
+        id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Option<Self>> {
+        let statement = "select id, name, created_by from groups where id = $1";
+        let rows = client
+            .query(statement, &[&id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read group: {}", e))?;
+        Ok(rows.last().map(Group::from_row))
+    }
+
+    /// Locks the group for the current transaction, so that membership changes checking
+    /// the admins of the group run one at a time.
+    pub async fn lock(id: &String, client: &tokio_postgres::Client) -> anyhow::Result<bool> {
+        let statement = "select id from groups where id = $1 for update";
+        let rows = client
+            .query(statement, &[&id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to lock group {}: {}", id, e))?;
+        Ok(!rows.is_empty())
+    }
+
+    /// Groups the user is a member of, by name.
+    pub async fn of_user(
+        user_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select g.id, g.name, g.created_by from groups g join group_members m on m.group_id = g.id where m.user_id = $1 order by g.name, g.id";
+        let rows = client
+            .query(statement, &[user_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read groups of {}: {}", user_id, e))?;
+        Ok(rows.iter().map(Group::from_row).collect())
+    }
+
+    pub async fn rename(
+        id: &String,
+        name: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "UPDATE groups SET name = $1 WHERE id = $2";
+        client
+            .execute(statement, &[name, id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to rename group {}: {}", id, e))?;
+        Ok(())
+    }
+
+    pub async fn delete(id: &String, client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        client
+            .execute("DELETE FROM groups WHERE id = $1", &[id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to delete group {}: {}", id, e))?;
+        Ok(())
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            name: row.get(1),
+            created_by: row.get(2),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Member {
+    pub user_id: String,
+    pub role: Role,
+}
+
+impl Member {
+    /// Adds the existing users among `user_ids` to the group, keeping the role of those
+    /// who are members already. Returns the number of users found.
+    pub async fn add(
+        group_id: &String,
+        user_ids: &[String],
+        role: Role,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<u64> {
+        let statement = "INSERT INTO group_members (group_id, user_id, role) SELECT $1, id, $3 FROM users WHERE id = any($2) ON CONFLICT (group_id, user_id) DO NOTHING";
+        client
+            .execute(statement, &[group_id, &user_ids, &role.as_str()])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to add members to group {}: {}", group_id, e))?;
+        let statement = "select count(*) from users where id = any($1)";
+        let row = client
+            .query_one(statement, &[&user_ids])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to count users: {}", e))?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    pub async fn remove(
+        group_id: &String,
+        user_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<bool> {
+        let statement = "DELETE FROM group_members WHERE group_id = $1 AND user_id = $2";
+        let removed = client
+            .execute(statement, &[group_id, user_id])
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "failed to remove {} from group {}: {}",
+                    user_id,
+                    group_id,
+                    e
+                )
+            })?;
+        Ok(removed == 1)
+    }
+
+    pub async fn set_role(
+        group_id: &String,
+        user_id: &String,
+        role: Role,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<bool> {
+        let statement = "UPDATE group_members SET role = $3 WHERE group_id = $1 AND user_id = $2";
+        let updated = client
+            .execute(statement, &[group_id, user_id, &role.as_str()])
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "failed to change role of {} in group {}: {}",
+                    user_id,
+                    group_id,
+                    e
+                )
+            })?;
+        Ok(updated == 1)
+    }
+
+    pub async fn get(
+        group_id: &String,
+        user_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Option<Self>> {
+        let statement =
+            "select user_id, role from group_members where group_id = $1 and user_id = $2";
+        let rows = client
+            .query(statement, &[group_id, user_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read group member: {}", e))?;
+        rows.last().map(Member::from_row).transpose()
+    }
+
+    /// Members of the group, admins first.
+    pub async fn list(
+        group_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select user_id, role from group_members where group_id = $1 order by role = 'admin' desc, joined_at, user_id";
+        let rows = client
+            .query(statement, &[group_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read members of group {}: {}", group_id, e))?;
+        rows.iter().map(Member::from_row).collect()
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            user_id: row.get(0),
+            role: Role::parse(row.get(1))?,
+        })
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct GroupMessage {
+    pub id: i64,
+    #[serde(rename = "from")]
+    pub from_user_id: String,
+    pub text: String,
+}
+
+impl GroupMessage {
+    pub async fn insert_to_db(
+        group_id: &String,
+        from_user_id: &String,
+        text: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Self> {
+        let statement = "INSERT INTO group_messages (group_id, from_user_id, text) VALUES ($1, $2, $3) RETURNING id";
+        let row = client
+            .query_one(statement, &[group_id, from_user_id, text])
+            .await
+            .map_err(|e| anyhow::anyhow!("insert group message failed: {}", e))?;
+        Ok(Self {
+            id: row.get(0),
+            from_user_id: from_user_id.clone(),
+            text: text.clone(),
+        })
+    }
+
+    /// Messages of the groups of the user matching the full-text query, newest first,
+    /// highlighted the same way as the dialog messages.
+    pub async fn search(
+        user_id: &str,
+        query: &str,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<db_message::Found>> {
+        let statement = "select gm.id, gm.from_user_id, gm.text, gm.group_id, ts_headline('russian', replace(replace(replace(gm.text, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), q, 'StartSel=<b>, StopSel=</b>, HighlightAll=true'), (extract(epoch from gm.created_at) * 1000000)::bigint from group_members m join group_messages gm on gm.group_id = m.group_id, websearch_to_tsquery('russian', $2) q where m.user_id = $1 and to_tsvector('russian', gm.text) @@ q order by gm.created_at desc, gm.id desc limit $3";
+        let rows = client
+            .query(statement, &[&user_id, &query, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to search group messages: {}", e))?;
+        Ok(rows
+            .iter()
+            .map(|row| db_message::Found {
+                message: db_message::FoundMessage::Group {
+                    group_id: row.get(3),
+                    message: Self {
+                        id: row.get(0),
+                        from_user_id: row.get(1),
+                        text: row.get(2),
+                    },
+                },
+                highlight: row.get(4),
+                sent_at: row.get(5),
+            })
+            .collect())
+    }
+
+    /// Messages of the group newest first.
+    pub async fn list(
+        group_id: &String,
+        offset: usize,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select id, from_user_id, text from group_messages where group_id = $1 order by id desc offset $2 limit $3";
+        let rows = client
+            .query(statement, &[group_id, &(offset as i64), &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read group {}: {}", group_id, e))?;
+        Ok(rows
+            .iter()
+            .map(|row| Self {
+                id: row.get(0),
+                from_user_id: row.get(1),
+                text: row.get(2),
+            })
+            .collect())
+    }
+}
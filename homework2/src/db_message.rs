@@ -0,0 +1,500 @@
+use crate::{db_counter, db_group};
+use std::collections::HashMap;
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Message {
+    pub id: i64,
+    #[serde(rename = "from")]
+    pub from_user_id: String,
+    #[serde(rename = "to")]
+    pub to_user_id: String,
+    pub text: String,
+    /// Kept as text, only to be carried over when the message is copied to another shard.
+    #[serde(skip)]
+    pub created_at: String,
+    /// Set when the message is listed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<State>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Sent,
+    Delivered,
+    Read,
+}
+
+/// Message found by `search`.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Found {
+    #[serde(flatten)]
+    pub message: FoundMessage,
+    /// HTML-escaped text of the message with the matching words wrapped in `<b>`.
+    pub highlight: String,
+    /// Microseconds since the epoch, to merge the results of several shards.
+    #[serde(skip)]
+    pub sent_at: i64,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum FoundMessage {
+    Dialog(Message),
+    Group {
+        group_id: String,
+        #[serde(flatten)]
+        message: db_group::GroupMessage,
+    },
+}
+
+impl FoundMessage {
+    pub fn id(&self) -> i64 {
+        match self {
+            FoundMessage::Dialog(message) => message.id,
+            FoundMessage::Group { message, .. } => message.id,
+        }
+    }
+}
+
+/// Ids of the last messages of a conversation a user has received and read.
+#[derive(Debug, Clone)]
+pub struct ReadMarker {
+    pub conversation_id: String,
+    pub user_id: String,
+    pub last_read_id: i64,
+    pub last_delivered_id: i64,
+}
+
+impl ReadMarker {
+    /// State of a message sent to the user of the marker.
+    pub fn state(&self, message_id: i64) -> State {
+        if message_id <= self.last_read_id {
+            State::Read
+        } else if message_id <= self.last_delivered_id {
+            State::Delivered
+        } else {
+            State::Sent
+        }
+    }
+}
+
+/// Number of messages of a conversation and a digest over their content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub count: i64,
+    pub md5: String,
+}
+
+/// Both participants of a dialog get the same id whoever of them writes.
+pub fn conversation_id(user_id: &str, other_user_id: &str) -> String {
+    if user_id < other_user_id {
+        format!("{user_id}:{other_user_id}")
+    } else {
+        format!("{other_user_id}:{user_id}")
+    }
+}
+
+impl Message {
+    /// Takes the id of a new message from the main database, so the id is unique whatever
+    /// shard the message is stored on and keeps growing when a conversation moves.
+    pub async fn next_id(main_client: &tokio_postgres::Client) -> anyhow::Result<i64> {
+        let statement = "select nextval('message_ids')";
+        let row = main_client
+            .query_one(statement, &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to take message id: {}", e))?;
+        Ok(row.get(0))
+    }
+
+    pub async fn insert_to_db(
+        id: i64,
+        from_user_id: &String,
+        to_user_id: &String,
+        text: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Self> {
+        let statement = "INSERT INTO messages (id, conversation_id, from_user_id, to_user_id, text) VALUES ($1, $2, $3, $4, $5) RETURNING created_at::text";
+        let row = client
+            .query_one(
+                statement,
+                &[
+                    &id,
+                    &conversation_id(from_user_id, to_user_id),
+                    from_user_id,
+                    to_user_id,
+                    text,
+                ],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("insert message failed: {}", e))?;
+        Ok(Self {
+            id,
+            from_user_id: from_user_id.clone(),
+            to_user_id: to_user_id.clone(),
+            text: text.clone(),
+            created_at: row.get(0),
+            state: None,
+        })
+    }
+
+    /// Stores a message read from another shard under its original id, doing nothing
+    /// if it has already been copied. Returns whether the message was inserted.
+    pub async fn copy_to_db(&self, client: &tokio_postgres::Client) -> anyhow::Result<bool> {
+        let statement = "INSERT INTO messages (id, conversation_id, from_user_id, to_user_id, text, created_at) VALUES ($1, $2, $3, $4, $5, $6::text::timestamptz) ON CONFLICT DO NOTHING";
+        let inserted = client
+            .execute(
+                statement,
+                &[
+                    &self.id,
+                    &self.conversation_id(),
+                    &self.from_user_id,
+                    &self.to_user_id,
+                    &self.text,
+                    &self.created_at,
+                ],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("copy message {} failed: {}", self.id, e))?;
+        Ok(inserted == 1)
+    }
+
+    pub async fn conversation_exists(
+        conversation_id: &str,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<bool> {
+        let statement = "select exists (select 1 from messages where conversation_id = $1)";
+        let row = client
+            .query_one(statement, &[&conversation_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to look conversation up: {}", e))?;
+        Ok(row.get(0))
+    }
+
+    /// Messages of the dialog between the two users, newest first.
+    pub async fn list(
+        user_id: &str,
+        other_user_id: &str,
+        offset: usize,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select id, from_user_id, to_user_id, text, created_at::text from messages where conversation_id = $1 order by id desc offset $2 limit $3";
+        let rows = client
+            .query(
+                statement,
+                &[
+                    &conversation_id(user_id, other_user_id),
+                    &(offset as i64),
+                    &(limit as i64),
+                ],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read dialog: {}", e))?;
+        Ok(rows.iter().map(Message::from_row).collect())
+    }
+
+    /// Messages of the user's conversations matching the full-text query, newest first.
+    /// The messages sent and received are looked up separately, each by its own index.
+    /// The text is escaped before highlighting, so the highlight is safe to render as HTML.
+    pub async fn search(
+        user_id: &str,
+        query: &str,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Found>> {
+        let statement = "with q as (select websearch_to_tsquery('russian', $2) q), found as ((select m.* from messages m, q where m.from_user_id = $1 and to_tsvector('russian', m.text) @@ q.q order by m.created_at desc, m.id desc limit $3) union all (select m.* from messages m, q where m.to_user_id = $1 and m.from_user_id <> $1 and to_tsvector('russian', m.text) @@ q.q order by m.created_at desc, m.id desc limit $3)) select id, from_user_id, to_user_id, text, created_at::text, ts_headline('russian', replace(replace(replace(text, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), q.q, 'StartSel=<b>, StopSel=</b>, HighlightAll=true'), (extract(epoch from created_at) * 1000000)::bigint from found, q order by created_at desc, id desc limit $3";
+        let rows = client
+            .query(statement, &[&user_id, &query, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to search messages: {}", e))?;
+        Ok(rows
+            .iter()
+            .map(|row| Found {
+                message: FoundMessage::Dialog(Message::from_row(row)),
+                highlight: row.get(5),
+                sent_at: row.get(6),
+            })
+            .collect())
+    }
+
+    /// Messages of all conversations in `(conversation_id, id)` order, starting right
+    /// after the given position.
+    pub async fn batch_after(
+        conversation_id: &str,
+        id: i64,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select id, from_user_id, to_user_id, text, created_at::text from messages where (conversation_id, id) > ($1, $2) order by conversation_id, id limit $3";
+        let rows = client
+            .query(statement, &[&conversation_id, &id, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read messages batch: {}", e))?;
+        Ok(rows.iter().map(Message::from_row).collect())
+    }
+
+    /// Checksums of up to `limit` conversations following `after` in id order.
+    pub async fn checksums_after(
+        after: &str,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<(String, Checksum)>> {
+        let statement = "select conversation_id, count(*), md5(string_agg(id || ':' || from_user_id || ':' || to_user_id || ':' || text, '|' order by id)) from messages where conversation_id > $1 group by conversation_id order by conversation_id limit $2";
+        let rows = client
+            .query(statement, &[&after, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to checksum conversations: {}", e))?;
+        Ok(rows.iter().map(Message::checksum_from_row).collect())
+    }
+
+    pub async fn checksums(
+        conversation_ids: &[String],
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<HashMap<String, Checksum>> {
+        let statement = "select conversation_id, count(*), md5(string_agg(id || ':' || from_user_id || ':' || to_user_id || ':' || text, '|' order by id)) from messages where conversation_id = ANY($1) group by conversation_id";
+        let rows = client
+            .query(statement, &[&conversation_ids])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to checksum conversations: {}", e))?;
+        Ok(rows.iter().map(Message::checksum_from_row).collect())
+    }
+
+    /// Last message of the conversation the user has read, locking the marker
+    /// until the end of the transaction.
+    pub async fn last_read_for_update(
+        conversation_id: &str,
+        user_id: &str,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<i64> {
+        let statement = "select last_read_id from message_reads where conversation_id = $1 and user_id = $2 for update";
+        let row = client
+            .query_opt(statement, &[&conversation_id, &user_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read read marker: {}", e))?;
+        Ok(row.map(|row| row.get(0)).unwrap_or(0))
+    }
+
+    /// Id of the last message of the conversation sent to the user, not after `up_to`.
+    pub async fn last_received(
+        conversation_id: &str,
+        user_id: &str,
+        up_to: i64,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Option<i64>> {
+        let statement = "select max(id) from messages where conversation_id = $1 and to_user_id = $2 and id <= $3";
+        let row = client
+            .query_one(statement, &[&conversation_id, &user_id, &up_to])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to find received message: {}", e))?;
+        Ok(row.get(0))
+    }
+
+    /// Number of messages of the conversation sent to the user with ids in `(after, up_to]`.
+    pub async fn count_received(
+        conversation_id: &str,
+        user_id: &str,
+        after: i64,
+        up_to: i64,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<i64> {
+        let statement = "select count(*) from messages where conversation_id = $1 and to_user_id = $2 and id > $3 and id <= $4";
+        let row = client
+            .query_one(statement, &[&conversation_id, &user_id, &after, &up_to])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to count received messages: {}", e))?;
+        Ok(row.get(0))
+    }
+
+    pub async fn delete(
+        conversation_id: &str,
+        id: i64,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "DELETE FROM messages WHERE conversation_id = $1 AND id = $2";
+        client
+            .execute(statement, &[&conversation_id, &id])
+            .await
+            .map_err(|e| anyhow::anyhow!("delete message {} failed: {}", id, e))?;
+        Ok(())
+    }
+
+    /// Unread messages per recipient of the given conversations as the counters are to have
+    /// them, not counting the changes still waiting in the outbox. Conversations read up to
+    /// the end are not returned.
+    pub async fn unread(
+        conversation_ids: &[String],
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<db_counter::Counter>> {
+        let statement = "select m.to_user_id, m.from_user_id, count(*) - coalesce((select sum(o.delta) from message_outbox o where o.conversation_id = m.conversation_id and o.user_id = m.to_user_id and o.dead_at is null), 0)::bigint from messages m left join message_reads r on r.conversation_id = m.conversation_id and r.user_id = m.to_user_id where m.conversation_id = ANY($1) and m.id > coalesce(r.last_read_id, 0) group by m.conversation_id, m.to_user_id, m.from_user_id";
+        let rows = client
+            .query(statement, &[&conversation_ids])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to count unread messages: {}", e))?;
+        Ok(rows.iter().map(db_counter::Counter::from_row).collect())
+    }
+
+    /// Same as `unread` for every conversation, in `(conversation_id, to_user_id)` order
+    /// starting right after the given position.
+    pub async fn unread_after(
+        conversation_id: &str,
+        user_id: &str,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<(String, db_counter::Counter)>> {
+        let statement = "select m.to_user_id, m.from_user_id, count(*) - coalesce((select sum(o.delta) from message_outbox o where o.conversation_id = m.conversation_id and o.user_id = m.to_user_id and o.dead_at is null), 0)::bigint, m.conversation_id from messages m left join message_reads r on r.conversation_id = m.conversation_id and r.user_id = m.to_user_id where m.id > coalesce(r.last_read_id, 0) and (m.conversation_id, m.to_user_id) > ($1, $2) group by m.conversation_id, m.to_user_id, m.from_user_id order by m.conversation_id, m.to_user_id limit $3";
+        let rows = client
+            .query(statement, &[&conversation_id, &user_id, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to count unread messages: {}", e))?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(3), db_counter::Counter::from_row(row)))
+            .collect())
+    }
+
+    /// Last id taken from the sequence the shard used to assign ids with before they were
+    /// taken from the main database, 0 if there is none.
+    pub async fn legacy_last_id(client: &tokio_postgres::Client) -> anyhow::Result<i64> {
+        let statement = "select coalesce((select last_value from pg_sequences where schemaname = current_schema() and sequencename = 'messages_id_seq'), 0)";
+        let row = client
+            .query_one(statement, &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read messages id sequence: {}", e))?;
+        Ok(row.get(0))
+    }
+
+    /// Moves the message ids of the main database past `last_id`, returns the last id taken.
+    pub async fn skip_ids(
+        last_id: i64,
+        main_client: &tokio_postgres::Client,
+    ) -> anyhow::Result<i64> {
+        let statement =
+            "select setval('message_ids', greatest($1, (select last_value from message_ids)))";
+        let row = main_client
+            .query_one(statement, &[&last_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to move message ids: {}", e))?;
+        Ok(row.get(0))
+    }
+
+    pub fn conversation_id(&self) -> String {
+        conversation_id(&self.from_user_id, &self.to_user_id)
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            from_user_id: row.get(1),
+            to_user_id: row.get(2),
+            text: row.get(3),
+            created_at: row.get(4),
+            state: None,
+        }
+    }
+
+    fn checksum_from_row(row: &tokio_postgres::Row) -> (String, Checksum) {
+        (
+            row.get(0),
+            Checksum {
+                count: row.get(1),
+                md5: row.get(2),
+            },
+        )
+    }
+}
+
+impl ReadMarker {
+    /// Markers only move forward, so copies and late writes can't unread anything.
+    pub async fn save(&self, client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        let statement = "INSERT INTO message_reads (conversation_id, user_id, last_read_id, last_delivered_id) VALUES ($1, $2, $3, $4) ON CONFLICT (conversation_id, user_id) DO UPDATE SET last_read_id = greatest(message_reads.last_read_id, $3), last_delivered_id = greatest(message_reads.last_delivered_id, $4)";
+        client
+            .execute(
+                statement,
+                &[
+                    &self.conversation_id,
+                    &self.user_id,
+                    &self.last_read_id,
+                    &self.last_delivered_id,
+                ],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to save read marker: {}", e))?;
+        Ok(())
+    }
+
+    /// Moves the delivered marker of the user forward to `message_id`. Returns whether
+    /// it has moved, messages read are delivered already.
+    pub async fn deliver(
+        conversation_id: &str,
+        user_id: &str,
+        message_id: i64,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<bool> {
+        let statement = "INSERT INTO message_reads (conversation_id, user_id, last_read_id, last_delivered_id) VALUES ($1, $2, 0, $3) ON CONFLICT (conversation_id, user_id) DO UPDATE SET last_delivered_id = $3 WHERE message_reads.last_delivered_id < $3 AND message_reads.last_read_id < $3";
+        let moved = client
+            .execute(statement, &[&conversation_id, &user_id, &message_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to mark messages delivered: {}", e))?;
+        Ok(moved == 1)
+    }
+
+    /// Markers of both participants of the conversation, missing ones are not returned.
+    pub async fn of_conversation(
+        conversation_id: &str,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select conversation_id, user_id, last_read_id, last_delivered_id from message_reads where conversation_id = $1";
+        let rows = client
+            .query(statement, &[&conversation_id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read read markers: {}", e))?;
+        Ok(rows.iter().map(ReadMarker::from_row).collect())
+    }
+
+    /// Moves the marker back to `previous_id`, unless it has moved on since.
+    pub async fn reset(
+        &self,
+        previous_id: i64,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "UPDATE message_reads SET last_read_id = $4 WHERE conversation_id = $1 AND user_id = $2 AND last_read_id = $3";
+        client
+            .execute(
+                statement,
+                &[
+                    &self.conversation_id,
+                    &self.user_id,
+                    &self.last_read_id,
+                    &previous_id,
+                ],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to reset read marker: {}", e))?;
+        Ok(())
+    }
+
+    /// Read markers in `(conversation_id, user_id)` order, starting right after the given one.
+    pub async fn batch_after(
+        conversation_id: &str,
+        user_id: &str,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select conversation_id, user_id, last_read_id, last_delivered_id from message_reads where (conversation_id, user_id) > ($1, $2) order by conversation_id, user_id limit $3";
+        let rows = client
+            .query(statement, &[&conversation_id, &user_id, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read read markers: {}", e))?;
+        Ok(rows.iter().map(ReadMarker::from_row).collect())
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            conversation_id: row.get(0),
+            user_id: row.get(1),
+            last_read_id: row.get(2),
+            last_delivered_id: row.get(3),
+        }
+    }
+}
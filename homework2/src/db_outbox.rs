@@ -0,0 +1,136 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Sent,
+    Read,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Sent => "sent",
+            Kind::Read => "read",
+        }
+    }
+
+    fn parse(kind: &str) -> anyhow::Result<Self> {
+        match kind {
+            "sent" => Ok(Kind::Sent),
+            "read" => Ok(Kind::Read),
+            _ => anyhow::bail!("unknown outbox entry kind '{}'", kind),
+        }
+    }
+}
+
+/// Unread counter change waiting next to the message change that caused it, written in the
+/// same transaction on the message shard and relayed to the counters later.
+#[derive(Debug, Clone)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub kind: Kind,
+    pub conversation_id: String,
+    /// Whose counter changes: the recipient of a sent message or the reader.
+    pub user_id: String,
+    pub other_user_id: String,
+    /// The sent message, or the last message read.
+    pub message_id: i64,
+    /// The last message read before, to restore if the change is rolled back.
+    pub previous_id: Option<i64>,
+    pub delta: i64,
+    pub attempts: i32,
+}
+
+impl OutboxEntry {
+    /// Identifies the change across redeliveries, so it is counted once.
+    pub fn idempotency_key(&self) -> String {
+        match self.kind {
+            Kind::Sent => format!("sent:{}:{}", self.conversation_id, self.message_id),
+            Kind::Read => format!(
+                "read:{}:{}:{}",
+                self.conversation_id, self.user_id, self.message_id
+            ),
+        }
+    }
+
+    /// Must be called within the transaction changing the messages.
+    pub async fn enqueue(&self, client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        let statement = "INSERT INTO message_outbox (idempotency_key, kind, conversation_id, user_id, other_user_id, message_id, previous_id, delta) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (idempotency_key) DO NOTHING";
+        client
+            .execute(
+                statement,
+                &[
+                    &self.idempotency_key(),
+                    &self.kind.as_str(),
+                    &self.conversation_id,
+                    &self.user_id,
+                    &self.other_user_id,
+                    &self.message_id,
+                    &self.previous_id,
+                    &self.delta,
+                ],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to write message outbox: {}", e))?;
+        Ok(())
+    }
+
+    /// Locks up to `limit` of the oldest entries due for delivery for the current transaction.
+    /// Dead entries are left alone.
+    pub async fn lock_batch(
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select id, kind, conversation_id, user_id, other_user_id, message_id, previous_id, delta, attempts from message_outbox where dead_at is null and next_attempt_at <= now() order by id limit $1 for update skip locked";
+        let rows = client
+            .query(statement, &[&(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to lock message outbox: {}", e))?;
+        rows.iter().map(OutboxEntry::from_row).collect()
+    }
+
+    pub async fn delete(&self, client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        client
+            .execute("DELETE FROM message_outbox WHERE id = $1", &[&self.id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to delete outbox entry {}: {}", self.id, e))?;
+        Ok(())
+    }
+
+    pub async fn retry_later(
+        &self,
+        error: &str,
+        delay: std::time::Duration,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "UPDATE message_outbox SET attempts = attempts + 1, last_error = $2, next_attempt_at = now() + make_interval(secs => $3) WHERE id = $1";
+        client
+            .execute(statement, &[&self.id, &error, &delay.as_secs_f64()])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to postpone outbox entry {}: {}", self.id, e))?;
+        Ok(())
+    }
+
+    /// Gives up on delivering the entry, it's kept for inspection. The reconciliation counts
+    /// the message change of a dead entry like one that has been delivered.
+    pub async fn park(&self, error: &str, client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        let statement = "UPDATE message_outbox SET attempts = attempts + 1, last_error = $2, dead_at = now() WHERE id = $1";
+        client
+            .execute(statement, &[&self.id, &error])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to park outbox entry {}: {}", self.id, e))?;
+        Ok(())
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: row.get(0),
+            kind: Kind::parse(row.get(1))?,
+            conversation_id: row.get(2),
+            user_id: row.get(3),
+            other_user_id: row.get(4),
+            message_id: row.get(5),
+            previous_id: row.get(6),
+            delta: row.get(7),
+            attempts: row.get(8),
+        })
+    }
+}
@@ -0,0 +1,73 @@
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Post {
+    pub id: String,
+    pub text: String,
+    pub author_user_id: String,
+}
+
+impl Post {
+    pub async fn insert_to_db(self, client: &tokio_postgres::Client) -> anyhow::Result<Self> {
+        let statement = "INSERT INTO posts (id, author_user_id, text) VALUES ($1, $2, $3)";
+        client
+            .execute(statement, &[&self.id, &self.author_user_id, &self.text])
+            .await
+            .map_err(|e| anyhow::anyhow!("insert post failed: {}", e))?;
+        Ok(self)
+    }
+
+    pub async fn from_id(
+        id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Option<Self>> {
+        let statement = "select id, text, author_user_id from posts where id = $1";
+        let rows = client
+            .query(statement, &[&id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read post: {}", e))?;
+        Ok(rows.last().map(Post::from_row))
+    }
+
+    pub async fn update_text(
+        id: &String,
+        text: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "UPDATE posts SET text = $1, updated_at = now() WHERE id = $2";
+        client
+            .execute(statement, &[text, id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to update post {}: {}", id, e))?;
+        Ok(())
+    }
+
+    pub async fn delete(id: &String, client: &tokio_postgres::Client) -> anyhow::Result<()> {
+        let statement = "DELETE FROM posts WHERE id = $1";
+        client
+            .execute(statement, &[id])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to delete post {}: {}", id, e))?;
+        Ok(())
+    }
+
+    /// Latest posts of the users `user_id` is friends with, newest first.
+    pub async fn feed(
+        user_id: &String,
+        limit: usize,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let statement = "select p.id, p.text, p.author_user_id from posts p join friends f on f.friend_id = p.author_user_id where f.user_id = $1 order by p.created_at desc limit $2";
+        let rows = client
+            .query(statement, &[user_id, &(limit as i64)])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read feed of {}: {}", user_id, e))?;
+        Ok(rows.iter().map(Post::from_row).collect())
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            text: row.get(1),
+            author_user_id: row.get(2),
+        }
+    }
+}
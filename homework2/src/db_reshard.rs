@@ -0,0 +1,104 @@
+/// State of a resharding, identified by the fingerprint of its source and target layouts.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Reshard {
+    pub cut_over: bool,
+    pub verified: bool,
+}
+
+impl Reshard {
+    pub async fn get(fingerprint: &str, client: &tokio_postgres::Client) -> anyhow::Result<Self> {
+        let statement =
+            "select cut_over, verified_at is not null from reshards where fingerprint = $1";
+        let row = client
+            .query_opt(statement, &[&fingerprint])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read resharding state: {}", e))?;
+        Ok(row
+            .map(|row| Self {
+                cut_over: row.get(0),
+                verified: row.get(1),
+            })
+            .unwrap_or_default())
+    }
+
+    pub async fn set_cut_over(
+        fingerprint: &str,
+        cut_over: bool,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "INSERT INTO reshards (fingerprint, cut_over) VALUES ($1, $2) ON CONFLICT (fingerprint) DO UPDATE SET cut_over = $2, updated_at = now()";
+        client
+            .execute(statement, &[&fingerprint, &cut_over])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to store cut over: {}", e))?;
+        Ok(())
+    }
+
+    /// Records that a verification pass found both locations equal, or resets it.
+    pub async fn set_verified(
+        fingerprint: &str,
+        verified: bool,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "INSERT INTO reshards (fingerprint, verified_at) VALUES ($1, CASE WHEN $2 THEN now() END) ON CONFLICT (fingerprint) DO UPDATE SET verified_at = CASE WHEN $2 THEN now() END, updated_at = now()";
+        client
+            .execute(statement, &[&fingerprint, &verified])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to store verification result: {}", e))?;
+        Ok(())
+    }
+}
+
+/// How far the backfill of a source shard has got: messages are copied in
+/// `(conversation_id, message_id)` order, so it resumes right after the last copied one.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint {
+    pub conversation_id: String,
+    pub message_id: i64,
+    pub copied: i64,
+    pub finished: bool,
+}
+
+impl Checkpoint {
+    pub async fn get(
+        fingerprint: &str,
+        source_shard: &str,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<Option<Self>> {
+        let statement = "select conversation_id, message_id, copied, finished from reshard_checkpoints where fingerprint = $1 and source_shard = $2";
+        let row = client
+            .query_opt(statement, &[&fingerprint, &source_shard])
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read backfill checkpoint: {}", e))?;
+        Ok(row.map(|row| Self {
+            conversation_id: row.get(0),
+            message_id: row.get(1),
+            copied: row.get(2),
+            finished: row.get(3),
+        }))
+    }
+
+    pub async fn save(
+        &self,
+        fingerprint: &str,
+        source_shard: &str,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let statement = "INSERT INTO reshard_checkpoints (fingerprint, source_shard, conversation_id, message_id, copied, finished) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (fingerprint, source_shard) DO UPDATE SET conversation_id = $3, message_id = $4, copied = $5, finished = $6, updated_at = now()";
+        client
+            .execute(
+                statement,
+                &[
+                    &fingerprint,
+                    &source_shard,
+                    &self.conversation_id,
+                    &self.message_id,
+                    &self.copied,
+                    &self.finished,
+                ],
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to save backfill checkpoint: {}", e))?;
+        Ok(())
+    }
+}
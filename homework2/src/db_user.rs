@@ -1,6 +1,12 @@
-use crate::{db::DB, password};
+use crate::{
+    db::{self, DB},
+    password, presence,
+};
+use deadpool_postgres::GenericClient;
+use std::collections::HashMap;
+use tokio_postgres::types::ToSql;
 
-#[derive(serde::Serialize, Debug)]
+#[derive(serde::Serialize, Debug, Clone)]
 pub struct User {
     pub id: String,
     pub first_name: String,
@@ -20,9 +26,22 @@ pub struct SearchResult {
     pub biography: String,
     pub birthdate: String,
     pub city: String,
+    #[serde(flatten)]
+    pub presence: presence::Status,
+}
+
+/// What the others see of a user, without the password hash and the login token.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub id: String,
+    pub first_name: String,
+    pub second_name: String,
+    pub biography: String,
+    pub birthdate: String,
+    pub city: String,
 }
 
-impl From<User> for SearchResult {
+impl From<User> for Profile {
     fn from(user: User) -> Self {
         Self {
             id: user.id,
@@ -35,13 +54,106 @@ impl From<User> for SearchResult {
     }
 }
 
+impl From<Profile> for SearchResult {
+    fn from(profile: Profile) -> Self {
+        Self {
+            id: profile.id,
+            first_name: profile.first_name,
+            second_name: profile.second_name,
+            biography: profile.biography,
+            birthdate: profile.birthdate,
+            city: profile.city,
+            presence: presence::Status::default(),
+        }
+    }
+}
+
+const USER_COLUMNS: &str =
+    "id, first_name, second_name, birthdate, biography, city, password_hash, token";
+const PROFILE_COLUMNS: &str = "id, first_name, second_name, birthdate, biography, city";
+
+/// Statement parsed once per pooled connection, and reused by the later queries on it.
+async fn prepare(
+    client: &impl GenericClient,
+    statement: &str,
+    failure: &str,
+) -> anyhow::Result<tokio_postgres::Statement> {
+    client
+        .prepare_cached(statement)
+        .await
+        .map_err(|e| db::query_error(failure, e))
+}
+
+impl Profile {
+    pub async fn from_id(id: &str, client: &impl GenericClient) -> anyhow::Result<Option<Self>> {
+        let failure = "failed to read user";
+        let statement = format!("select {PROFILE_COLUMNS} from users where id = $1");
+        let statement = prepare(client, &statement, failure).await?;
+        let row = client
+            .query_opt(&statement, &[&id])
+            .await
+            .map_err(|e| db::query_error(failure, e))?;
+        Ok(row.as_ref().map(Profile::from_row))
+    }
+
+    /// Profiles whose first and second names start with the given ones.
+    pub async fn search(
+        client: &impl GenericClient,
+        first_name: Option<&String>,
+        second_name: Option<&String>,
+    ) -> anyhow::Result<Vec<Self>> {
+        let (condition, params) = match (first_name, second_name) {
+            (Some(first_name), Some(second_name)) => (
+                "first_name LIKE $1 and second_name LIKE $2",
+                vec![first_name.to_owned() + "%", second_name.to_owned() + "%"],
+            ),
+            (Some(first_name), None) => ("first_name LIKE $1", vec![first_name.to_owned() + "%"]),
+            (None, Some(second_name)) => {
+                ("second_name LIKE $1", vec![second_name.to_owned() + "%"])
+            }
+            (None, None) => return Ok(vec![]),
+        };
+        let failure = format!(
+            "failed to search users: first_name={first_name:?} second_name={second_name:?}"
+        );
+        let statement = format!("select {PROFILE_COLUMNS} from users where {condition}");
+        let statement = prepare(client, &statement, &failure).await?;
+        let params: Vec<&(dyn ToSql + Sync)> = params
+            .iter()
+            .map(|param| param as &(dyn ToSql + Sync))
+            .collect();
+        let rows = client
+            .query(&statement, &params)
+            .await
+            .map_err(|e| db::query_error(&failure, e))?;
+        Ok(rows.iter().map(Profile::from_row).collect())
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            first_name: row.get(1),
+            second_name: row.get(2),
+            birthdate: row.get(3),
+            biography: row.try_get(4).unwrap_or("".into()),
+            city: row.get(5),
+        }
+    }
+}
+
 impl User {
     pub async fn insert_to_db(self, db: &DB) -> anyhow::Result<String> {
-        let statement = "INSERT INTO users (id, first_name, second_name, birthdate, biography, city, password_hash, token) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)";
-        db.get()
-            .await?
+        let failure = "insert failed";
+        let client = db.get().await?;
+        let statement = prepare(
+            &client,
+            &format!("INSERT INTO users ({USER_COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"),
+            failure,
+        )
+        .await?;
+        client
             .execute(
-                statement,
+                &statement,
                 &[
                     &self.id,
                     &self.first_name,
@@ -54,46 +166,10 @@ impl User {
                 ],
             )
             .await
-            .map_err(|e| anyhow::anyhow!("insert failed: {}", e))?;
+            .map_err(|e| db::query_error(failure, e))?;
         Ok(self.id)
     }
 
-    pub async fn search(
-        client: &tokio_postgres::Client,
-        first_name: Option<&String>,
-        second_name: Option<&String>,
-    ) -> anyhow::Result<Vec<SearchResult>> {
-        let stmt_both = "select * from users where first_name LIKE '$1%' and second_name LIKE $2";
-        let stmt_first_name = "select * from users where first_name LIKE $1";
-        let stmt_second_name = "select * from users where second_name LIKE $1";
-        let rows = if first_name.is_some() && second_name.is_some() {
-            #[allow(clippy::unnecessary_unwrap)]
-            let first_name = first_name.unwrap().to_owned() + "%";
-            #[allow(clippy::unnecessary_unwrap)]
-            let second_name = second_name.unwrap().to_owned() + "%";
-            client
-                .query(stmt_both, &[&first_name, &second_name])
-                .await
-        } else if let Some(first_name) = first_name {
-            let first_name = first_name.to_owned() + "%";
-            client.query(stmt_first_name, &[&first_name]).await
-        } else if let Some(second_name) = second_name {
-            let second_name = second_name.to_owned() + "%";
-            client.query(stmt_second_name, &[&second_name]).await
-        } else {
-            return Ok(vec![]);
-        }
-        .map_err(|e| anyhow::anyhow!("failed to search users: first_name={first_name:?} second_name={second_name:?}: {e:?}"))?;
-
-        let mut result: Vec<SearchResult> = Vec::with_capacity(rows.len());
-        for row in rows {
-            let user = User::from_row(&row);
-            result.push(user.into());
-        }
-
-        Ok(result)
-    }
-
     fn from_row(row: &tokio_postgres::Row) -> Self {
         Self {
             id: row.get(0),
@@ -107,25 +183,116 @@ impl User {
         }
     }
 
-    pub async fn from_id(
-        id: &String,
-        client: &tokio_postgres::Client,
-    ) -> anyhow::Result<Option<Self>> {
-        let statement = "select * from users where id = $1";
-        let rows = client
-            .query(statement, &[&id])
+    pub async fn from_id(id: &str, client: &impl GenericClient) -> anyhow::Result<Option<Self>> {
+        let failure = "failed to read user";
+        let statement = format!("select {USER_COLUMNS} from users where id = $1");
+        let statement = prepare(client, &statement, failure).await?;
+        let row = client
+            .query_opt(&statement, &[&id])
             .await
-            .map_err(|e| anyhow::anyhow!("failed to read user: {}", e))?;
-        if rows.is_empty() {
-            Ok(None)
-        } else {
-            let row = rows.iter().last().unwrap();
-            Ok(Some(User::from_row(row)))
+            .map_err(|e| db::query_error(failure, e))?;
+        Ok(row.as_ref().map(User::from_row))
+    }
+
+    pub async fn exists(id: &str, client: &impl GenericClient) -> anyhow::Result<bool> {
+        let failure = "failed to read user";
+        let statement = prepare(client, "select 1 from users where id = $1", failure).await?;
+        let row = client
+            .query_opt(&statement, &[&id])
+            .await
+            .map_err(|e| db::query_error(failure, e))?;
+        Ok(row.is_some())
+    }
+
+    /// Id of the user the login token has been issued to.
+    pub async fn id_by_token(
+        token: &str,
+        client: &impl GenericClient,
+    ) -> anyhow::Result<Option<String>> {
+        if token.is_empty() {
+            return Ok(None);
         }
+        let failure = "failed to read user by token";
+        let statement = prepare(client, "select id from users where token = $1", failure).await?;
+        let rows = client
+            .query(&statement, &[&token])
+            .await
+            .map_err(|e| db::query_error(failure, e))?;
+        Ok(rows.last().map(|row| row.get(0)))
+    }
+
+    /// Ids of the users who logged in most recently.
+    pub async fn most_active(
+        limit: usize,
+        client: &impl GenericClient,
+    ) -> anyhow::Result<Vec<String>> {
+        let statement = "select id from users where last_login_at is not null order by last_login_at desc limit $1";
+        let failure = "failed to read most active users";
+        let statement = prepare(client, statement, failure).await?;
+        let rows = client
+            .query(&statement, &[&(limit as i64)])
+            .await
+            .map_err(|e| db::query_error(failure, e))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Last-seen times flushed so far and the privacy setting of the users.
+    pub async fn presence(
+        user_ids: &[String],
+        client: &impl GenericClient,
+    ) -> anyhow::Result<Vec<StoredPresence>> {
+        let statement = "select id, (extract(epoch from last_seen_at) * 1000)::bigint, presence_hidden from users where id = any($1)";
+        let failure = "failed to read presence of users";
+        let statement = prepare(client, statement, failure).await?;
+        let rows = client
+            .query(&statement, &[&user_ids])
+            .await
+            .map_err(|e| db::query_error(failure, e))?;
+        Ok(rows
+            .iter()
+            .map(|row| StoredPresence {
+                user_id: row.get(0),
+                last_seen_at: row.get(1),
+                hidden: row.get(2),
+            })
+            .collect())
+    }
+
+    /// Saves the last-seen times, milliseconds since the epoch, by user id. A time older
+    /// than the saved one is ignored.
+    pub async fn update_last_seen(
+        seen: &HashMap<String, i64>,
+        client: &impl GenericClient,
+    ) -> anyhow::Result<()> {
+        let (user_ids, times): (Vec<&String>, Vec<i64>) =
+            seen.iter().map(|(user_id, time)| (user_id, *time)).unzip();
+        let statement = "UPDATE users u SET last_seen_at = greatest(u.last_seen_at, to_timestamp(s.at / 1000.0)) FROM unnest($1::text[], $2::bigint[]) s(id, at) WHERE u.id = s.id";
+        let failure = "failed to update last seen times";
+        let statement = prepare(client, statement, failure).await?;
+        client
+            .execute(&statement, &[&user_ids, &times])
+            .await
+            .map_err(|e| db::query_error(failure, e))?;
+        Ok(())
+    }
+
+    pub async fn set_presence_hidden(
+        user_id: &str,
+        hidden: bool,
+        client: &impl GenericClient,
+    ) -> anyhow::Result<()> {
+        let statement = "UPDATE users SET presence_hidden = $1 WHERE id = $2";
+        let failure = "failed to update presence setting";
+        let statement = prepare(client, statement, failure).await?;
+        client
+            .execute(&statement, &[&hidden, &user_id])
+            .await
+            .map_err(|e| db::query_error(failure, e))?;
+        Ok(())
     }
 
     pub async fn update_token(
-        user_id: &String,
+        user_id: &str,
         password: &str,
         pg_pool: &mut deadpool_postgres::Object,
     ) -> anyhow::Result<UpdatedTokenResult> {
@@ -133,12 +300,18 @@ impl User {
         let transaction = pg_pool
             .transaction()
             .await
-            .map_err(|e| anyhow::anyhow!("failed to update token: transaction error: {}", e))?;
-        let update_token_statement = "UPDATE users SET token = $1 WHERE id = $2";
+            .map_err(|e| db::query_error("failed to update token: transaction error", e))?;
+        let failure = "failed to updated: update error";
+        let update_token_statement = prepare(
+            &transaction,
+            "UPDATE users SET token = $1, last_login_at = now() WHERE id = $2",
+            failure,
+        )
+        .await?;
         let updated = transaction
-            .execute(update_token_statement, &[&token, user_id])
+            .execute(&update_token_statement, &[&token, &user_id])
             .await
-            .map_err(|e| anyhow::anyhow!("failed to updated: update error: {}", e))?;
+            .map_err(|e| db::query_error(failure, e))?;
         if updated != 1 {
             if updated > 1 {
                 tracing::error!(
@@ -151,7 +324,7 @@ impl User {
             return Ok(UpdatedTokenResult::UserNotFound);
         }
 
-        let user = User::from_id(user_id, transaction.client()).await?.unwrap();
+        let user = User::from_id(user_id, &transaction).await?.unwrap();
         if !password::verify_password(password, &user.password_hash) {
             let _ = transaction.rollback().await;
             return Ok(UpdatedTokenResult::WrongPassword);
@@ -160,12 +333,18 @@ impl User {
         transaction
             .commit()
             .await
-            .map_err(|e| anyhow::anyhow!("failed to update token: commit error:{}", e))?;
+            .map_err(|e| db::query_error("failed to update token: commit error", e))?;
 
         Ok(UpdatedTokenResult::Ok(user))
     }
 }
 
+pub struct StoredPresence {
+    pub user_id: String,
+    pub last_seen_at: Option<i64>,
+    pub hidden: bool,
+}
+
 pub enum UpdatedTokenResult {
     UserNotFound,
     WrongPassword,
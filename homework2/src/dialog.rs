@@ -0,0 +1,338 @@
+use crate::{
+    db,
+    db_group::GroupMessage,
+    db_message::{self, FoundMessage, Message, ReadMarker},
+    db_outbox::{Kind, OutboxEntry},
+    memory_store, shard,
+};
+use std::sync::Arc;
+use tokio_postgres::GenericClient;
+
+/// Where the dialog messages are kept.
+pub enum Store {
+    Postgres(Arc<shard::Shards>),
+    Memory(Arc<memory_store::MemoryStore>),
+}
+
+pub enum StoreConfig {
+    Postgres(shard::Config),
+    Memory(memory_store::Config),
+}
+
+pub async fn send(
+    store: &Store,
+    from_user_id: &String,
+    to_user_id: &String,
+    text: &String,
+) -> anyhow::Result<Message> {
+    match store {
+        Store::Postgres(shards) => send_to_shards(shards, from_user_id, to_user_id, text).await,
+        Store::Memory(store) => store.send(from_user_id, to_user_id, text).await,
+    }
+}
+
+/// Marks the messages of the conversation sent to the user up to `up_to` as read, taking
+/// them off the user's unread counter. Read messages are delivered too. Returns the id of
+/// the last message read if the marker has moved.
+pub async fn mark_read(
+    store: &Store,
+    user_id: &str,
+    other_user_id: &str,
+    up_to: i64,
+) -> anyhow::Result<Option<i64>> {
+    match store {
+        Store::Postgres(shards) => mark_read_on_shards(shards, user_id, other_user_id, up_to).await,
+        Store::Memory(store) => store.mark_read(user_id, other_user_id, up_to).await,
+    }
+}
+
+/// Marks the messages of the conversation sent to the user up to `up_to` as delivered.
+/// Returns whether the marker has moved.
+async fn mark_delivered(
+    store: &Store,
+    user_id: &str,
+    other_user_id: &str,
+    up_to: i64,
+) -> anyhow::Result<bool> {
+    match store {
+        Store::Postgres(shards) => {
+            mark_delivered_on_shards(shards, user_id, other_user_id, up_to).await
+        }
+        Store::Memory(store) => store.mark_delivered(user_id, other_user_id, up_to).await,
+    }
+}
+
+/// Stores the message together with the outbox entry bumping the recipient's unread counter
+/// on the shard of the conversation. While resharding, the message is then copied under
+/// the same id to the other location of the conversation.
+async fn send_to_shards(
+    shards: &shard::Shards,
+    from_user_id: &String,
+    to_user_id: &String,
+    text: &String,
+) -> anyhow::Result<Message> {
+    let conversation_id = db_message::conversation_id(from_user_id, to_user_id);
+    let route = shards.route(&conversation_id);
+    let (primary_name, primary_db) = route.primary;
+    let id = Message::next_id(shards.main_db().get().await?.client()).await?;
+    let message = async {
+        let mut pg_pool = primary_db.get().await?;
+        let transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("transaction error: {}", e))?;
+        let message =
+            Message::insert_to_db(id, from_user_id, to_user_id, text, transaction.client()).await?;
+        OutboxEntry {
+            id: 0,
+            kind: Kind::Sent,
+            conversation_id: conversation_id.clone(),
+            user_id: to_user_id.clone(),
+            other_user_id: from_user_id.clone(),
+            message_id: message.id,
+            previous_id: None,
+            delta: 1,
+            attempts: 0,
+        }
+        .enqueue(transaction.client())
+        .await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))?;
+        anyhow::Ok(message)
+    }
+    .await
+    .map_err(|e| anyhow::anyhow!("message shard '{}': {}", primary_name, e))?;
+
+    if let Some((secondary_name, secondary_db)) = route.secondary {
+        // the message is stored already, a lost copy is found by reshard-verify
+        let copied = match secondary_db.get().await {
+            Ok(pg_pool) => message.copy_to_db(pg_pool.client()).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = copied {
+            tracing::error!(
+                "failed to copy message {} to shard '{}': {err:?}",
+                message.id,
+                secondary_name
+            );
+        }
+    }
+    Ok(message)
+}
+
+/// The read marker is saved together with the outbox entry taking the messages off the
+/// user's unread counter.
+async fn mark_read_on_shards(
+    shards: &shard::Shards,
+    user_id: &str,
+    other_user_id: &str,
+    up_to: i64,
+) -> anyhow::Result<Option<i64>> {
+    let conversation_id = db_message::conversation_id(user_id, other_user_id);
+    let route = shards.route(&conversation_id);
+    let (primary_name, primary_db) = route.primary;
+    let marker = async {
+        let mut pg_pool = primary_db.get().await?;
+        let transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("transaction error: {}", e))?;
+        let previous_id =
+            Message::last_read_for_update(&conversation_id, user_id, transaction.client()).await?;
+        // only messages sent to the user can be read by them
+        let last_read_id =
+            match Message::last_received(&conversation_id, user_id, up_to, transaction.client())
+                .await?
+            {
+                Some(id) if id > previous_id => id,
+                _ => return anyhow::Ok(None),
+            };
+        let read = Message::count_received(
+            &conversation_id,
+            user_id,
+            previous_id,
+            last_read_id,
+            transaction.client(),
+        )
+        .await?;
+        let marker = ReadMarker {
+            conversation_id: conversation_id.clone(),
+            user_id: user_id.to_owned(),
+            last_read_id,
+            last_delivered_id: last_read_id,
+        };
+        marker.save(transaction.client()).await?;
+        OutboxEntry {
+            id: 0,
+            kind: Kind::Read,
+            conversation_id: conversation_id.clone(),
+            user_id: user_id.to_owned(),
+            other_user_id: other_user_id.to_owned(),
+            message_id: last_read_id,
+            previous_id: Some(previous_id),
+            delta: -read,
+            attempts: 0,
+        }
+        .enqueue(transaction.client())
+        .await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("commit error: {}", e))?;
+        anyhow::Ok(Some(marker))
+    }
+    .await
+    .map_err(|e| anyhow::anyhow!("message shard '{}': {}", primary_name, e))?;
+
+    let Some(marker) = marker else {
+        return Ok(None);
+    };
+    if let Some(secondary) = route.secondary {
+        copy_marker(&marker, secondary).await;
+    }
+    Ok(Some(marker.last_read_id))
+}
+
+async fn mark_delivered_on_shards(
+    shards: &shard::Shards,
+    user_id: &str,
+    other_user_id: &str,
+    up_to: i64,
+) -> anyhow::Result<bool> {
+    let marker = ReadMarker {
+        conversation_id: db_message::conversation_id(user_id, other_user_id),
+        user_id: user_id.to_owned(),
+        last_read_id: 0,
+        last_delivered_id: up_to,
+    };
+    let route = shards.route(&marker.conversation_id);
+    let (primary_name, primary_db) = route.primary;
+    let moved = async {
+        let pg_pool = primary_db.get().await?;
+        ReadMarker::deliver(&marker.conversation_id, user_id, up_to, pg_pool.client()).await
+    }
+    .await
+    .map_err(|e| anyhow::anyhow!("message shard '{}': {}", primary_name, e))?;
+
+    if let (true, Some(secondary)) = (moved, route.secondary) {
+        copy_marker(&marker, secondary).await;
+    }
+    Ok(moved)
+}
+
+/// Whether the users have written to each other, the dialog may be empty for friends.
+pub async fn has_dialog(store: &Store, user_id: &str, other_user_id: &str) -> anyhow::Result<bool> {
+    match store {
+        Store::Postgres(shards) => {
+            let conversation_id = db_message::conversation_id(user_id, other_user_id);
+            let pg_pool = shards.get(&conversation_id).await?;
+            Message::conversation_exists(&conversation_id, pg_pool.client()).await
+        }
+        Store::Memory(store) => Ok(store.has_dialog(user_id, other_user_id)),
+    }
+}
+
+/// Lists the dialog of the user newest first with the state of every message, marking
+/// the listed messages sent to the user as delivered. Returns the messages and the id of
+/// the last one delivered if the delivered marker has moved.
+pub async fn list(
+    store: &Store,
+    user_id: &str,
+    other_user_id: &str,
+    offset: usize,
+    limit: usize,
+) -> anyhow::Result<(Vec<Message>, Option<i64>)> {
+    let (mut messages, markers) = match store {
+        Store::Postgres(shards) => {
+            let conversation_id = db_message::conversation_id(user_id, other_user_id);
+            let pg_pool = shards.get(&conversation_id).await?;
+            let messages =
+                Message::list(user_id, other_user_id, offset, limit, pg_pool.client()).await?;
+            let markers = ReadMarker::of_conversation(&conversation_id, pg_pool.client()).await?;
+            (messages, markers)
+        }
+        Store::Memory(store) => store.list(user_id, other_user_id, offset, limit),
+    };
+
+    let last_received = messages
+        .iter()
+        .filter(|message| message.to_user_id == user_id)
+        .map(|message| message.id)
+        .max();
+    let delivered = match last_received {
+        Some(id) if mark_delivered(store, user_id, other_user_id, id).await? => Some(id),
+        _ => None,
+    };
+
+    for message in messages.iter_mut() {
+        let marker = markers
+            .iter()
+            .find(|marker| marker.user_id == message.to_user_id);
+        let mut state = marker.map_or(db_message::State::Sent, |marker| marker.state(message.id));
+        // the page itself has just been delivered to the user
+        if message.to_user_id == user_id && state == db_message::State::Sent {
+            state = db_message::State::Delivered;
+        }
+        message.state = Some(state);
+    }
+    Ok((messages, delivered))
+}
+
+/// Messages of the user's dialogs and groups matching the query, newest first. Every
+/// shard and the main database are asked for the whole window before the results are merged.
+pub async fn search(
+    store: &Store,
+    db: &db::DB,
+    user_id: &str,
+    query: &str,
+    offset: usize,
+    limit: usize,
+) -> anyhow::Result<Vec<db_message::Found>> {
+    let mut found = async {
+        let pg_pool = db.get().await?;
+        GroupMessage::search(user_id, query, offset + limit, pg_pool.client()).await
+    }
+    .await?;
+    match store {
+        Store::Memory(store) => found.extend(store.search(user_id, query, offset + limit)),
+        Store::Postgres(shards) => {
+            for (shard_name, shard_db) in shards.primaries() {
+                let shard_found = async {
+                    let pg_pool = shard_db.get().await?;
+                    Message::search(user_id, query, offset + limit, pg_pool.client()).await
+                }
+                .await
+                .map_err(|e| anyhow::anyhow!("message shard '{}': {}", shard_name, e))?;
+                found.extend(
+                    shard_found
+                        .into_iter()
+                        .filter(|found| match &found.message {
+                            FoundMessage::Dialog(message) => {
+                                shards.owns(shard_name, &message.conversation_id())
+                            }
+                            FoundMessage::Group { .. } => true,
+                        }),
+                );
+            }
+        }
+    }
+    found.sort_by_key(|found| std::cmp::Reverse((found.sent_at, found.message.id())));
+    Ok(found.into_iter().skip(offset).take(limit).collect())
+}
+
+/// The marker is stored already, a lost copy is restored by the next reshard-backfill.
+async fn copy_marker(marker: &ReadMarker, (name, db): shard::Named<'_>) {
+    let copied = match db.get().await {
+        Ok(pg_pool) => marker.save(pg_pool.client()).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = copied {
+        tracing::error!(
+            "failed to copy read marker of {} to shard '{}': {err:?}",
+            marker.conversation_id,
+            name
+        );
+    }
+}
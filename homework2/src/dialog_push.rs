@@ -0,0 +1,102 @@
+use crate::{db, db_friend, dialog, presence, push};
+use axum::extract::ws::WebSocket;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+/// Dialog events sent to the participants over WebSocket. Typing indicators are ephemeral:
+/// they are only forwarded to the connections open at the moment and never stored.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Sent by a client, `user_id` is the one it is typing to. Forwarded to that user
+    /// with `user_id` replaced by the one typing, if they are friends or have a dialog.
+    Typing { user_id: String },
+    /// The user has received the messages of the dialog up to `message_id`.
+    Delivered { user_id: String, message_id: i64 },
+    /// The user has read the messages of the dialog up to `message_id`.
+    Read { user_id: String, message_id: i64 },
+    /// A new message of a group the user is a member of, sent by `user_id`.
+    GroupMessage {
+        group_id: String,
+        user_id: String,
+        message_id: i64,
+        text: String,
+    },
+}
+
+pub struct DialogPush {
+    hub: push::Hub,
+    db: Arc<db::DB>,
+    store: Arc<dialog::Store>,
+}
+
+impl DialogPush {
+    pub fn new(
+        presence: Arc<presence::Presence>,
+        db: Arc<db::DB>,
+        store: Arc<dialog::Store>,
+    ) -> Self {
+        Self {
+            hub: push::Hub::new("dialog", presence),
+            db,
+            store,
+        }
+    }
+
+    pub fn send<'a>(&self, users: impl IntoIterator<Item = &'a String>, event: &Event) {
+        let message = Arc::new(serde_json::to_string(event).unwrap());
+        self.hub.send(users, message);
+    }
+
+    /// Serves the connection until the client leaves, stops answering or falls behind.
+    pub async fn serve(&self, user_id: String, socket: WebSocket) {
+        let from = user_id.clone();
+        // users the connection has been found to be in touch with
+        let in_touch = Arc::new(Mutex::new(HashSet::new()));
+        self.hub
+            .serve(user_id, socket, |text| {
+                let from = from.clone();
+                let in_touch = in_touch.clone();
+                async move {
+                    match serde_json::from_str::<Event>(&text) {
+                        Ok(Event::Typing { user_id: to }) if to != from => {
+                            if !in_touch.lock().unwrap().contains(&to) {
+                                match self.in_touch(&from, &to).await {
+                                    Ok(true) => {
+                                        in_touch.lock().unwrap().insert(to.clone());
+                                    }
+                                    Ok(false) => {
+                                        tracing::debug!(
+                                            "user {from} is typing to stranger {to}, dropped"
+                                        );
+                                        return;
+                                    }
+                                    Err(err) => {
+                                        tracing::error!(
+                                            "failed to check if user {from} may write to {to}: {err:?}"
+                                        );
+                                        return;
+                                    }
+                                }
+                            }
+                            self.send([&to], &Event::Typing { user_id: from })
+                        }
+                        _ => {
+                            tracing::debug!("unexpected dialog event from user {}: {}", from, text)
+                        }
+                    }
+                }
+            })
+            .await
+    }
+
+    async fn in_touch(&self, user_id: &str, other_user_id: &str) -> anyhow::Result<bool> {
+        let friends = {
+            let pg_pool = self.db.get().await?;
+            db_friend::Friend::either_way(user_id, other_user_id, &pg_pool).await?
+        };
+        Ok(friends || dialog::has_dialog(&self.store, user_id, other_user_id).await?)
+    }
+}
@@ -0,0 +1,580 @@
+use crate::{
+    db,
+    db_feed_event::{FeedEvent, Kind},
+    db_friend, db_post,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio_postgres::GenericClient;
+
+/// How many of the latest friends' posts are kept per user.
+pub const FEED_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// Most feeds kept at once, the least recently read one is dropped to make room.
+    pub max_users: usize,
+    /// Feeds of that many most active users are loaded at startup.
+    pub warmup_users: usize,
+}
+
+/// In-process cache of users' feeds. A feed is built from Postgres on the first read and
+/// then kept up to date by the feed queue workers, so subsequent reads never touch the database.
+pub struct FeedCache {
+    feeds: Mutex<HashMap<String, Feed>>,
+    next_ticket: AtomicU64,
+    max_users: usize,
+}
+
+enum Feed {
+    /// The feed is being read from Postgres. Any change arriving meanwhile marks it stale,
+    /// so the loaded snapshot is not cached and the next read goes to the database again.
+    Loading {
+        ticket: u64,
+        stale: bool,
+    },
+    Ready(CachedFeed),
+}
+
+struct CachedFeed {
+    posts: VecDeque<Arc<db_post::Post>>,
+    friends: HashSet<String>,
+    /// Ticket of the last read, the least recently read feed has the smallest one.
+    read: u64,
+    /// Set while the feed is read from Postgres again, the cached one is served meanwhile.
+    reload: Option<Reload>,
+}
+
+struct Reload {
+    ticket: u64,
+    /// Changes the new snapshot may have missed, replayed over it before it's swapped in.
+    missed: Vec<(Kind, Arc<db_post::Post>)>,
+}
+
+impl FeedCache {
+    pub fn new(max_users: usize) -> Self {
+        Self {
+            feeds: Mutex::new(HashMap::new()),
+            next_ticket: AtomicU64::new(0),
+            max_users,
+        }
+    }
+
+    /// Page of the feed, only the first [`FEED_LIMIT`] posts are kept. A connection
+    /// is taken from the pool only when the feed isn't cached.
+    pub async fn get(
+        &self,
+        user_id: &String,
+        offset: usize,
+        limit: usize,
+        db: &db::DB,
+    ) -> anyhow::Result<Vec<db_post::Post>> {
+        if let Some(posts) = self.cached_page(user_id, offset, limit) {
+            tracing::debug!("feed cache hit for user {}", user_id);
+            return Ok(posts);
+        }
+
+        tracing::debug!("feed cache miss for user {}", user_id);
+        let pg_pool = db.get().await?;
+        let posts = self.load(user_id, pg_pool.client()).await?;
+        Ok(page(&posts, offset, limit))
+    }
+
+    /// Reads the feed of the user from Postgres and caches it. A feed that is already cached
+    /// keeps being served until the new one is read, then it's replaced.
+    pub async fn load(
+        &self,
+        user_id: &String,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<VecDeque<Arc<db_post::Post>>> {
+        let ticket = self.begin_load(user_id);
+        match read_feed(user_id, ticket, client).await {
+            Ok(feed) => Ok(self.finish_load(user_id, ticket, feed)),
+            Err(err) => {
+                self.abort_load(user_id, ticket);
+                Err(err)
+            }
+        }
+    }
+
+    /// Users whose feeds are currently cached.
+    pub fn cached_users(&self) -> Vec<String> {
+        self.feeds.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Drops the cached feed of the user, e.g. after the friend list has changed.
+    pub fn invalidate(&self, user_id: &str) {
+        self.feeds.lock().unwrap().remove(user_id);
+    }
+
+    /// Drops the cached feeds following the author, used when an event of the author could
+    /// not be applied to all of them. Feeds being loaded are not cached once read.
+    pub fn invalidate_followers(&self, author_user_id: &str) {
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds.retain(|_, feed| match feed {
+            Feed::Loading { .. } => true,
+            Feed::Ready(feed) => !feed.friends.contains(author_user_id),
+        });
+        for feed in feeds.values_mut() {
+            if let Feed::Loading { stale, .. } = feed {
+                *stale = true;
+            }
+        }
+    }
+
+    /// Applies the event to the cached feeds of the given followers of its author.
+    pub fn apply(&self, event: &FeedEvent, followers: &[String]) {
+        let post = Arc::new(event.post.clone());
+        let mut feeds = self.feeds.lock().unwrap();
+        for follower in followers {
+            apply_to(&mut feeds, follower, event.kind, &post);
+        }
+    }
+
+    /// Applies the event to every cached feed following its author. Used for authors with
+    /// huge follower lists, where walking the cache is cheaper than reading all followers.
+    pub fn apply_to_cached(&self, event: &FeedEvent) {
+        let post = Arc::new(event.post.clone());
+        let mut feeds = self.feeds.lock().unwrap();
+        let followers: Vec<String> = feeds
+            .iter()
+            .filter(|(_, feed)| match feed {
+                // whether a loading feed follows the author is unknown yet
+                Feed::Loading { .. } => true,
+                Feed::Ready(feed) => feed.friends.contains(&post.author_user_id),
+            })
+            .map(|(user_id, _)| user_id.clone())
+            .collect();
+        for follower in followers {
+            apply_to(&mut feeds, &follower, event.kind, &post);
+        }
+    }
+
+    /// Page of the cached feed, which becomes the most recently read one.
+    fn cached_page(
+        &self,
+        user_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Option<Vec<db_post::Post>> {
+        match self.feeds.lock().unwrap().get_mut(user_id) {
+            Some(Feed::Ready(feed)) => {
+                feed.read = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+                Some(page(&feed.posts, offset, limit))
+            }
+            _ => None,
+        }
+    }
+
+    fn begin_load(&self, user_id: &str) -> u64 {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let mut feeds = self.feeds.lock().unwrap();
+        match feeds.get_mut(user_id) {
+            Some(Feed::Ready(feed)) => {
+                feed.reload = Some(Reload {
+                    ticket,
+                    missed: Vec::new(),
+                });
+            }
+            _ => {
+                feeds.insert(
+                    user_id.to_owned(),
+                    Feed::Loading {
+                        ticket,
+                        stale: false,
+                    },
+                );
+            }
+        }
+        ticket
+    }
+
+    /// Caches the loaded feed unless a newer load has started meanwhile, returns the posts
+    /// to serve.
+    fn finish_load(
+        &self,
+        user_id: &str,
+        ticket: u64,
+        mut feed: CachedFeed,
+    ) -> VecDeque<Arc<db_post::Post>> {
+        let mut feeds = self.feeds.lock().unwrap();
+        match feeds.get_mut(user_id) {
+            Some(Feed::Ready(cached))
+                if cached
+                    .reload
+                    .as_ref()
+                    .is_some_and(|reload| reload.ticket == ticket) =>
+            {
+                let missed = cached.reload.take().unwrap().missed;
+                // when a replayed change can't be applied the cached feed, which has got
+                // all the changes already, is kept
+                if missed
+                    .iter()
+                    .all(|(kind, post)| apply_to_posts(&mut feed.posts, *kind, post))
+                {
+                    feed.read = cached.read;
+                    *cached = feed;
+                }
+                cached.posts.clone()
+            }
+            Some(Feed::Loading {
+                ticket: current,
+                stale: false,
+            }) if *current == ticket => {
+                let posts = feed.posts.clone();
+                feeds.insert(user_id.to_owned(), Feed::Ready(feed));
+                if feeds.len() > self.max_users {
+                    evict_least_read(&mut feeds, user_id);
+                }
+                posts
+            }
+            Some(Feed::Loading {
+                ticket: current, ..
+            }) if *current == ticket => {
+                feeds.remove(user_id);
+                feed.posts
+            }
+            _ => feed.posts,
+        }
+    }
+
+    fn abort_load(&self, user_id: &str, ticket: u64) {
+        let mut feeds = self.feeds.lock().unwrap();
+        match feeds.get_mut(user_id) {
+            Some(Feed::Ready(cached))
+                if cached
+                    .reload
+                    .as_ref()
+                    .is_some_and(|reload| reload.ticket == ticket) =>
+            {
+                cached.reload = None;
+            }
+            Some(Feed::Loading {
+                ticket: current, ..
+            }) if *current == ticket => {
+                feeds.remove(user_id);
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn read_feed(
+    user_id: &String,
+    ticket: u64,
+    client: &tokio_postgres::Client,
+) -> anyhow::Result<CachedFeed> {
+    let friends = db_friend::Friend::friends_of(user_id, client)
+        .await?
+        .into_iter()
+        .collect();
+    let posts = db_post::Post::feed(user_id, FEED_LIMIT, client)
+        .await?
+        .into_iter()
+        .map(Arc::new)
+        .collect();
+    Ok(CachedFeed {
+        posts,
+        friends,
+        read: ticket,
+        reload: None,
+    })
+}
+
+/// Drops the ready feed read the longest ago, except the one just loaded.
+fn evict_least_read(feeds: &mut HashMap<String, Feed>, loaded: &str) {
+    let least_read = feeds
+        .iter()
+        .filter_map(|(user_id, feed)| match feed {
+            Feed::Ready(feed) if user_id != loaded => Some((feed.read, user_id)),
+            _ => None,
+        })
+        .min()
+        .map(|(_, user_id)| user_id.clone());
+    if let Some(user_id) = least_read {
+        tracing::debug!("feed of user {} evicted from the cache", user_id);
+        feeds.remove(&user_id);
+    }
+}
+
+fn apply_to(
+    feeds: &mut HashMap<String, Feed>,
+    user_id: &str,
+    kind: Kind,
+    post: &Arc<db_post::Post>,
+) {
+    let feed = match feeds.get_mut(user_id) {
+        None => return,
+        Some(Feed::Loading { stale, .. }) => {
+            *stale = true;
+            return;
+        }
+        Some(Feed::Ready(feed)) => feed,
+    };
+    if let Some(reload) = &mut feed.reload {
+        reload.missed.push((kind, post.clone()));
+    }
+    if !apply_to_posts(&mut feed.posts, kind, post) {
+        feeds.remove(user_id);
+    }
+}
+
+/// Returns false when the feed can't be kept up to date and has to be read again.
+fn apply_to_posts(
+    posts: &mut VecDeque<Arc<db_post::Post>>,
+    kind: Kind,
+    post: &Arc<db_post::Post>,
+) -> bool {
+    match kind {
+        Kind::Created => {
+            // the feed may have been loaded after the post was committed but before the
+            // event got here, in which case the post is already in place
+            if posts.iter().all(|cached| cached.id != post.id) {
+                posts.push_front(post.clone());
+                posts.truncate(FEED_LIMIT);
+            }
+        }
+        Kind::Updated => {
+            if let Some(cached) = posts.iter_mut().find(|cached| cached.id == post.id) {
+                *cached = post.clone();
+            }
+        }
+        Kind::Deleted => {
+            let was_full = posts.len() == FEED_LIMIT;
+            let len = posts.len();
+            posts.retain(|cached| cached.id != post.id);
+            // a full feed would miss the post that now moves into the window
+            if was_full && posts.len() != len {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn page(posts: &VecDeque<Arc<db_post::Post>>, offset: usize, limit: usize) -> Vec<db_post::Post> {
+    posts
+        .iter()
+        .skip(offset)
+        .take(limit)
+        .map(|post| post.as_ref().clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(id: &str, author: &str) -> Arc<db_post::Post> {
+        Arc::new(db_post::Post {
+            id: id.to_owned(),
+            text: format!("text of {id}"),
+            author_user_id: author.to_owned(),
+        })
+    }
+
+    fn posts(count: usize) -> VecDeque<Arc<db_post::Post>> {
+        (0..count)
+            .map(|n| post(&format!("p{n}"), "author"))
+            .collect()
+    }
+
+    fn ids(posts: &VecDeque<Arc<db_post::Post>>) -> Vec<&str> {
+        posts.iter().map(|post| post.id.as_str()).collect()
+    }
+
+    fn event(kind: Kind, post: &Arc<db_post::Post>) -> FeedEvent {
+        FeedEvent {
+            id: 1,
+            kind,
+            post: post.as_ref().clone(),
+            attempts: 0,
+        }
+    }
+
+    fn snapshot(posts: VecDeque<Arc<db_post::Post>>, friends: &[&str]) -> CachedFeed {
+        CachedFeed {
+            posts,
+            friends: friends.iter().map(|friend| friend.to_string()).collect(),
+            read: 0,
+            reload: None,
+        }
+    }
+
+    /// Caches the feed as if it was read from postgres.
+    fn load(cache: &FeedCache, user_id: &str, feed: CachedFeed) {
+        let ticket = cache.begin_load(user_id);
+        cache.finish_load(user_id, ticket, feed);
+    }
+
+    /// Ids of the cached posts, none when the feed is not ready.
+    fn cached(cache: &FeedCache, user_id: &str) -> Option<Vec<String>> {
+        match cache.feeds.lock().unwrap().get(user_id) {
+            Some(Feed::Ready(feed)) => {
+                Some(feed.posts.iter().map(|post| post.id.clone()).collect())
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn created_post_pushes_the_oldest_one_out_of_a_full_feed() {
+        let mut feed = posts(FEED_LIMIT);
+        let new = post("new", "author");
+        assert!(apply_to_posts(&mut feed, Kind::Created, &new));
+        assert_eq!(feed.len(), FEED_LIMIT);
+        assert_eq!(feed.front().unwrap().id, "new");
+        assert_eq!(feed.back().unwrap().id, format!("p{}", FEED_LIMIT - 2));
+
+        // the event of a post the feed was loaded with changes nothing
+        assert!(apply_to_posts(&mut feed, Kind::Created, &new));
+        assert_eq!(feed.len(), FEED_LIMIT);
+        assert_eq!(feed.iter().filter(|post| post.id == "new").count(), 1);
+    }
+
+    #[test]
+    fn updated_post_is_replaced_in_place() {
+        let mut feed = posts(3);
+        let edited = Arc::new(db_post::Post {
+            text: "edited".to_owned(),
+            ..post("p1", "author").as_ref().clone()
+        });
+        assert!(apply_to_posts(&mut feed, Kind::Updated, &edited));
+        assert_eq!(ids(&feed), ["p0", "p1", "p2"]);
+        assert_eq!(feed[1].text, "edited");
+
+        assert!(apply_to_posts(
+            &mut feed,
+            Kind::Updated,
+            &post("p9", "author")
+        ));
+        assert_eq!(ids(&feed), ["p0", "p1", "p2"]);
+    }
+
+    #[test]
+    fn deleted_post_leaves_a_feed_that_is_not_full() {
+        let mut feed = posts(3);
+        assert!(apply_to_posts(
+            &mut feed,
+            Kind::Deleted,
+            &post("p1", "author")
+        ));
+        assert_eq!(ids(&feed), ["p0", "p2"]);
+    }
+
+    #[test]
+    fn deleted_post_from_a_full_feed_forces_a_reload() {
+        let mut feed = posts(FEED_LIMIT);
+        assert!(apply_to_posts(
+            &mut feed,
+            Kind::Deleted,
+            &post("missing", "author")
+        ));
+        assert_eq!(feed.len(), FEED_LIMIT);
+        assert!(!apply_to_posts(
+            &mut feed,
+            Kind::Deleted,
+            &post("p1", "author")
+        ));
+
+        let cache = FeedCache::new(10);
+        load(&cache, "user", snapshot(posts(FEED_LIMIT), &["author"]));
+        cache.apply(
+            &event(Kind::Deleted, &post("p1", "author")),
+            &["user".to_owned()],
+        );
+        assert_eq!(cached(&cache, "user"), None);
+        assert!(cache.cached_users().is_empty());
+    }
+
+    #[test]
+    fn least_recently_read_feed_is_evicted() {
+        let cache = FeedCache::new(2);
+        load(&cache, "a", snapshot(posts(1), &[]));
+        load(&cache, "b", snapshot(posts(1), &[]));
+        assert!(cache.cached_page("a", 0, 10).is_some());
+        load(&cache, "c", snapshot(posts(1), &[]));
+        let mut users = cache.cached_users();
+        users.sort();
+        assert_eq!(users, ["a", "c"]);
+    }
+
+    #[test]
+    fn feed_changed_while_loading_is_not_cached() {
+        let cache = FeedCache::new(10);
+        let ticket = cache.begin_load("user");
+        assert_eq!(cached(&cache, "user"), None);
+        cache.apply(
+            &event(Kind::Created, &post("new", "author")),
+            &["user".to_owned()],
+        );
+        let served = cache.finish_load("user", ticket, snapshot(posts(1), &["author"]));
+        assert_eq!(ids(&served), ["p0"]);
+        assert!(cache.cached_users().is_empty());
+    }
+
+    #[test]
+    fn only_the_latest_load_is_cached() {
+        let cache = FeedCache::new(10);
+        let first = cache.begin_load("user");
+        let second = cache.begin_load("user");
+        cache.finish_load("user", first, snapshot(posts(1), &[]));
+        assert_eq!(cached(&cache, "user"), None);
+        cache.finish_load("user", second, snapshot(posts(2), &[]));
+        assert_eq!(cached(&cache, "user").unwrap(), ["p0", "p1"]);
+
+        let failed = cache.begin_load("other");
+        cache.abort_load("other", failed);
+        assert_eq!(cache.cached_users(), ["user"]);
+    }
+
+    #[test]
+    fn reload_serves_the_cached_feed_and_replays_the_missed_changes() {
+        let cache = FeedCache::new(10);
+        load(&cache, "user", snapshot(posts(2), &["author"]));
+        let ticket = cache.begin_load("user");
+        let new = post("new", "author");
+        cache.apply(&event(Kind::Created, &new), &["user".to_owned()]);
+        cache.apply(
+            &event(Kind::Deleted, &post("p0", "author")),
+            &["user".to_owned()],
+        );
+        assert_eq!(cached(&cache, "user").unwrap(), ["new", "p1"]);
+
+        // the snapshot was read before both changes
+        let served = cache.finish_load("user", ticket, snapshot(posts(3), &["author"]));
+        assert_eq!(ids(&served), ["new", "p1", "p2"]);
+        assert_eq!(cached(&cache, "user").unwrap(), ["new", "p1", "p2"]);
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_cached_feed() {
+        let cache = FeedCache::new(10);
+        load(&cache, "user", snapshot(posts(2), &["author"]));
+        let ticket = cache.begin_load("user");
+        cache.abort_load("user", ticket);
+        cache.apply(
+            &event(Kind::Created, &post("new", "author")),
+            &["user".to_owned()],
+        );
+        assert_eq!(cached(&cache, "user").unwrap(), ["new", "p0", "p1"]);
+    }
+
+    #[test]
+    fn invalidating_followers_drops_only_their_feeds() {
+        let cache = FeedCache::new(10);
+        load(&cache, "follower", snapshot(posts(1), &["author"]));
+        load(&cache, "stranger", snapshot(posts(1), &["someone"]));
+        let ticket = cache.begin_load("loading");
+        cache.invalidate_followers("author");
+        assert_eq!(cached(&cache, "follower"), None);
+        assert_eq!(cached(&cache, "stranger").unwrap(), ["p0"]);
+        cache.finish_load("loading", ticket, snapshot(posts(1), &["author"]));
+        assert_eq!(cached(&cache, "loading"), None);
+    }
+}
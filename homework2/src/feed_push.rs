@@ -0,0 +1,58 @@
+use crate::{db_friend, db_post, presence, push};
+use axum::extract::ws::WebSocket;
+use std::sync::Arc;
+
+#[derive(Debug, serde::Serialize)]
+pub struct PostedMessage {
+    #[serde(rename = "postId")]
+    post_id: String,
+    #[serde(rename = "postText")]
+    post_text: String,
+    author_user_id: String,
+}
+
+/// Pushes new posts to the WebSocket connections of the author's followers.
+pub struct FeedPush {
+    hub: push::Hub,
+}
+
+impl FeedPush {
+    pub fn new(presence: Arc<presence::Presence>) -> Self {
+        Self {
+            hub: push::Hub::new("feed", presence),
+        }
+    }
+
+    /// Sends the post to the connected followers of its author.
+    pub async fn post_created(
+        &self,
+        post: &db_post::Post,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let connected = self.hub.connected_users();
+        if connected.is_empty() {
+            return Ok(());
+        }
+        let followers =
+            db_friend::Friend::followers_among(&post.author_user_id, &connected, client).await?;
+        if followers.is_empty() {
+            return Ok(());
+        }
+
+        let message = Arc::new(
+            serde_json::to_string(&PostedMessage {
+                post_id: post.id.clone(),
+                post_text: post.text.clone(),
+                author_user_id: post.author_user_id.clone(),
+            })
+            .unwrap(),
+        );
+        self.hub.send(&followers, message);
+        Ok(())
+    }
+
+    /// Serves the connection until the client leaves, stops answering or falls behind.
+    pub async fn serve(&self, user_id: String, socket: WebSocket) {
+        self.hub.serve(user_id, socket, |_| async {}).await
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{
+    db,
+    db_feed_event::{FeedEvent, Kind},
+    db_friend, feed, feed_push,
+};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::sync::Notify;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Delay before the first retry of a failed event, doubled with every next one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+/// Failed fan-outs of an event before it is parked, unless the failures are transient.
+const MAX_ATTEMPTS: i32 = 10;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Number of background workers draining the queue.
+    pub workers: usize,
+    /// Events taken by a worker per transaction; also the size of follower batches.
+    pub batch_size: usize,
+    /// Authors with more followers than this are fanned out over the cached feeds only.
+    pub celebrity_threshold: i64,
+}
+
+/// Delivers post changes queued in the `feed_events` table to the cached feeds
+/// and pushes new posts to the followers connected over WebSocket.
+///
+/// Handlers enqueue an event in the same transaction as the post change and wake the
+/// workers up, the workers then fan the change out to the followers in batches.
+pub struct FeedQueue {
+    db: Arc<db::DB>,
+    feed: Arc<feed::FeedCache>,
+    push: Arc<feed_push::FeedPush>,
+    config: Config,
+    notify: Notify,
+}
+
+impl FeedQueue {
+    pub fn new(
+        db: Arc<db::DB>,
+        feed: Arc<feed::FeedCache>,
+        push: Arc<feed_push::FeedPush>,
+        config: Config,
+    ) -> Self {
+        Self {
+            db,
+            feed,
+            push,
+            config,
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn spawn_workers(self: &Arc<Self>) {
+        tracing::info!("starting {} feed queue workers", self.config.workers);
+        for worker in 0..self.config.workers {
+            let queue = self.clone();
+            tokio::spawn(async move { queue.run_worker(worker).await });
+        }
+    }
+
+    /// Wakes a worker up after an event has been committed.
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+
+    async fn run_worker(&self, worker: usize) {
+        loop {
+            match self.process_batch().await {
+                // there may be more events waiting, so don't sleep
+                Ok(processed) if processed == self.config.batch_size => continue,
+                Ok(_) => {}
+                Err(err) => tracing::error!("feed queue worker {worker} failed: {err:?}"),
+            }
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    async fn process_batch(&self) -> anyhow::Result<usize> {
+        let mut pg_pool = self.db.get().await?;
+        let mut transaction = pg_pool
+            .transaction()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to start feed queue transaction: {}", e))?;
+        let events = FeedEvent::lock_batch(self.config.batch_size, transaction.client()).await?;
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        let mut fanned_out = Vec::with_capacity(events.len());
+        // authors with a failed event, whose later events wait for it to be retried
+        let mut held_back = HashSet::new();
+        for event in &events {
+            let author = &event.post.author_user_id;
+            if held_back.contains(author) {
+                continue;
+            }
+            // a failed query aborts only the event's savepoint, so the batch can still be handled
+            let savepoint = transaction
+                .savepoint("feed_event")
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to create feed event savepoint: {}", e))?;
+            let result = self.fan_out(event, savepoint.client()).await;
+            // fanning out only reads, there is nothing to keep
+            savepoint
+                .rollback()
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to roll back feed event savepoint: {}", e))?;
+            let err = match result {
+                Ok(()) => {
+                    fanned_out.push(event);
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            // some followers may have got the change, the others are read from postgres again
+            self.feed.invalidate_followers(author);
+            held_back.insert(author.clone());
+            if event.attempts + 1 >= MAX_ATTEMPTS && !db::is_transient(&err) {
+                tracing::error!(
+                    "feed event {} failed {} times, parking it: {err:?}",
+                    event.id,
+                    MAX_ATTEMPTS
+                );
+                event.park(&err.to_string(), transaction.client()).await?;
+            } else {
+                let delay = RETRY_DELAY
+                    .saturating_mul(2u32.saturating_pow(event.attempts as u32))
+                    .min(MAX_RETRY_DELAY);
+                tracing::warn!(
+                    "feed event {} failed, retrying in {:?}: {err:?}",
+                    event.id,
+                    delay
+                );
+                event
+                    .retry_later(&err.to_string(), delay, transaction.client())
+                    .await?;
+            }
+        }
+
+        let ids: Vec<i64> = fanned_out.iter().map(|event| event.id).collect();
+        FeedEvent::delete(&ids, transaction.client()).await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to commit feed events: {}", e))?;
+
+        // pushed only once committed, so a batch taken again after a failure isn't pushed twice
+        for event in fanned_out
+            .iter()
+            .filter(|event| event.kind == Kind::Created)
+        {
+            if let Err(err) = self.push.post_created(&event.post, &pg_pool).await {
+                tracing::error!("failed to push post {}: {err:?}", event.post.id);
+            }
+        }
+        tracing::debug!("{} feed events processed", events.len());
+        Ok(events.len())
+    }
+
+    async fn fan_out(
+        &self,
+        event: &FeedEvent,
+        client: &tokio_postgres::Client,
+    ) -> anyhow::Result<()> {
+        let author = &event.post.author_user_id;
+        let followers_count = db_friend::Friend::followers_count(author, client).await?;
+        if followers_count > self.config.celebrity_threshold {
+            tracing::debug!(
+                "user {} has {} followers, fanning out over cached feeds",
+                author,
+                followers_count
+            );
+            self.feed.apply_to_cached(event);
+            return Ok(());
+        }
+
+        let mut after = String::new();
+        loop {
+            let followers =
+                db_friend::Friend::followers_batch(author, &after, self.config.batch_size, client)
+                    .await?;
+            self.feed.apply(event, &followers);
+            match followers.last() {
+                Some(last) if followers.len() == self.config.batch_size => after = last.clone(),
+                _ => return Ok(()),
+            }
+        }
+    }
+}
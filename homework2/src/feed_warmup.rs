@@ -0,0 +1,208 @@
+use crate::{db, db_user, feed};
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+use tokio_postgres::GenericClient;
+
+/// How often the progress of a warmup is logged, in users.
+const PROGRESS_LOG_STEP: usize = 100;
+const REBUILD_PATH: &str = "/admin/feed/rebuild";
+
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Progress {
+    pub running: bool,
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+}
+
+/// Fills the feed cache from Postgres, either at startup for the most active users,
+/// or on request for every feed that is cached at the moment.
+pub struct FeedWarmup {
+    db: Arc<db::DB>,
+    feed: Arc<feed::FeedCache>,
+    top_users: usize,
+    progress: Mutex<Progress>,
+}
+
+impl FeedWarmup {
+    pub fn new(db: Arc<db::DB>, feed: Arc<feed::FeedCache>, top_users: usize) -> Self {
+        Self {
+            db,
+            feed,
+            top_users,
+            progress: Mutex::new(Progress::default()),
+        }
+    }
+
+    /// Loads the feeds of the top most active users, so that they don't all hit
+    /// Postgres at once right after a restart.
+    pub async fn warmup(&self) -> anyhow::Result<()> {
+        if self.top_users == 0 {
+            tracing::info!("feed warmup disabled");
+            return Ok(());
+        }
+        let users = self.most_active_users().await?;
+        self.start(users.len())?;
+        self.load_feeds("feed warmup", users).await;
+        Ok(())
+    }
+
+    /// Starts rebuilding every cached feed along with the feeds of the most active users
+    /// in background. Fails if a warmup or rebuild is already running.
+    pub fn start_rebuild(self: &Arc<Self>) -> anyhow::Result<Progress> {
+        let mut users: BTreeSet<String> = self.feed.cached_users().into_iter().collect();
+        let this = self.clone();
+        let progress = self.start(0)?;
+        tokio::spawn(async move {
+            match this.most_active_users().await {
+                Ok(active) => users.extend(active),
+                Err(err) => tracing::error!("feed rebuild: {err:?}"),
+            }
+            this.progress.lock().unwrap().total = users.len();
+            this.load_feeds("feed rebuild", users.into_iter().collect())
+                .await;
+        });
+        Ok(progress)
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    fn start(&self, total: usize) -> anyhow::Result<Progress> {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.running {
+            anyhow::bail!("feed warmup is already running: {progress:?}");
+        }
+        *progress = Progress {
+            running: true,
+            total,
+            ..Default::default()
+        };
+        Ok(progress.clone())
+    }
+
+    async fn most_active_users(&self) -> anyhow::Result<Vec<String>> {
+        let pg_pool = self.db.get().await?;
+        db_user::User::most_active(self.top_users, &pg_pool).await
+    }
+
+    async fn load_feeds(&self, name: &str, users: Vec<String>) {
+        let started = std::time::Instant::now();
+        tracing::info!("{name}: loading feeds of {} users", users.len());
+        for user_id in &users {
+            let result = match self.db.get().await {
+                Ok(pg_pool) => self.feed.load(user_id, pg_pool.client()).await,
+                Err(err) => Err(err),
+            };
+            let progress = {
+                let mut progress = self.progress.lock().unwrap();
+                progress.done += 1;
+                if let Err(err) = result {
+                    progress.failed += 1;
+                    tracing::error!("{name}: failed to load feed of user {user_id}: {err:?}");
+                }
+                progress.clone()
+            };
+            if progress.done % PROGRESS_LOG_STEP == 0 {
+                tracing::info!(
+                    "{name}: {}/{} feeds loaded, {} failed",
+                    progress.done,
+                    progress.total,
+                    progress.failed
+                );
+            }
+        }
+
+        let mut progress = self.progress.lock().unwrap();
+        progress.running = false;
+        tracing::info!(
+            "{name}: finished in {:?}, {}/{} feeds loaded, {} failed",
+            started.elapsed(),
+            progress.done - progress.failed,
+            progress.total,
+            progress.failed
+        );
+    }
+}
+
+/// Rebuilds the feed caches of all the servers at once, failing if any of them fails.
+pub async fn rebuild_all(servers: &[String], admin_token: &str) -> anyhow::Result<()> {
+    let mut rebuilds = tokio::task::JoinSet::new();
+    for server in servers {
+        let (server, admin_token) = (server.clone(), admin_token.to_owned());
+        rebuilds.spawn(async move {
+            let result = rebuild(&server, &admin_token).await;
+            (server, result)
+        });
+    }
+    let mut failed = 0;
+    while let Some(joined) = rebuilds.join_next().await {
+        match joined {
+            Ok((_, Ok(()))) => {}
+            Ok((server, Err(err))) => {
+                tracing::error!("failed to rebuild feeds of {server}: {err:?}");
+                failed += 1;
+            }
+            Err(err) => {
+                tracing::error!("feed rebuild panicked: {err:?}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!(
+            "{} of {} servers failed to rebuild feeds",
+            failed,
+            servers.len()
+        );
+    }
+    Ok(())
+}
+
+/// Asks a running server to rebuild its feed cache and reports the progress until it's done.
+async fn rebuild(server: &str, admin_token: &str) -> anyhow::Result<()> {
+    let url = format!("{}{}", server.trim_end_matches('/'), REBUILD_PATH);
+    let client = reqwest::Client::new();
+    let response = client
+        .post(&url)
+        .bearer_auth(admin_token)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to request feed rebuild at {}: {}", url, e))?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "failed to start feed rebuild: {}: {}",
+            response.status(),
+            response.text().await.unwrap_or_default()
+        );
+    }
+    tracing::info!("feed rebuild started at {}", server);
+
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let body = client
+            .get(&url)
+            .bearer_auth(admin_token)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read feed rebuild progress: {}", e))?
+            .text()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read feed rebuild progress: {}", e))?;
+        let progress: Progress = serde_json::from_str(&body)
+            .map_err(|e| anyhow::anyhow!("unexpected feed rebuild progress '{}': {}", body, e))?;
+        tracing::info!(
+            "{}: {}/{} feeds rebuilt, {} failed",
+            server,
+            progress.done,
+            progress.total,
+            progress.failed
+        );
+        if !progress.running {
+            return Ok(());
+        }
+    }
+}
@@ -1,14 +1,46 @@
 mod app;
 mod controller;
+mod controller_admin;
 mod controller_auth;
+mod controller_counter;
+mod controller_dialog;
+mod controller_friend;
+mod controller_group;
+mod controller_post;
 mod controller_user;
+mod counters;
 mod db;
+mod db_counter;
+mod db_feed_event;
+mod db_friend;
+mod db_group;
+mod db_message;
+mod db_outbox;
+mod db_post;
+mod db_reshard;
 mod db_user;
+mod dialog;
+mod dialog_push;
+mod feed;
+mod feed_push;
+mod feed_queue;
+mod feed_warmup;
+mod memory_store;
+#[cfg(test)]
+mod memory_users;
 mod password;
+mod pg_config;
+mod presence;
+mod push;
+mod read_your_writes;
+mod reshard;
 mod schema;
+mod shard;
+mod sqlite_users;
+mod user_repository;
 
 use app::App;
-use clap::{arg, Parser};
+use clap::Parser;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 #[tokio::main]
@@ -19,13 +51,70 @@ async fn main() {
             tracing::info!("PID {}", std::process::id());
             let server_args = server_args.init_not_specified_opts();
             tracing::info!("opts: {server_args:?}");
-            if let Err(err) = App::run(
-                &server_args.postgres_conn_string,
-                &server_args.bind_string.unwrap(),
-                server_args.conn_pool_size.unwrap(),
-            )
-            .await
-            {
+            let result = async {
+                if let Some(path) =
+                    sqlite_users::SqliteUserRepository::path(&server_args.postgres_conn_string)
+                {
+                    tracing::warn!("only the user API is served, the rest of it needs postgres");
+                    let users = sqlite_users::SqliteUserRepository::open(path).await?;
+                    return App::run_users(
+                        std::sync::Arc::new(users),
+                        server_args.bind_string.as_ref().unwrap(),
+                        std::time::Duration::from_secs(
+                            server_args.presence_flush_interval.unwrap(),
+                        ),
+                    )
+                    .await;
+                }
+                let db = std::sync::Arc::new(
+                    db::DB::new(&db::Config {
+                        conn_string: server_args.postgres_conn_string.clone(),
+                        failover_candidates: server_args.postgres_failover_candidates.clone(),
+                        replicas: server_args.postgres_replicas.clone(),
+                        pool_size: server_args.conn_pool_size.unwrap(),
+                        tls: db::TlsConfig::from(&server_args.postgres_tls),
+                        timeouts: server_args.timeouts(),
+                        retry: db::RetryPolicy {
+                            retries: server_args.postgres_retries.unwrap(),
+                            backoff: std::time::Duration::from_millis(
+                                server_args.postgres_retry_backoff.unwrap(),
+                            ),
+                        },
+                    })
+                    .await?,
+                );
+                db.spawn_workers();
+                let users =
+                    std::sync::Arc::new(user_repository::PostgresUserRepository::new(db.clone()));
+                App::run(
+                    db,
+                    users,
+                    &app::Listen {
+                        bind_string: server_args.bind_string.as_ref().unwrap(),
+                        admin_token: server_args.admin_token.as_deref(),
+                    },
+                    feed_queue::Config {
+                        workers: server_args.feed_workers.unwrap(),
+                        batch_size: server_args.feed_batch_size.unwrap(),
+                        celebrity_threshold: server_args.celebrity_threshold.unwrap(),
+                    },
+                    feed::CacheConfig {
+                        max_users: server_args.feed_cache_users.unwrap(),
+                        warmup_users: server_args.feed_warmup_users.unwrap(),
+                    },
+                    &server_args.store_config()?,
+                    &app::Intervals {
+                        counters_reconcile: std::time::Duration::from_secs(
+                            server_args.counters_reconcile_interval.unwrap(),
+                        ),
+                        presence_flush: std::time::Duration::from_secs(
+                            server_args.presence_flush_interval.unwrap(),
+                        ),
+                    },
+                )
+                .await
+            };
+            if let Err(err) = result.await {
                 tracing::error!("failed to run server: {err:?}");
             }
         }
@@ -35,6 +124,32 @@ async fn main() {
                 tracing::error!("failed to fill: {err:?}");
             }
         }
+        Cli::RebuildFeeds(args) => {
+            tracing::info!("opts: {args:?}");
+            if let Err(err) = feed_warmup::rebuild_all(&args.servers, &args.admin_token).await {
+                tracing::error!("failed to rebuild feeds: {err:?}");
+            }
+        }
+        Cli::ReshardBackfill(args) => {
+            tracing::info!("opts: {args:?}");
+            let result = async {
+                let shards = args.shards.connect().await?;
+                reshard::backfill(&shards, args.shards.batch_size, args.restart).await
+            };
+            if let Err(err) = result.await {
+                tracing::error!("failed to backfill messages: {err:?}");
+            }
+        }
+        Cli::ReshardVerify(args) => {
+            tracing::info!("opts: {args:?}");
+            let result = async {
+                let shards = args.connect().await?;
+                reshard::verify(&shards, args.batch_size).await
+            };
+            if let Err(err) = result.await {
+                tracing::error!("failed to verify messages: {err:?}");
+            }
+        }
     }
 }
 
@@ -57,8 +172,11 @@ fn init_logger() {
     help_template = "{about}\nAuthor: {author}\nUse LOG_LEVEL=debug to see debug logging\n{usage-heading} {usage}\n{all-args}{after-help}"
 )]
 enum Cli {
-    Server(ServerArgs),
+    Server(Box<ServerArgs>),
     GenerateInserts(GenerateInsert),
+    RebuildFeeds(RebuildFeeds),
+    ReshardBackfill(ReshardBackfill),
+    ReshardVerify(ReshardArgs),
 }
 
 #[derive(clap::Args, Debug, Clone)]
@@ -67,26 +185,262 @@ struct GenerateInsert {
     limit: usize,
 }
 
+/// Running servers are asked to rebuild their feed caches from postgres.
+#[derive(clap::Args, Debug, Clone)]
+struct RebuildFeeds {
+    #[arg(
+        long = "server",
+        value_name = "url",
+        required = true,
+        help = "running server whose cached feeds are rebuilt, for example, \"http://127.0.0.1:8080\", repeat to rebuild several servers at once"
+    )]
+    servers: Vec<String>,
+    #[arg(
+        long = "admin-token",
+        value_name = "token",
+        help = "admin token the servers are run with"
+    )]
+    admin_token: String,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ReshardBackfill {
+    #[command(flatten)]
+    shards: ReshardArgs,
+    #[arg(
+        long = "restart",
+        help = "ignore the saved checkpoints and copy everything again"
+    )]
+    restart: bool,
+}
+
+/// Same shards as the server being resharded is run with.
+#[derive(clap::Args, Debug, Clone)]
+struct ReshardArgs {
+    #[arg(long = "postgres-conn-string", value_name = "string")]
+    postgres_conn_string: String,
+    #[command(flatten)]
+    postgres_tls: PostgresTlsArgs,
+    #[arg(
+        long = "messages-shard",
+        value_name = "name:conn string",
+        help = "current shard of the dialog messages, repeat for every shard, messages are kept in the main DB if none"
+    )]
+    messages_shards: Vec<String>,
+    #[arg(
+        long = "messages-shard-target",
+        value_name = "name:conn string",
+        required = true,
+        help = "shard the dialog messages are moved to, repeat for every shard"
+    )]
+    messages_shard_targets: Vec<String>,
+    #[arg(
+        long = "batch-size",
+        value_name = "size",
+        default_value_t = 1000,
+        help = "messages or conversations read at once"
+    )]
+    batch_size: usize,
+}
+
+impl ReshardArgs {
+    async fn connect(&self) -> anyhow::Result<shard::Shards> {
+        const POOL_SIZE: usize = 2;
+        let db = std::sync::Arc::new(
+            db::DB::new(&db::Config {
+                conn_string: self.postgres_conn_string.clone(),
+                pool_size: POOL_SIZE,
+                tls: db::TlsConfig::from(&self.postgres_tls),
+                ..Default::default()
+            })
+            .await?,
+        );
+        let config = shard::Config {
+            shards: self.messages_shards.clone(),
+            targets: self.messages_shard_targets.clone(),
+        };
+        shard::Shards::new(&config, db, POOL_SIZE).await
+    }
+}
+
+/// Certificates of TLS connections to postgres, which are made as `sslmode` of the connection
+/// strings asks, for example, "host=db user=postgres sslmode=verify-full".
+#[derive(clap::Args, Debug, Clone)]
+struct PostgresTlsArgs {
+    #[arg(
+        long = "postgres-ssl-root-cert",
+        value_name = "path",
+        help = "PEM bundle of the CAs the postgres certificates are verified with when sslmode is verify-ca, verify-full or require, optional, the system CAs are used by default"
+    )]
+    ssl_root_cert: Option<std::path::PathBuf>,
+    #[arg(
+        long = "postgres-ssl-cert",
+        value_name = "path",
+        requires = "ssl_key",
+        help = "PEM client certificate chain for postgres requiring one, optional"
+    )]
+    ssl_cert: Option<std::path::PathBuf>,
+    #[arg(
+        long = "postgres-ssl-key",
+        value_name = "path",
+        requires = "ssl_cert",
+        help = "PEM private key of the client certificate, optional"
+    )]
+    ssl_key: Option<std::path::PathBuf>,
+}
+
+impl From<&PostgresTlsArgs> for db::TlsConfig {
+    fn from(args: &PostgresTlsArgs) -> Self {
+        Self {
+            root_cert: args.ssl_root_cert.clone(),
+            cert: args.ssl_cert.clone(),
+            key: args.ssl_key.clone(),
+        }
+    }
+}
+
 #[derive(clap::Args, Debug, Clone)]
 pub struct ServerArgs {
     #[arg(
         long = "postgres-conn-string",
         value_name = "string",
-        help = "for example, \"host=localhost user=postgres\", to specify your own DB use dbname=your_db_name otherwise DB will be created"
+        help = "key/value or URL form as libpq takes it, for example, \"host=localhost user=postgres\" or \"postgresql://postgres@localhost:5432\", parameters missing from it are taken from the PG* environment variables and the password from the password file, to specify your own DB use dbname=your_db_name otherwise DB will be created if it doesn't exist yet, \"sqlite://path/to/users.db\" keeps the users in SQLite instead and serves the user API only"
     )]
     postgres_conn_string: String,
+    #[arg(
+        long = "postgres-replica",
+        value_name = "string",
+        help = "streaming replica of the main postgres serving user profiles and search, for example, \"host=replica1 user=postgres\", repeat to add more replicas, optional, everything is read from the main postgres by default"
+    )]
+    postgres_replicas: Vec<String>,
+    #[arg(
+        long = "postgres-failover-candidate",
+        value_name = "string",
+        help = "standby of the main postgres that takes the writes once it is promoted, the server not in recovery is looked for among the main postgres and the candidates at startup and every 2 seconds, repeat to add more candidates, optional"
+    )]
+    postgres_failover_candidates: Vec<String>,
+    #[command(flatten)]
+    postgres_tls: PostgresTlsArgs,
     #[arg(
         long = "bind-string",
         value_name = "host:port",
         help = "optional, default value is \"127.0.0.1:8080\""
     )]
     bind_string: Option<String>,
+    #[arg(
+        long = "admin-token",
+        value_name = "token",
+        help = "bearer token the /admin routes are called with, optional, the /admin routes are disabled without it"
+    )]
+    admin_token: Option<String>,
     #[arg(
         long = "pg-pool-size",
         value_name = "size",
         help = "postgres connection pool size, optional, default value is 16"
     )]
     conn_pool_size: Option<usize>,
+    #[arg(
+        long = "postgres-statement-timeout",
+        value_name = "milliseconds",
+        help = "statement_timeout of the connections to the main postgres and its replicas, 0 disables it, optional, default value is 30000"
+    )]
+    postgres_statement_timeout: Option<u64>,
+    #[arg(
+        long = "postgres-pool-timeout",
+        value_name = "milliseconds",
+        help = "how long a request waits for a free postgres connection before it is answered with 503 Service Unavailable, 0 waits forever, optional, default value is 5000"
+    )]
+    postgres_pool_timeout: Option<u64>,
+    #[arg(
+        long = "postgres-retries",
+        value_name = "count",
+        help = "how many times a reading or idempotent user query is made again after a broken connection, a serialization failure or a deadlock, optional, default value is 2"
+    )]
+    postgres_retries: Option<u32>,
+    #[arg(
+        long = "postgres-retry-backoff",
+        value_name = "milliseconds",
+        help = "wait before the first retry of a postgres query, doubled before each next one, optional, default value is 50"
+    )]
+    postgres_retry_backoff: Option<u64>,
+    #[arg(
+        long = "feed-workers",
+        value_name = "count",
+        help = "number of workers delivering new posts to feeds, optional, default value is 2"
+    )]
+    feed_workers: Option<usize>,
+    #[arg(
+        long = "feed-batch-size",
+        value_name = "size",
+        help = "feed events and followers processed by a worker at once, optional, default value is 100"
+    )]
+    feed_batch_size: Option<usize>,
+    #[arg(
+        long = "celebrity-threshold",
+        value_name = "followers",
+        help = "users with more followers get posts delivered only to cached feeds, optional, default value is 10000"
+    )]
+    celebrity_threshold: Option<i64>,
+    #[arg(
+        long = "feed-warmup-users",
+        value_name = "count",
+        help = "feeds of that many most recently logged in users are loaded at startup, 0 disables warmup, optional, default value is 1000"
+    )]
+    feed_warmup_users: Option<usize>,
+    #[arg(
+        long = "feed-cache-users",
+        value_name = "count",
+        help = "most feeds kept in memory, the least recently read one is dropped to make room, optional, default value is 10000"
+    )]
+    feed_cache_users: Option<usize>,
+    #[arg(
+        long = "messages-shard",
+        value_name = "name:conn string",
+        help = "postgres keeping a shard of the dialog messages, for example, \"s1:host=localhost port=5433 user=postgres\", repeat to add more shards, optional, messages are kept in the main DB by default"
+    )]
+    messages_shards: Vec<String>,
+    #[arg(
+        long = "messages-shard-target",
+        value_name = "name:conn string",
+        help = "shard the dialog messages are being moved to, enables resharding: messages are written to both the current and the target shards until the server is restarted with the targets as --messages-shard, repeat to add more shards, optional"
+    )]
+    messages_shard_targets: Vec<String>,
+    #[arg(
+        long = "counters-reconcile-interval",
+        value_name = "seconds",
+        help = "how often unread message counters are recomputed from the messages to fix drift, optional, default value is 300"
+    )]
+    counters_reconcile_interval: Option<u64>,
+    #[arg(
+        long = "messages-store",
+        value_name = "store",
+        help = "where the dialog messages are kept: \"postgres\", or \"memory\" for the process memory backed by a write-ahead log and snapshots, optional, default value is \"postgres\""
+    )]
+    messages_store: Option<MessagesStore>,
+    #[arg(
+        long = "messages-dir",
+        value_name = "path",
+        help = "directory of the write-ahead log and the snapshots of the messages kept in memory, optional, default value is \"messages\""
+    )]
+    messages_dir: Option<std::path::PathBuf>,
+    #[arg(
+        long = "messages-snapshot-interval",
+        value_name = "seconds",
+        help = "how often the messages kept in memory are written to a snapshot, which empties the write-ahead log, optional, default value is 300"
+    )]
+    messages_snapshot_interval: Option<u64>,
+    #[arg(
+        long = "presence-flush-interval",
+        value_name = "seconds",
+        help = "how often the last-seen times of the users are written to postgres, optional, default value is 10"
+    )]
+    presence_flush_interval: Option<u64>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum MessagesStore {
+    Postgres,
+    Memory,
 }
 
 impl ServerArgs {
@@ -99,6 +453,94 @@ impl ServerArgs {
             self.conn_pool_size = Some(16);
         }
 
+        if self.postgres_statement_timeout.is_none() {
+            self.postgres_statement_timeout = Some(30000);
+        }
+
+        if self.postgres_pool_timeout.is_none() {
+            self.postgres_pool_timeout = Some(5000);
+        }
+
+        if self.postgres_retries.is_none() {
+            self.postgres_retries = Some(2);
+        }
+
+        if self.postgres_retry_backoff.is_none() {
+            self.postgres_retry_backoff = Some(50);
+        }
+
+        if self.feed_workers.is_none() {
+            self.feed_workers = Some(2);
+        }
+
+        if self.feed_batch_size.is_none() {
+            self.feed_batch_size = Some(100);
+        }
+
+        if self.celebrity_threshold.is_none() {
+            self.celebrity_threshold = Some(10000);
+        }
+
+        if self.feed_warmup_users.is_none() {
+            self.feed_warmup_users = Some(1000);
+        }
+        if self.feed_cache_users.is_none() {
+            self.feed_cache_users = Some(10000);
+        }
+
+        if self.counters_reconcile_interval.is_none() {
+            self.counters_reconcile_interval = Some(300);
+        }
+
+        if self.messages_store.is_none() {
+            self.messages_store = Some(MessagesStore::Postgres);
+        }
+
+        if self.messages_dir.is_none() {
+            self.messages_dir = Some("messages".into());
+        }
+
+        if self.messages_snapshot_interval.is_none() {
+            self.messages_snapshot_interval = Some(300);
+        }
+
+        if self.presence_flush_interval.is_none() {
+            self.presence_flush_interval = Some(10);
+        }
+
         self
     }
+
+    /// Zero turns a timeout off.
+    fn timeouts(&self) -> db::Timeouts {
+        let millis = |value: Option<u64>| {
+            value
+                .filter(|millis| *millis > 0)
+                .map(std::time::Duration::from_millis)
+        };
+        db::Timeouts {
+            statement: millis(self.postgres_statement_timeout),
+            pool_wait: millis(self.postgres_pool_timeout),
+        }
+    }
+
+    fn store_config(&self) -> anyhow::Result<dialog::StoreConfig> {
+        match self.messages_store.unwrap() {
+            MessagesStore::Postgres => Ok(dialog::StoreConfig::Postgres(shard::Config {
+                shards: self.messages_shards.clone(),
+                targets: self.messages_shard_targets.clone(),
+            })),
+            MessagesStore::Memory => {
+                if !self.messages_shards.is_empty() || !self.messages_shard_targets.is_empty() {
+                    anyhow::bail!("messages kept in memory can't be sharded");
+                }
+                Ok(dialog::StoreConfig::Memory(memory_store::Config {
+                    dir: self.messages_dir.clone().unwrap(),
+                    snapshot_interval: std::time::Duration::from_secs(
+                        self.messages_snapshot_interval.unwrap(),
+                    ),
+                }))
+            }
+        }
+    }
 }
@@ -0,0 +1,1020 @@
+use crate::{
+    db,
+    db_counter::Counter,
+    db_message::{self, Message, ReadMarker},
+    db_outbox::{Kind, OutboxEntry},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, OnceLock, RwLock,
+    },
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const WAL_FILE: &str = "wal.log";
+/// The log written before the last snapshot, kept until the snapshot is safely stored.
+const OLD_WAL_FILE: &str = "wal.log.old";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub dir: PathBuf,
+    pub snapshot_interval: Duration,
+}
+
+/// Dialog messages kept entirely in process memory.
+///
+/// Every change is applied and queued to the write-ahead log in one step. A writer thread
+/// appends the queued changes and flushes them to disk with a single sync, and only then are
+/// they acknowledged, so an acknowledged write survives a crash. A change may be seen by
+/// readers shortly before it is flushed; as the log is written in order, a change lost to
+/// a crash takes every later one with it. The state is periodically written to a snapshot
+/// and the log is started anew; on startup the snapshot is loaded and the log replayed on top.
+/// Unread counters are updated right after the change, the reconciliation repairs the ones
+/// lost to a crash in between.
+pub struct MemoryStore {
+    dir: PathBuf,
+    snapshot_interval: Duration,
+    db: Arc<db::DB>,
+    /// Sent while the state is locked for writing, so the log keeps the order of the changes.
+    log: mpsc::Sender<LogCommand>,
+    /// Set by the writer thread once the log fails to be written. The state is ahead of the
+    /// log from then on, so writes are refused until the store is reopened.
+    log_failure: Arc<OnceLock<String>>,
+    state: RwLock<State>,
+    /// Sequence number of the last change in the snapshot.
+    snapshot_seq: AtomicU64,
+}
+
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+struct State {
+    /// Sequence number of the last change applied.
+    seq: u64,
+    last_message_id: i64,
+    conversations: HashMap<String, Conversation>,
+    /// Counter changes applied to the state but not to the counters yet.
+    #[serde(skip)]
+    pending: HashMap<(String, String), i64>,
+    /// Messages by the words of their text, for each participant. Built when the state
+    /// is restored.
+    #[serde(skip)]
+    words: HashMap<String, BTreeMap<String, Vec<MessageRef>>>,
+}
+
+/// Conversation id and position of a message within the conversation.
+type MessageRef = (String, usize);
+
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+struct Conversation {
+    /// In id order.
+    messages: Vec<StoredMessage>,
+    /// By user id.
+    markers: HashMap<String, Marker>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct StoredMessage {
+    id: i64,
+    from_user_id: String,
+    to_user_id: String,
+    text: String,
+    created_at: String,
+}
+
+#[derive(Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct Marker {
+    last_read_id: i64,
+    last_delivered_id: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct LogEntry {
+    seq: u64,
+    #[serde(flatten)]
+    change: Change,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Change {
+    Sent(StoredMessage),
+    Read {
+        conversation_id: String,
+        user_id: String,
+        message_id: i64,
+    },
+    Delivered {
+        conversation_id: String,
+        user_id: String,
+        message_id: i64,
+    },
+}
+
+impl MemoryStore {
+    /// Restores the state from the snapshot and the log, then compacts them into a new
+    /// snapshot so the log starts empty.
+    pub fn open(config: &Config, db: Arc<db::DB>) -> anyhow::Result<Self> {
+        let started = std::time::Instant::now();
+        fs::create_dir_all(&config.dir)
+            .map_err(|e| anyhow::anyhow!("failed to create directory {:?}: {}", config.dir, e))?;
+        let state = restore(&config.dir)?;
+        tracing::info!(
+            "message store restored in {:?}: {} conversations",
+            started.elapsed(),
+            state.conversations.len(),
+        );
+
+        write_snapshot(&config.dir, &state)?;
+        for file in [OLD_WAL_FILE, WAL_FILE] {
+            remove_if_exists(&config.dir.join(file))?;
+        }
+        let (log, commands) = mpsc::channel();
+        let log_failure = Arc::new(OnceLock::new());
+        let writer = LogWriter {
+            dir: config.dir.clone(),
+            wal: open_wal(&config.dir)?,
+            failure: log_failure.clone(),
+        };
+        std::thread::Builder::new()
+            .name("message-log".to_owned())
+            .spawn(move || writer.run(commands))
+            .map_err(|e| anyhow::anyhow!("failed to start message log writer: {}", e))?;
+        Ok(Self {
+            dir: config.dir.clone(),
+            snapshot_interval: config.snapshot_interval,
+            db,
+            log,
+            log_failure,
+            snapshot_seq: AtomicU64::new(state.seq),
+            state: RwLock::new(state),
+        })
+    }
+
+    pub fn spawn_workers(self: &Arc<Self>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + store.snapshot_interval,
+                store.snapshot_interval,
+            );
+            loop {
+                interval.tick().await;
+                let snapshot = store.clone();
+                match tokio::task::spawn_blocking(move || snapshot.snapshot()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => tracing::error!("failed to snapshot messages: {err:?}"),
+                    Err(err) => tracing::error!("message snapshot panicked: {err:?}"),
+                }
+            }
+        });
+    }
+
+    pub async fn send(
+        &self,
+        from_user_id: &str,
+        to_user_id: &str,
+        text: &str,
+    ) -> anyhow::Result<Message> {
+        let written = self
+            .write(|state| {
+                let id = state.last_message_id + 1;
+                let counted = OutboxEntry {
+                    id: 0,
+                    kind: Kind::Sent,
+                    conversation_id: db_message::conversation_id(from_user_id, to_user_id),
+                    user_id: to_user_id.to_owned(),
+                    other_user_id: from_user_id.to_owned(),
+                    message_id: id,
+                    previous_id: None,
+                    delta: 1,
+                    attempts: 0,
+                };
+                let message = StoredMessage {
+                    id,
+                    from_user_id: from_user_id.to_owned(),
+                    to_user_id: to_user_id.to_owned(),
+                    text: text.to_owned(),
+                    created_at: chrono::Utc::now().to_rfc3339(),
+                };
+                Some((Change::Sent(message), Some(counted)))
+            })
+            .await?;
+        let Some((Change::Sent(message), Some(counted))) = written else {
+            unreachable!("a message is always sent")
+        };
+        self.count(counted).await;
+        Ok(message.into())
+    }
+
+    /// Same as `dialog::mark_read`.
+    pub async fn mark_read(
+        &self,
+        user_id: &str,
+        other_user_id: &str,
+        up_to: i64,
+    ) -> anyhow::Result<Option<i64>> {
+        let conversation_id = db_message::conversation_id(user_id, other_user_id);
+        let written = self
+            .write(|state| {
+                let conversation = state.conversations.get(&conversation_id)?;
+                let previous_id = conversation.marker(user_id).last_read_id;
+                // only messages sent to the user can be read by them
+                let received: Vec<i64> = conversation
+                    .messages
+                    .iter()
+                    .filter(|m| m.to_user_id == user_id && m.id > previous_id && m.id <= up_to)
+                    .map(|m| m.id)
+                    .collect();
+                let last_read_id = *received.last()?;
+                let counted = OutboxEntry {
+                    id: 0,
+                    kind: Kind::Read,
+                    conversation_id: conversation_id.clone(),
+                    user_id: user_id.to_owned(),
+                    other_user_id: other_user_id.to_owned(),
+                    message_id: last_read_id,
+                    previous_id: Some(previous_id),
+                    delta: -(received.len() as i64),
+                    attempts: 0,
+                };
+                let change = Change::Read {
+                    conversation_id: conversation_id.clone(),
+                    user_id: user_id.to_owned(),
+                    message_id: last_read_id,
+                };
+                Some((change, Some(counted)))
+            })
+            .await?;
+        let Some((_, Some(counted))) = written else {
+            return Ok(None);
+        };
+        let last_read_id = counted.message_id;
+        self.count(counted).await;
+        Ok(Some(last_read_id))
+    }
+
+    /// Same as `dialog::mark_delivered`.
+    pub async fn mark_delivered(
+        &self,
+        user_id: &str,
+        other_user_id: &str,
+        up_to: i64,
+    ) -> anyhow::Result<bool> {
+        let conversation_id = db_message::conversation_id(user_id, other_user_id);
+        let written = self
+            .write(|state| {
+                let marker = state
+                    .conversations
+                    .get(&conversation_id)
+                    .map(|conversation| conversation.marker(user_id))
+                    .unwrap_or_default();
+                let change = Change::Delivered {
+                    conversation_id: conversation_id.clone(),
+                    user_id: user_id.to_owned(),
+                    message_id: up_to,
+                };
+                (marker.last_delivered_id < up_to && marker.last_read_id < up_to)
+                    .then_some((change, None))
+            })
+            .await?;
+        Ok(written.is_some())
+    }
+
+    /// Messages of the dialog newest first, with the markers of its participants.
+    pub fn list(
+        &self,
+        user_id: &str,
+        other_user_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<Message>, Vec<ReadMarker>) {
+        let conversation_id = db_message::conversation_id(user_id, other_user_id);
+        let state = self.state.read().unwrap();
+        let Some(conversation) = state.conversations.get(&conversation_id) else {
+            return (Vec::new(), Vec::new());
+        };
+        let messages = conversation
+            .messages
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .map(Message::from)
+            .collect();
+        let markers = conversation
+            .markers
+            .iter()
+            .map(|(user_id, marker)| ReadMarker {
+                conversation_id: conversation_id.clone(),
+                user_id: user_id.clone(),
+                last_read_id: marker.last_read_id,
+                last_delivered_id: marker.last_delivered_id,
+            })
+            .collect();
+        (messages, markers)
+    }
+
+    pub fn has_dialog(&self, user_id: &str, other_user_id: &str) -> bool {
+        let conversation_id = db_message::conversation_id(user_id, other_user_id);
+        self.state
+            .read()
+            .unwrap()
+            .conversations
+            .get(&conversation_id)
+            .is_some_and(|conversation| !conversation.messages.is_empty())
+    }
+
+    /// Up to `limit` messages of the user's conversations having a word starting with every
+    /// word of the query, newest first.
+    pub fn search(&self, user_id: &str, query: &str, limit: usize) -> Vec<db_message::Found> {
+        self.state.read().unwrap().search(user_id, query, limit)
+    }
+
+    /// Non-zero unread counters computed from the messages, leaving out the changes still
+    /// being applied to the stored counters.
+    pub fn unread(&self) -> Vec<Counter> {
+        let state = self.state.read().unwrap();
+        let mut unread: HashMap<(String, String), i64> = HashMap::new();
+        for conversation in state.conversations.values() {
+            for message in &conversation.messages {
+                if message.id > conversation.marker(&message.to_user_id).last_read_id {
+                    *unread
+                        .entry((message.to_user_id.clone(), message.from_user_id.clone()))
+                        .or_default() += 1;
+                }
+            }
+        }
+        for (dialog, delta) in &state.pending {
+            *unread.entry(dialog.clone()).or_default() -= delta;
+        }
+        unread
+            .into_iter()
+            .filter(|(_, unread)| *unread > 0)
+            .map(|((user_id, other_user_id), unread)| Counter {
+                user_id,
+                other_user_id,
+                unread,
+            })
+            .collect()
+    }
+
+    /// Applies the change built by `prepare` from the current state, together with the counter
+    /// change it causes to the pending ones, and waits until it is logged. Nothing is written
+    /// when `prepare` returns `None`.
+    async fn write(
+        &self,
+        prepare: impl FnOnce(&State) -> Option<(Change, Option<OutboxEntry>)>,
+    ) -> anyhow::Result<Option<(Change, Option<OutboxEntry>)>> {
+        let (written, logged) = {
+            let mut state = self.state.write().unwrap();
+            if let Some(err) = self.log_failure.get() {
+                anyhow::bail!("message log is broken since: {}", err);
+            }
+            let Some((change, counted)) = prepare(&state) else {
+                return Ok(None);
+            };
+            let seq = state.seq + 1;
+            let mut line = serde_json::to_vec(&LogEntry {
+                seq,
+                change: change.clone(),
+            })?;
+            line.push(b'\n');
+            let (done, logged) = oneshot::channel();
+            self.log
+                .send(LogCommand::Append(line, done))
+                .map_err(|_| anyhow::anyhow!("message log writer has stopped"))?;
+
+            state.apply(seq, &change);
+            if let Some(counted) = &counted {
+                *state
+                    .pending
+                    .entry((counted.user_id.clone(), counted.other_user_id.clone()))
+                    .or_default() += counted.delta;
+            }
+            ((change, counted), logged)
+        };
+        logged
+            .await
+            .map_err(|_| anyhow::anyhow!("message log writer has stopped"))?
+            .map_err(|e| anyhow::anyhow!("failed to write message log: {}", e))?;
+        Ok(Some(written))
+    }
+
+    /// Applies the counter change of a logged message change. A change lost here is
+    /// repaired by the reconciliation.
+    async fn count(&self, change: OutboxEntry) {
+        let dialog = (change.user_id.clone(), change.other_user_id.clone());
+        let result = async {
+            let mut pg_pool = self.db.get().await?;
+            let transaction = pg_pool
+                .transaction()
+                .await
+                .map_err(|e| anyhow::anyhow!("transaction error: {}", e))?;
+            Counter::apply(
+                &change.user_id,
+                &change.other_user_id,
+                change.delta,
+                &change.idempotency_key(),
+                transaction.client(),
+            )
+            .await?;
+            transaction
+                .commit()
+                .await
+                .map_err(|e| anyhow::anyhow!("commit error: {}", e))
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::error!(
+                "failed to update unread counter with {}: {err:?}",
+                change.idempotency_key()
+            );
+        }
+
+        let mut state = self.state.write().unwrap();
+        if let Some(pending) = state.pending.get_mut(&dialog) {
+            *pending -= change.delta;
+            if *pending == 0 {
+                state.pending.remove(&dialog);
+            }
+        }
+    }
+
+    /// Writes the current state to a new snapshot and drops the log it covers. Writers are
+    /// only held while the log switch is queued and the state copied.
+    fn snapshot(&self) -> anyhow::Result<()> {
+        let started = std::time::Instant::now();
+        let (state, rotated) = {
+            let state = self.state.read().unwrap();
+            if state.seq == self.snapshot_seq.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let (done, rotated) = oneshot::channel();
+            self.log
+                .send(LogCommand::Rotate(done))
+                .map_err(|_| anyhow::anyhow!("message log writer has stopped"))?;
+            (state.clone(), rotated)
+        };
+        rotated
+            .blocking_recv()
+            .map_err(|_| anyhow::anyhow!("message log writer has stopped"))??;
+        write_snapshot(&self.dir, &state)?;
+        remove_if_exists(&self.dir.join(OLD_WAL_FILE))?;
+        self.snapshot_seq.store(state.seq, Ordering::Relaxed);
+        tracing::info!(
+            "messages snapshot at change {} written in {:?}",
+            state.seq,
+            started.elapsed()
+        );
+        Ok(())
+    }
+}
+
+enum LogCommand {
+    /// A log line, acknowledged once it is on disk.
+    Append(Vec<u8>, oneshot::Sender<Result<(), String>>),
+    /// Moves the log written so far to the old one, after the lines queued before.
+    Rotate(oneshot::Sender<anyhow::Result<()>>),
+}
+
+struct LogWriter {
+    dir: PathBuf,
+    wal: fs::File,
+    failure: Arc<OnceLock<String>>,
+}
+
+impl LogWriter {
+    /// Takes all the lines queued meanwhile, writes them and flushes them with a single sync
+    /// (group commit). Stops once the store is dropped.
+    fn run(mut self, commands: mpsc::Receiver<LogCommand>) {
+        let mut next = commands.recv().ok();
+        while let Some(command) = next.take() {
+            let mut lines = Vec::new();
+            let mut acks = Vec::new();
+            let mut rotate = None;
+            let mut command = Some(command);
+            while let Some(queued) = command.take() {
+                match queued {
+                    LogCommand::Append(line, done) => {
+                        lines.extend_from_slice(&line);
+                        acks.push(done);
+                        command = commands.try_recv().ok();
+                    }
+                    LogCommand::Rotate(done) => rotate = Some(done),
+                }
+            }
+            if !acks.is_empty() {
+                let result = self.append(&lines);
+                for done in acks {
+                    let _ = done.send(result.clone());
+                }
+            }
+            if let Some(done) = rotate {
+                let _ = done.send(self.rotate());
+            }
+            next = commands.recv().ok();
+        }
+    }
+
+    fn append(&mut self, lines: &[u8]) -> Result<(), String> {
+        if let Some(err) = self.failure.get() {
+            return Err(err.clone());
+        }
+        let result = self
+            .wal
+            .write_all(lines)
+            .and_then(|()| self.wal.sync_data());
+        result.map_err(|err| {
+            let err = err.to_string();
+            tracing::error!("failed to write message log, refusing writes until restart: {err}");
+            self.failure.get_or_init(|| err).clone()
+        })
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        if let Some(err) = self.failure.get() {
+            anyhow::bail!("message log is broken since: {}", err);
+        }
+        let current = self.dir.join(WAL_FILE);
+        let old = self.dir.join(OLD_WAL_FILE);
+        if old.exists() {
+            // the previous snapshot has failed, so the old log is still needed
+            let mut old = fs::OpenOptions::new()
+                .append(true)
+                .open(&old)
+                .map_err(|e| anyhow::anyhow!("failed to open {:?}: {}", old, e))?;
+            old.write_all(&fs::read(&current)?)?;
+            old.sync_data()?;
+            fs::remove_file(&current)?;
+        } else {
+            fs::rename(&current, &old)
+                .map_err(|e| anyhow::anyhow!("failed to rotate message log: {}", e))?;
+        }
+        self.wal = open_wal(&self.dir)?;
+        Ok(())
+    }
+}
+
+impl State {
+    fn apply(&mut self, seq: u64, change: &Change) {
+        self.seq = seq;
+        match change {
+            Change::Sent(message) => {
+                self.last_message_id = self.last_message_id.max(message.id);
+                let conversation_id =
+                    db_message::conversation_id(&message.from_user_id, &message.to_user_id);
+                let messages = &mut self
+                    .conversations
+                    .entry(conversation_id.clone())
+                    .or_default()
+                    .messages;
+                messages.push(message.clone());
+                let index = messages.len() - 1;
+                index_message(&mut self.words, message, (conversation_id, index));
+            }
+            Change::Read {
+                conversation_id,
+                user_id,
+                message_id,
+            } => {
+                let marker = self.marker_mut(conversation_id, user_id);
+                marker.last_read_id = marker.last_read_id.max(*message_id);
+                marker.last_delivered_id = marker.last_delivered_id.max(*message_id);
+            }
+            Change::Delivered {
+                conversation_id,
+                user_id,
+                message_id,
+            } => {
+                let marker = self.marker_mut(conversation_id, user_id);
+                marker.last_delivered_id = marker.last_delivered_id.max(*message_id);
+            }
+        }
+    }
+
+    fn search(&self, user_id: &str, query: &str, limit: usize) -> Vec<db_message::Found> {
+        let terms = lowercase_words(query);
+        let Some(words) = self.words.get(user_id) else {
+            return Vec::new();
+        };
+        let mut matching: Option<HashSet<&MessageRef>> = None;
+        for term in &terms {
+            let found: HashSet<&MessageRef> = words
+                .range(term.clone()..)
+                .take_while(|(word, _)| word.starts_with(term.as_str()))
+                .flat_map(|(_, messages)| messages)
+                .collect();
+            matching = Some(match matching {
+                None => found,
+                Some(matching) => matching.intersection(&found).copied().collect(),
+            });
+        }
+        let mut found: Vec<&StoredMessage> = matching
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(conversation_id, index)| &self.conversations[conversation_id].messages[*index])
+            .collect();
+        found.sort_by_key(|message| std::cmp::Reverse(message.id));
+        found
+            .into_iter()
+            .take(limit)
+            .map(|message| db_message::Found {
+                highlight: highlight(&message.text, &terms),
+                sent_at: chrono::DateTime::parse_from_rfc3339(&message.created_at)
+                    .map_or(0, |sent_at| sent_at.timestamp_micros()),
+                message: db_message::FoundMessage::Dialog(message.clone().into()),
+            })
+            .collect()
+    }
+
+    fn index_words(&mut self) {
+        for (conversation_id, conversation) in &self.conversations {
+            for (index, message) in conversation.messages.iter().enumerate() {
+                index_message(&mut self.words, message, (conversation_id.clone(), index));
+            }
+        }
+    }
+
+    fn marker_mut(&mut self, conversation_id: &str, user_id: &str) -> &mut Marker {
+        self.conversations
+            .entry(conversation_id.to_owned())
+            .or_default()
+            .markers
+            .entry(user_id.to_owned())
+            .or_default()
+    }
+}
+
+impl Conversation {
+    fn marker(&self, user_id: &str) -> Marker {
+        self.markers.get(user_id).copied().unwrap_or_default()
+    }
+}
+
+impl From<StoredMessage> for Message {
+    fn from(message: StoredMessage) -> Self {
+        Self {
+            id: message.id,
+            from_user_id: message.from_user_id,
+            to_user_id: message.to_user_id,
+            text: message.text,
+            created_at: message.created_at,
+            state: None,
+        }
+    }
+}
+
+/// Splits the text into runs of alphanumeric characters and runs of the others, telling
+/// which is which.
+fn words(text: &str) -> impl Iterator<Item = (&str, bool)> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let is_word = first.is_alphanumeric();
+        let end = rest
+            .char_indices()
+            .find(|(_, c)| c.is_alphanumeric() != is_word)
+            .map_or(rest.len(), |(i, _)| i);
+        let (run, tail) = rest.split_at(end);
+        rest = tail;
+        Some((run, is_word))
+    })
+}
+
+fn lowercase_words(text: &str) -> Vec<String> {
+    words(text)
+        .filter(|(_, is_word)| *is_word)
+        .map(|(word, _)| word.to_lowercase())
+        .collect()
+}
+
+fn index_message(
+    words: &mut HashMap<String, BTreeMap<String, Vec<MessageRef>>>,
+    message: &StoredMessage,
+    at: MessageRef,
+) {
+    let mut message_words = lowercase_words(&message.text);
+    message_words.sort();
+    message_words.dedup();
+    let mut users = vec![&message.from_user_id, &message.to_user_id];
+    users.dedup();
+    for user_id in users {
+        let user_words = words.entry(user_id.clone()).or_default();
+        for word in &message_words {
+            user_words.entry(word.clone()).or_default().push(at.clone());
+        }
+    }
+}
+
+/// Same markup as the highlights of the messages kept in postgres.
+fn highlight(text: &str, terms: &[String]) -> String {
+    let mut highlight = String::with_capacity(text.len());
+    for (run, is_word) in words(text) {
+        let escaped = run
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        let lowercase = run.to_lowercase();
+        if is_word && terms.iter().any(|term| lowercase.starts_with(term)) {
+            highlight.push_str("<b>");
+            highlight.push_str(&escaped);
+            highlight.push_str("</b>");
+        } else {
+            highlight.push_str(&escaped);
+        }
+    }
+    highlight
+}
+
+/// Loads the snapshot and replays the old log and then the current one on top.
+fn restore(dir: &Path) -> anyhow::Result<State> {
+    let mut state = match fs::read(dir.join(SNAPSHOT_FILE)) {
+        Ok(data) => serde_json::from_slice::<State>(&data)
+            .map_err(|e| anyhow::anyhow!("failed to read message snapshot: {}", e))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => State::default(),
+        Err(err) => anyhow::bail!("failed to read message snapshot: {}", err),
+    };
+    state.index_words();
+    let snapshot_seq = state.seq;
+    for file in [OLD_WAL_FILE, WAL_FILE] {
+        replay(&dir.join(file), &mut state)?;
+    }
+    tracing::info!("{} message changes replayed", state.seq - snapshot_seq);
+    Ok(state)
+}
+
+/// Applies the changes of the log newer than the state. A torn last line is left from
+/// a crash in the middle of a write that has never been acknowledged, so it is dropped.
+fn replay(path: &Path, state: &mut State) -> anyhow::Result<()> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => anyhow::bail!("failed to read {:?}: {}", path, err),
+    };
+    let mut lines = data.split(|byte| *byte == b'\n').peekable();
+    let mut number = 0;
+    while let Some(line) = lines.next() {
+        number += 1;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: LogEntry = match serde_json::from_slice(line) {
+            Ok(entry) => entry,
+            Err(err) if lines.peek().is_none() => {
+                tracing::warn!("torn write at the end of {:?} dropped: {}", path, err);
+                break;
+            }
+            Err(err) => anyhow::bail!("{:?} is corrupted at line {}: {}", path, number, err),
+        };
+        if entry.seq > state.seq {
+            state.apply(entry.seq, &entry.change);
+        }
+    }
+    Ok(())
+}
+
+/// Written next to the old snapshot and renamed over it, so there's always a complete one.
+fn write_snapshot(dir: &Path, state: &State) -> anyhow::Result<()> {
+    let path = dir.join(SNAPSHOT_FILE);
+    let tmp = dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+    let file =
+        fs::File::create(&tmp).map_err(|e| anyhow::anyhow!("failed to create {:?}: {}", tmp, e))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, state)
+        .map_err(|e| anyhow::anyhow!("failed to write message snapshot: {}", e))?;
+    writer
+        .into_inner()
+        .map_err(|e| anyhow::anyhow!("failed to write message snapshot: {}", e))?
+        .sync_all()?;
+    fs::rename(&tmp, &path).map_err(|e| anyhow::anyhow!("failed to replace {:?}: {}", path, e))?;
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn open_wal(dir: &Path) -> anyhow::Result<fs::File> {
+    let path = dir.join(WAL_FILE);
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| anyhow::anyhow!("failed to open {:?}: {}", path, e))
+}
+
+fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            anyhow::bail!("failed to remove {:?}: {}", path, err)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory of the test, cleared on every run.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("memory-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn message(id: i64, from_user_id: &str, to_user_id: &str, text: &str) -> StoredMessage {
+        StoredMessage {
+            id,
+            from_user_id: from_user_id.to_owned(),
+            to_user_id: to_user_id.to_owned(),
+            text: text.to_owned(),
+            created_at: "2024-01-01T00:00:00Z".to_owned(),
+        }
+    }
+
+    fn log_line(seq: u64, text: &str) -> String {
+        let entry = LogEntry {
+            seq,
+            change: Change::Sent(message(seq as i64, "alice", "bob", text)),
+        };
+        format!("{}\n", serde_json::to_string(&entry).unwrap())
+    }
+
+    fn texts(state: &State) -> Vec<&str> {
+        let mut messages: Vec<&StoredMessage> = state
+            .conversations
+            .values()
+            .flat_map(|conversation| &conversation.messages)
+            .collect();
+        messages.sort_by_key(|message| message.id);
+        messages
+            .into_iter()
+            .map(|message| message.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn torn_last_line_is_dropped() {
+        let dir = test_dir("torn");
+        let torn = log_line(2, "second");
+        fs::write(
+            dir.join(WAL_FILE),
+            log_line(1, "first") + &torn[..torn.len() / 2],
+        )
+        .unwrap();
+        let state = restore(&dir).unwrap();
+        assert_eq!(texts(&state), ["first"]);
+        assert_eq!(state.seq, 1);
+    }
+
+    #[test]
+    fn corrupted_line_in_the_middle_fails() {
+        let dir = test_dir("corrupted");
+        let torn = log_line(2, "second");
+        fs::write(
+            dir.join(WAL_FILE),
+            log_line(1, "first") + &torn[..torn.len() / 2] + "\n" + &log_line(3, "third"),
+        )
+        .unwrap();
+        let err = restore(&dir).err().unwrap().to_string();
+        assert!(err.contains("corrupted at line 2"), "{err}");
+    }
+
+    #[test]
+    fn old_log_is_replayed_before_the_current_one() {
+        let dir = test_dir("old-log");
+        fs::write(
+            dir.join(OLD_WAL_FILE),
+            log_line(1, "first") + &log_line(2, "second"),
+        )
+        .unwrap();
+        fs::write(dir.join(WAL_FILE), log_line(3, "third")).unwrap();
+        let state = restore(&dir).unwrap();
+        assert_eq!(texts(&state), ["first", "second", "third"]);
+        assert_eq!(state.seq, 3);
+        assert_eq!(state.last_message_id, 3);
+    }
+
+    #[test]
+    fn changes_in_the_snapshot_are_not_replayed() {
+        let dir = test_dir("snapshot");
+        let mut snapshot = State::default();
+        for seq in 1..=2 {
+            snapshot.apply(
+                seq,
+                &Change::Sent(message(seq as i64, "alice", "bob", "old")),
+            );
+        }
+        write_snapshot(&dir, &snapshot).unwrap();
+        // the snapshot failed to be taken before the old log got removed
+        fs::write(
+            dir.join(OLD_WAL_FILE),
+            log_line(1, "old") + &log_line(2, "old"),
+        )
+        .unwrap();
+        fs::write(dir.join(WAL_FILE), log_line(2, "old") + &log_line(3, "new")).unwrap();
+        let state = restore(&dir).unwrap();
+        assert_eq!(texts(&state), ["old", "old", "new"]);
+        assert_eq!(state.seq, 3);
+        // the words of the snapshot are indexed as well
+        assert_eq!(state.words["alice"]["old"].len(), 2);
+    }
+
+    fn state_with(messages: &[(&str, &str, &str)]) -> State {
+        let mut state = State::default();
+        for (n, (from_user_id, to_user_id, text)) in messages.iter().enumerate() {
+            let seq = n as u64 + 1;
+            state.apply(
+                seq,
+                &Change::Sent(message(seq as i64, from_user_id, to_user_id, text)),
+            );
+        }
+        state
+    }
+
+    /// Ids of the found messages, in the order they were found.
+    fn found(state: &State, user_id: &str, query: &str) -> Vec<i64> {
+        state
+            .search(user_id, query, 10)
+            .into_iter()
+            .map(|found| match found.message {
+                db_message::FoundMessage::Dialog(message) => message.id,
+                db_message::FoundMessage::Group { .. } => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn words_split_text_into_word_and_other_runs() {
+        let runs: Vec<(&str, bool)> = words("Hi, Мир-42!").collect();
+        assert_eq!(
+            runs,
+            [
+                ("Hi", true),
+                (", ", false),
+                ("Мир", true),
+                ("-", false),
+                ("42", true),
+                ("!", false)
+            ]
+        );
+        assert_eq!(lowercase_words("Hi, Мир-42!"), ["hi", "мир", "42"]);
+    }
+
+    #[test]
+    fn search_matches_word_prefixes() {
+        let state = state_with(&[
+            ("alice", "bob", "Meeting tomorrow"),
+            ("bob", "alice", "the meet is off"),
+            ("alice", "bob", "unmet"),
+        ]);
+        assert_eq!(found(&state, "alice", "MEET"), [2, 1]);
+        assert_eq!(found(&state, "alice", "meeting"), [1]);
+        assert_eq!(found(&state, "alice", "met"), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn search_intersects_the_terms() {
+        let state = state_with(&[
+            ("alice", "bob", "red apple"),
+            ("alice", "bob", "green apple"),
+            ("alice", "bob", "red car"),
+        ]);
+        assert_eq!(found(&state, "alice", "apple"), [2, 1]);
+        assert_eq!(found(&state, "alice", "red app"), [1]);
+        assert_eq!(found(&state, "alice", "app red gr"), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn search_sees_only_the_users_conversations() {
+        let state = state_with(&[
+            ("alice", "bob", "secret plan"),
+            ("carol", "dave", "secret party"),
+        ]);
+        assert_eq!(found(&state, "bob", "secret"), [1]);
+        assert_eq!(found(&state, "carol", "secret"), [2]);
+        assert_eq!(found(&state, "erin", "secret"), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn highlight_uses_the_ts_headline_markup() {
+        let terms = lowercase_words("fish");
+        assert_eq!(
+            highlight("Fish & <chips> > fishing", &terms),
+            "<b>Fish</b> &amp; &lt;chips&gt; &gt; <b>fishing</b>"
+        );
+        let state = state_with(&[("alice", "bob", "<b>bold</b> & fish")]);
+        let results = state.search("alice", "b", 10);
+        assert_eq!(
+            results[0].highlight,
+            "&lt;<b>b</b>&gt;<b>bold</b>&lt;/<b>b</b>&gt; &amp; fish"
+        );
+    }
+}
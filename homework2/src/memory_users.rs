@@ -0,0 +1,155 @@
+use crate::{db, db_user, password, user_repository::UserRepository};
+use std::{collections::HashMap, sync::Mutex};
+
+/// Users kept in the process memory, so the user API can be run without a database.
+#[derive(Default)]
+pub struct MemoryUserRepository {
+    users: Mutex<HashMap<String, StoredUser>>,
+    /// Run by the next update of the last-seen times, which then fails.
+    last_seen_failure: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+struct StoredUser {
+    user: db_user::User,
+    last_seen_at: Option<i64>,
+    presence_hidden: bool,
+}
+
+impl MemoryUserRepository {
+    /// Makes the next update of the last-seen times fail after running `meanwhile`.
+    pub fn fail_next_last_seen_update(&self, meanwhile: impl FnOnce() + Send + 'static) {
+        *self.last_seen_failure.lock().unwrap() = Some(Box::new(meanwhile));
+    }
+}
+
+#[axum::async_trait]
+impl UserRepository for MemoryUserRepository {
+    async fn insert(&self, user: db_user::User) -> anyhow::Result<String> {
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(&user.id) {
+            anyhow::bail!("insert failed: user with id '{}' already exists", user.id);
+        }
+        let id = user.id.clone();
+        users.insert(
+            id.clone(),
+            StoredUser {
+                user: db_user::User {
+                    token: String::new(),
+                    ..user
+                },
+                last_seen_at: None,
+                presence_hidden: false,
+            },
+        );
+        Ok(id)
+    }
+
+    async fn get(
+        &self,
+        id: &str,
+        _min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Option<db_user::Profile>> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|stored| stored.user.clone().into()))
+    }
+
+    async fn search(
+        &self,
+        first_name: Option<&String>,
+        second_name: Option<&String>,
+        _min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Vec<db_user::Profile>> {
+        if first_name.is_none() && second_name.is_none() {
+            return Ok(vec![]);
+        }
+        let users = self.users.lock().unwrap();
+        let mut found: Vec<db_user::Profile> = users
+            .values()
+            .map(|stored| &stored.user)
+            .filter(|user| first_name.is_none_or(|name| user.first_name.starts_with(name.as_str())))
+            .filter(|user| {
+                second_name.is_none_or(|name| user.second_name.starts_with(name.as_str()))
+            })
+            .map(|user| user.clone().into())
+            .collect();
+        found.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(found)
+    }
+
+    async fn update_token(
+        &self,
+        user_id: &str,
+        password: &str,
+    ) -> anyhow::Result<db_user::UpdatedTokenResult> {
+        let password_hash = match self.users.lock().unwrap().get(user_id) {
+            None => return Ok(db_user::UpdatedTokenResult::UserNotFound),
+            Some(stored) => stored.user.password_hash.clone(),
+        };
+        // hashing is slow, the other requests don't wait for it
+        if !password::verify_password(password, &password_hash) {
+            return Ok(db_user::UpdatedTokenResult::WrongPassword);
+        }
+        let mut users = self.users.lock().unwrap();
+        let Some(stored) = users.get_mut(user_id) else {
+            return Ok(db_user::UpdatedTokenResult::UserNotFound);
+        };
+        stored.user.token = uuid::Uuid::new_v4().to_string();
+        Ok(db_user::UpdatedTokenResult::Ok(stored.user.clone()))
+    }
+
+    async fn user_id_by_token(&self, token: &str) -> anyhow::Result<Option<String>> {
+        if token.is_empty() {
+            return Ok(None);
+        }
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .find(|stored| stored.user.token == token)
+            .map(|stored| stored.user.id.clone()))
+    }
+
+    async fn presence(
+        &self,
+        user_ids: &[String],
+        _min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Vec<db_user::StoredPresence>> {
+        let users = self.users.lock().unwrap();
+        Ok(user_ids
+            .iter()
+            .filter_map(|id| users.get(id))
+            .map(|stored| db_user::StoredPresence {
+                user_id: stored.user.id.clone(),
+                last_seen_at: stored.last_seen_at,
+                hidden: stored.presence_hidden,
+            })
+            .collect())
+    }
+
+    async fn update_last_seen(&self, seen: &HashMap<String, i64>) -> anyhow::Result<()> {
+        let failure = self.last_seen_failure.lock().unwrap().take();
+        if let Some(meanwhile) = failure {
+            meanwhile();
+            anyhow::bail!("update last seen failed");
+        }
+        let mut users = self.users.lock().unwrap();
+        for (user_id, time) in seen {
+            if let Some(stored) = users.get_mut(user_id) {
+                stored.last_seen_at = stored.last_seen_at.max(Some(*time));
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_presence_hidden(&self, user_id: &str, hidden: bool) -> anyhow::Result<()> {
+        if let Some(stored) = self.users.lock().unwrap().get_mut(user_id) {
+            stored.presence_hidden = hidden;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,701 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Connection parameters known to libpq that are understood here.
+const KEYWORDS: &[&str] = &[
+    "host",
+    "hostaddr",
+    "port",
+    "dbname",
+    "user",
+    "password",
+    "passfile",
+    "options",
+    "application_name",
+    "connect_timeout",
+    "keepalives",
+    "keepalives_idle",
+    "target_session_attrs",
+    "channel_binding",
+    "sslmode",
+    "sslrootcert",
+    "sslcert",
+    "sslkey",
+];
+
+/// Environment variables libpq takes the parameters missing from the connection string from.
+const ENV_VARS: &[(&str, &str)] = &[
+    ("host", "PGHOST"),
+    ("hostaddr", "PGHOSTADDR"),
+    ("port", "PGPORT"),
+    ("dbname", "PGDATABASE"),
+    ("user", "PGUSER"),
+    ("password", "PGPASSWORD"),
+    ("passfile", "PGPASSFILE"),
+    ("options", "PGOPTIONS"),
+    ("application_name", "PGAPPNAME"),
+    ("connect_timeout", "PGCONNECT_TIMEOUT"),
+    ("target_session_attrs", "PGTARGETSESSIONATTRS"),
+    ("channel_binding", "PGCHANNELBINDING"),
+    ("sslmode", "PGSSLMODE"),
+    ("sslrootcert", "PGSSLROOTCERT"),
+    ("sslcert", "PGSSLCERT"),
+    ("sslkey", "PGSSLKEY"),
+];
+
+const DEFAULT_HOST: &str = "localhost";
+const DEFAULT_PORT: u16 = 5432;
+
+/// Settings of the connections to one postgres server, read as libpq reads them: from
+/// a key/value (`host=db user=postgres`) or a URL (`postgresql://postgres@db/app`)
+/// connection string, then from the `PG*` environment variables for the parameters
+/// the string lacks. Without a password, it is looked up in the password file.
+#[derive(Clone)]
+pub struct PgConfig {
+    config: tokio_postgres::Config,
+    ssl_mode: SslMode,
+    ssl_root_cert: Option<PathBuf>,
+    ssl_cert: Option<PathBuf>,
+    ssl_key: Option<PathBuf>,
+    passfile: Option<PathBuf>,
+}
+
+/// `sslmode` of libpq, tokio-postgres knows `disable`, `prefer` and `require` only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SslMode {
+    Disable,
+    Allow,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl PgConfig {
+    pub fn parse(conn_string: &str) -> anyhow::Result<Self> {
+        let conn_string = conn_string.trim();
+        let params =
+            if conn_string.starts_with("postgresql://") || conn_string.starts_with("postgres://") {
+                parse_url(conn_string)?
+            } else {
+                parse_key_values(conn_string)?
+            };
+        let mut merged: Vec<(String, String)> = Vec::with_capacity(params.len());
+        for (key, value) in params {
+            if !KEYWORDS.contains(&key.as_str()) {
+                anyhow::bail!("unknown postgres connection parameter '{}'", key);
+            }
+            // a repeated parameter overrides the earlier one, as in libpq
+            merged.retain(|(known, _)| *known != key);
+            merged.push((key, value));
+        }
+        for (key, var) in ENV_VARS {
+            if merged.iter().any(|(known, _)| known == key) {
+                continue;
+            }
+            if let Some(value) = std::env::var(var).ok().filter(|value| !value.is_empty()) {
+                merged.push((key.to_string(), value));
+            }
+        }
+        PgConfig::from_params(&merged)
+    }
+
+    fn from_params(params: &[(String, String)]) -> anyhow::Result<Self> {
+        use tokio_postgres::config::{ChannelBinding, TargetSessionAttrs};
+
+        let mut pg_config = PgConfig {
+            config: tokio_postgres::Config::new(),
+            ssl_mode: SslMode::Prefer,
+            ssl_root_cert: None,
+            ssl_cert: None,
+            ssl_key: None,
+            passfile: None,
+        };
+        let config = &mut pg_config.config;
+        let invalid = |key: &str, value: &str| {
+            anyhow::anyhow!(
+                "invalid value '{}' of postgres connection parameter '{}'",
+                value,
+                key
+            )
+        };
+        let seconds = |key: &str, value: &str| {
+            value
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|_| invalid(key, value))
+        };
+        for (key, value) in params {
+            let (key, value) = (key.as_str(), value.as_str());
+            match key {
+                "host" => {
+                    for host in value.split(',') {
+                        config.host(if host.is_empty() { DEFAULT_HOST } else { host });
+                    }
+                }
+                "hostaddr" => {
+                    for addr in value.split(',') {
+                        config.hostaddr(addr.parse::<IpAddr>().map_err(|_| invalid(key, value))?);
+                    }
+                }
+                "port" => {
+                    for port in value.split(',') {
+                        config.port(if port.is_empty() {
+                            DEFAULT_PORT
+                        } else {
+                            port.parse().map_err(|_| invalid(key, value))?
+                        });
+                    }
+                }
+                "dbname" => {
+                    config.dbname(value);
+                }
+                "user" => {
+                    config.user(value);
+                }
+                "password" => {
+                    config.password(value);
+                }
+                "passfile" => pg_config.passfile = Some(PathBuf::from(value)),
+                "options" => {
+                    config.options(value);
+                }
+                "application_name" => {
+                    config.application_name(value);
+                }
+                // zero waits forever, as in libpq
+                "connect_timeout" => match seconds(key, value)? {
+                    Duration::ZERO => {}
+                    timeout => {
+                        config.connect_timeout(timeout);
+                    }
+                },
+                "keepalives" => {
+                    config.keepalives(value != "0");
+                }
+                "keepalives_idle" => {
+                    config.keepalives_idle(seconds(key, value)?);
+                }
+                "target_session_attrs" => {
+                    config.target_session_attrs(match value {
+                        "any" => TargetSessionAttrs::Any,
+                        "read-write" => TargetSessionAttrs::ReadWrite,
+                        _ => return Err(invalid(key, value)),
+                    });
+                }
+                "channel_binding" => {
+                    config.channel_binding(match value {
+                        "disable" => ChannelBinding::Disable,
+                        "prefer" => ChannelBinding::Prefer,
+                        "require" => ChannelBinding::Require,
+                        _ => return Err(invalid(key, value)),
+                    });
+                }
+                "sslmode" => {
+                    pg_config.ssl_mode = match value {
+                        "disable" => SslMode::Disable,
+                        "allow" => SslMode::Allow,
+                        "prefer" => SslMode::Prefer,
+                        "require" => SslMode::Require,
+                        "verify-ca" => SslMode::VerifyCa,
+                        "verify-full" => SslMode::VerifyFull,
+                        _ => return Err(invalid(key, value)),
+                    }
+                }
+                "sslrootcert" => pg_config.ssl_root_cert = Some(PathBuf::from(value)),
+                "sslcert" => pg_config.ssl_cert = Some(PathBuf::from(value)),
+                "sslkey" => pg_config.ssl_key = Some(PathBuf::from(value)),
+                _ => unreachable!("parameter '{}' isn't known", key),
+            }
+        }
+
+        let config = &mut pg_config.config;
+        // libpq defaults to its unix socket, whose directory depends on the build
+        if config.get_hosts().is_empty() && config.get_hostaddrs().is_empty() {
+            config.host(DEFAULT_HOST);
+        }
+        // libpq defaults to the name of the OS user
+        if config.get_user().is_none() {
+            match ["USER", "LOGNAME"]
+                .iter()
+                .find_map(|var| std::env::var(var).ok().filter(|user| !user.is_empty()))
+            {
+                Some(user) => {
+                    config.user(user);
+                }
+                None => anyhow::bail!(
+                    "postgres user is specified neither by the connection string nor by PGUSER"
+                ),
+            }
+        }
+        config.ssl_mode(match pg_config.ssl_mode {
+            SslMode::Disable => tokio_postgres::config::SslMode::Disable,
+            SslMode::Allow | SslMode::Prefer => tokio_postgres::config::SslMode::Prefer,
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => {
+                tokio_postgres::config::SslMode::Require
+            }
+        });
+        Ok(pg_config)
+    }
+
+    pub fn dbname(&self) -> Option<&str> {
+        self.config.get_dbname()
+    }
+
+    pub fn with_dbname(&self, dbname: &str) -> Self {
+        let mut config = self.clone();
+        config.config.dbname(dbname);
+        config
+    }
+
+    pub fn ssl_mode(&self) -> SslMode {
+        self.ssl_mode
+    }
+
+    /// Certificates given by the connection string, they take precedence over the ones
+    /// given on the command line.
+    pub fn ssl_root_cert(&self) -> Option<&Path> {
+        self.ssl_root_cert.as_deref()
+    }
+
+    pub fn ssl_cert(&self) -> Option<&Path> {
+        self.ssl_cert.as_deref()
+    }
+
+    pub fn ssl_key(&self) -> Option<&Path> {
+        self.ssl_key.as_deref()
+    }
+
+    /// Hosts and ports of the server, for the logs.
+    pub fn server_name(&self) -> String {
+        let hosts: Vec<String> = if self.config.get_hosts().is_empty() {
+            self.config
+                .get_hostaddrs()
+                .iter()
+                .map(IpAddr::to_string)
+                .collect()
+        } else {
+            self.config
+                .get_hosts()
+                .iter()
+                .map(|host| match host {
+                    tokio_postgres::config::Host::Tcp(host) => host.clone(),
+                    #[cfg(unix)]
+                    tokio_postgres::config::Host::Unix(path) => path.display().to_string(),
+                })
+                .collect()
+        };
+        let ports = self.config.get_ports();
+        hosts
+            .iter()
+            .enumerate()
+            .map(|(i, host)| {
+                let port = match ports {
+                    [] => DEFAULT_PORT,
+                    [port] => *port,
+                    ports => ports.get(i).copied().unwrap_or(DEFAULT_PORT),
+                };
+                format!("{host}:{port}")
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Config to connect with, the password coming from the password file if needed.
+    pub fn tokio_config(&self) -> tokio_postgres::Config {
+        let mut config = self.config.clone();
+        if config.get_password().is_none() {
+            if let Some(password) = self.passfile_password() {
+                config.password(password);
+            }
+        }
+        config
+    }
+
+    /// Password of the first line of the password file matching the first server, the
+    /// database and the user. The file is ignored when others can read it, as libpq does.
+    fn passfile_password(&self) -> Option<String> {
+        let path = match &self.passfile {
+            Some(path) => path.clone(),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".pgpass"),
+        };
+        let metadata = std::fs::metadata(&path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if metadata.permissions().mode() & 0o077 != 0 {
+                tracing::warn!(
+                    "password file {} is ignored, it must be readable by its owner only",
+                    path.display()
+                );
+                return None;
+            }
+        }
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                tracing::warn!("failed to read password file {}: {}", path.display(), err);
+                return None;
+            }
+        };
+
+        let host = match self.config.get_hosts().first() {
+            Some(tokio_postgres::config::Host::Tcp(host)) => host.clone(),
+            // like libpq, a socket is matched by `localhost`
+            #[cfg(unix)]
+            Some(tokio_postgres::config::Host::Unix(_)) => DEFAULT_HOST.to_owned(),
+            None => self
+                .config
+                .get_hostaddrs()
+                .first()
+                .map_or_else(|| DEFAULT_HOST.to_owned(), IpAddr::to_string),
+        };
+        let port = self
+            .config
+            .get_ports()
+            .first()
+            .copied()
+            .unwrap_or(DEFAULT_PORT)
+            .to_string();
+        let user = self.config.get_user().unwrap_or_default();
+        let dbname = self.config.get_dbname().unwrap_or(user);
+        let wanted = [host.as_str(), port.as_str(), dbname, user];
+
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .find_map(|line| {
+                let fields = split_passfile_line(line);
+                let matches = fields.len() == 5
+                    && fields
+                        .iter()
+                        .zip(wanted)
+                        .all(|(field, wanted)| field == "*" || field == wanted);
+                matches.then(|| fields[4].clone())
+            })
+    }
+}
+
+/// Fields of a `hostname:port:database:username:password` line, where `\:` and `\\` stand
+/// for the characters themselves. The password is the rest of the line.
+fn split_passfile_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => fields.last_mut().unwrap().extend(chars.next()),
+            ':' if fields.len() < 5 => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// `key = value` pairs separated by whitespace, a value may be single-quoted and
+/// a backslash escapes the next character.
+fn parse_key_values(conn_string: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut params = Vec::new();
+    let mut chars = conn_string.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(params);
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+            key.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next() != Some('=') {
+            anyhow::bail!(
+                "missing \"=\" after '{}' in postgres connection string",
+                key
+            );
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'\'').is_some() {
+            loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(c) => value.push(c),
+                    None => anyhow::bail!(
+                        "unterminated quoted value of '{}' in postgres connection string",
+                        key
+                    ),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        }
+        params.push((key, value));
+    }
+}
+
+/// `postgresql://[user[:password]@][host][:port][,...][/dbname][?param=value&...]`,
+/// a host may be an `[IPv6]` address or a percent-encoded socket directory.
+fn parse_url(conn_string: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let rest = conn_string
+        .strip_prefix("postgresql://")
+        .or_else(|| conn_string.strip_prefix("postgres://"))
+        .unwrap();
+    let (rest, query) = match rest.split_once('?') {
+        Some((rest, query)) => (rest, Some(query)),
+        None => (rest, None),
+    };
+    let (authority, dbname) = match rest.split_once('/') {
+        Some((authority, dbname)) => (authority, Some(dbname)),
+        None => (rest, None),
+    };
+    let (userinfo, hosts) = match authority.rsplit_once('@') {
+        Some((userinfo, hosts)) => (Some(userinfo), hosts),
+        None => (None, authority),
+    };
+
+    let mut params = Vec::new();
+    if let Some(userinfo) = userinfo {
+        let (user, password) = match userinfo.split_once(':') {
+            Some((user, password)) => (user, Some(password)),
+            None => (userinfo, None),
+        };
+        if !user.is_empty() {
+            params.push(("user".to_owned(), percent_decode(user)?));
+        }
+        if let Some(password) = password {
+            params.push(("password".to_owned(), percent_decode(password)?));
+        }
+    }
+    if !hosts.is_empty() {
+        let mut host_list = Vec::new();
+        let mut port_list = Vec::new();
+        for host_port in hosts.split(',') {
+            let (host, port) = if let Some(ipv6) = host_port.strip_prefix('[') {
+                let (host, rest) = ipv6.split_once(']').ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "missing \"]\" of IPv6 host in postgres connection URL '{}'",
+                        host_port
+                    )
+                })?;
+                (host.to_owned(), rest.strip_prefix(':').unwrap_or(rest))
+            } else {
+                match host_port.rsplit_once(':') {
+                    Some((host, port)) => (percent_decode(host)?, port),
+                    None => (percent_decode(host_port)?, ""),
+                }
+            };
+            host_list.push(host);
+            port_list.push(port.to_owned());
+        }
+        if host_list.iter().any(|host| !host.is_empty()) {
+            params.push(("host".to_owned(), host_list.join(",")));
+        }
+        if port_list.iter().any(|port| !port.is_empty()) {
+            params.push(("port".to_owned(), port_list.join(",")));
+        }
+    }
+    if let Some(dbname) = dbname.filter(|dbname| !dbname.is_empty()) {
+        params.push(("dbname".to_owned(), percent_decode(dbname)?));
+    }
+    for param in query.into_iter().flat_map(|query| query.split('&')) {
+        if param.is_empty() {
+            continue;
+        }
+        let (key, value) = param.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!(
+                "missing \"=\" in parameter '{}' of postgres connection URL",
+                param
+            )
+        })?;
+        params.push((percent_decode(key)?, percent_decode(value)?));
+    }
+    Ok(params)
+}
+
+fn percent_decode(text: &str) -> anyhow::Result<String> {
+    let invalid = || anyhow::anyhow!("invalid percent-encoding in '{}'", text);
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn urls_are_split_into_params() {
+        let cases: &[(&str, &[(&str, &str)])] = &[
+            ("postgresql://", &[]),
+            ("postgres://db", &[("host", "db")]),
+            (
+                "postgresql://app:p%40ss@db:5433/social",
+                &[
+                    ("user", "app"),
+                    ("password", "p@ss"),
+                    ("host", "db"),
+                    ("port", "5433"),
+                    ("dbname", "social"),
+                ],
+            ),
+            ("postgresql://@db/", &[("host", "db")]),
+            (
+                "postgresql://app@/social?",
+                &[("user", "app"), ("dbname", "social")],
+            ),
+            (
+                "postgresql://a:5433,b/social",
+                &[("host", "a,b"), ("port", "5433,"), ("dbname", "social")],
+            ),
+            (
+                "postgresql://[::1]:5433,[fe80::2]/social",
+                &[
+                    ("host", "::1,fe80::2"),
+                    ("port", "5433,"),
+                    ("dbname", "social"),
+                ],
+            ),
+            (
+                "postgresql://%2Fvar%2Frun%2Fpostgresql/social",
+                &[("host", "/var/run/postgresql"), ("dbname", "social")],
+            ),
+            (
+                "postgresql://db?sslmode=require&&application_name=social%20network",
+                &[
+                    ("host", "db"),
+                    ("sslmode", "require"),
+                    ("application_name", "social network"),
+                ],
+            ),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(parse_url(url).unwrap(), params(expected), "{url}");
+        }
+    }
+
+    #[test]
+    fn malformed_urls_are_rejected() {
+        for url in [
+            "postgresql://[::1:5433/social",
+            "postgresql://db?sslmode",
+            "postgresql://db/%zz",
+            "postgresql://app:%4@db",
+        ] {
+            assert!(parse_url(url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn key_values_are_split_into_params() {
+        let cases: &[(&str, &[(&str, &str)])] = &[
+            ("", &[]),
+            ("   ", &[]),
+            ("host=db port=5433", &[("host", "db"), ("port", "5433")]),
+            (
+                "  host = db\tuser=postgres  ",
+                &[("host", "db"), ("user", "postgres")],
+            ),
+            ("dbname=", &[("dbname", "")]),
+            (r"password='a b\'c'", &[("password", "a b'c")]),
+            (r"password=''", &[("password", "")]),
+            (
+                r"options=-c\ search_path=x",
+                &[("options", "-c search_path=x")],
+            ),
+            ("host=a host=b", &[("host", "a"), ("host", "b")]),
+        ];
+        for (conn_string, expected) in cases {
+            assert_eq!(
+                parse_key_values(conn_string).unwrap(),
+                params(expected),
+                "{conn_string}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_key_values_are_rejected() {
+        for conn_string in ["host", "host db", "password='abc", r"password='abc\'"] {
+            assert!(parse_key_values(conn_string).is_err(), "{conn_string}");
+        }
+    }
+
+    #[test]
+    fn passfile_lines_are_split_into_fields() {
+        let cases: &[(&str, &[&str])] = &[
+            (
+                "db:5432:social:app:secret",
+                &["db", "5432", "social", "app", "secret"],
+            ),
+            ("*:*:*:*:", &["*", "*", "*", "*", ""]),
+            (
+                r"db\:1:*:social:app:pa:ss",
+                &["db:1", "*", "social", "app", "pa:ss"],
+            ),
+            (
+                r"db:5432:social:app:back\\slash",
+                &["db", "5432", "social", "app", r"back\slash"],
+            ),
+            (
+                r"db:5432:social:app:secret\",
+                &["db", "5432", "social", "app", "secret"],
+            ),
+            ("db:5432:social", &["db", "5432", "social"]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(split_passfile_line(line), *expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn percent_encoding_is_decoded() {
+        let cases = [
+            ("", ""),
+            ("plain", "plain"),
+            ("a%20b", "a b"),
+            ("%2Fvar%2frun", "/var/run"),
+            ("%D0%B1%D0%B4", "бд"),
+            ("100%25", "100%"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(percent_decode(text).unwrap(), expected, "{text}");
+        }
+        for text in ["%", "%2", "a%zz", "%+1", "%FF"] {
+            assert!(percent_decode(text).is_err(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use crate::{db, user_repository::UserRepository};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A user seen within that time is online. Open WebSocket connections answer the
+/// heartbeat more often, so their users stay online.
+const ONLINE_WINDOW: Duration = Duration::from_secs(120);
+
+/// Last-seen times of the users. Requests only update the memory, the times are written
+/// to the user repository in batches every flush interval, so a request costs no write.
+pub struct Presence {
+    users: Arc<dyn UserRepository>,
+    flush_interval: Duration,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    /// Milliseconds since the epoch of the users seen recently.
+    at: HashMap<String, i64>,
+    /// Times not written to the database yet.
+    unflushed: HashMap<String, i64>,
+}
+
+/// Presence of a user as shown to others, empty if the user hides it.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct Status {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub online: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<String>,
+}
+
+impl Presence {
+    pub fn new(users: Arc<dyn UserRepository>, flush_interval: Duration) -> Self {
+        Self {
+            users,
+            flush_interval,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    pub fn spawn_workers(self: &Arc<Self>) {
+        let presence = self.clone();
+        tokio::spawn(async move { presence.run_flush().await });
+    }
+
+    /// Records that the user has just made a request or answered the heartbeat.
+    pub fn touch(&self, user_id: &str) {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut seen = self.seen.lock().unwrap();
+        let Seen { at, unflushed } = &mut *seen;
+        for times in [at, unflushed] {
+            match times.get_mut(user_id) {
+                Some(time) => *time = now,
+                None => {
+                    times.insert(user_id.to_owned(), now);
+                }
+            }
+        }
+    }
+
+    /// Statuses of the users, by their ids, combining the times kept in memory
+    /// with the ones flushed already.
+    pub async fn statuses(
+        &self,
+        user_ids: &[String],
+        min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<HashMap<String, Status>> {
+        let stored = self.users.presence(user_ids, min_lsn).await?;
+        let now = chrono::Utc::now().timestamp_millis();
+        let seen = self.seen.lock().unwrap();
+        Ok(stored
+            .into_iter()
+            .map(|presence| {
+                if presence.hidden {
+                    return (presence.user_id, Status::default());
+                }
+                let last_seen = seen
+                    .at
+                    .get(&presence.user_id)
+                    .copied()
+                    .max(presence.last_seen_at);
+                let status = Status {
+                    online: Some(
+                        last_seen.is_some_and(|at| now - at < ONLINE_WINDOW.as_millis() as i64),
+                    ),
+                    last_seen: last_seen
+                        .and_then(chrono::DateTime::from_timestamp_millis)
+                        .map(|at| at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+                };
+                (presence.user_id, status)
+            })
+            .collect())
+    }
+
+    async fn run_flush(&self) {
+        let mut interval = tokio::time::interval(self.flush_interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.flush().await {
+                tracing::error!("presence flush failed: {err:?}");
+            }
+        }
+    }
+
+    /// Writes the unflushed times to the repository. The times that failed to be written
+    /// are kept for the next flush, unless the users have been seen again since.
+    async fn flush(&self) -> anyhow::Result<()> {
+        let unflushed = std::mem::take(&mut self.seen.lock().unwrap().unflushed);
+        if unflushed.is_empty() {
+            return Ok(());
+        }
+        let result = self.users.update_last_seen(&unflushed).await;
+
+        let mut seen = self.seen.lock().unwrap();
+        if result.is_err() {
+            for (user_id, time) in unflushed {
+                seen.unflushed.entry(user_id).or_insert(time);
+            }
+        }
+        // the times of the users gone offline are in the database now
+        let expired = chrono::Utc::now().timestamp_millis() - ONLINE_WINDOW.as_millis() as i64;
+        let Seen { at, unflushed } = &mut *seen;
+        at.retain(|user_id, time| *time > expired || unflushed.contains_key(user_id));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db_user, memory_users::MemoryUserRepository};
+
+    async fn users(ids: &[&str]) -> Arc<MemoryUserRepository> {
+        let users = Arc::new(MemoryUserRepository::default());
+        for id in ids {
+            users
+                .insert(db_user::User {
+                    id: id.to_string(),
+                    first_name: id.to_string(),
+                    second_name: id.to_string(),
+                    biography: String::new(),
+                    birthdate: "2000-01-01".to_owned(),
+                    city: String::new(),
+                    password_hash: String::new(),
+                    token: String::new(),
+                })
+                .await
+                .unwrap();
+        }
+        users
+    }
+
+    /// Records that the user was seen at the given time, as `touch` does with the current one.
+    fn seen_at(presence: &Presence, user_id: &str, time: i64) {
+        let mut seen = presence.seen.lock().unwrap();
+        seen.at.insert(user_id.to_owned(), time);
+        seen.unflushed.insert(user_id.to_owned(), time);
+    }
+
+    async fn status(presence: &Presence, user_id: &str) -> Status {
+        presence
+            .statuses(&[user_id.to_owned()], None)
+            .await
+            .unwrap()
+            .remove(user_id)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_flush_keeps_the_times_but_not_over_newer_ones() {
+        let users = users(&["a", "b"]).await;
+        let presence = Arc::new(Presence::new(users.clone(), Duration::from_secs(10)));
+        seen_at(&presence, "a", 1_000);
+        seen_at(&presence, "b", 2_000);
+        let seen_again = presence.clone();
+        users.fail_next_last_seen_update(move || seen_at(&seen_again, "a", 3_000));
+        assert!(presence.flush().await.is_err());
+        {
+            let seen = presence.seen.lock().unwrap();
+            assert_eq!(seen.unflushed["a"], 3_000);
+            assert_eq!(seen.unflushed["b"], 2_000);
+            // long expired, but not in the database yet
+            assert_eq!(seen.at["b"], 2_000);
+        }
+
+        presence.flush().await.unwrap();
+        {
+            let seen = presence.seen.lock().unwrap();
+            assert!(seen.unflushed.is_empty());
+            assert!(seen.at.is_empty());
+        }
+        let stored = users
+            .presence(&["a".to_owned(), "b".to_owned()], None)
+            .await
+            .unwrap();
+        let mut times: Vec<(String, Option<i64>)> = stored
+            .into_iter()
+            .map(|presence| (presence.user_id, presence.last_seen_at))
+            .collect();
+        times.sort();
+        assert_eq!(
+            times,
+            [("a".to_owned(), Some(3_000)), ("b".to_owned(), Some(2_000))]
+        );
+        let b = status(&presence, "b").await;
+        assert_eq!(b.online, Some(false));
+        assert_eq!(b.last_seen.as_deref(), Some("1970-01-01T00:00:02Z"));
+    }
+
+    #[tokio::test]
+    async fn hidden_users_get_an_empty_status() {
+        let users = users(&["shy", "open"]).await;
+        let presence = Presence::new(users.clone(), Duration::from_secs(10));
+        users.set_presence_hidden("shy", true).await.unwrap();
+        presence.touch("shy");
+        presence.touch("open");
+        presence.flush().await.unwrap();
+
+        let shy = status(&presence, "shy").await;
+        assert_eq!((shy.online, shy.last_seen.as_deref()), (None, None));
+        let open = status(&presence, "open").await;
+        assert_eq!(open.online, Some(true));
+        assert!(open.last_seen.is_some());
+        assert_eq!(serde_json::to_value(&shy).unwrap(), serde_json::json!({}));
+    }
+
+    #[tokio::test]
+    async fn unknown_users_get_no_status() {
+        let presence = Presence::new(users(&[]).await, Duration::from_secs(10));
+        presence.touch("ghost");
+        assert!(presence
+            .statuses(&["ghost".to_owned()], None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::presence;
+use axum::extract::ws::{Message, WebSocket};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// Messages a connection may have queued before it is considered too slow and dropped.
+const CONNECTION_BUFFER: usize = 64;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// A connection that hasn't answered for that long is considered dead.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Open WebSocket connections of the users, several per user. Users with an open
+/// connection are seen whenever their client answers the heartbeat or sends anything.
+pub struct Hub {
+    name: &'static str,
+    presence: Arc<presence::Presence>,
+    connections: Mutex<HashMap<String, Vec<Connection>>>,
+    next_id: AtomicU64,
+}
+
+struct Connection {
+    id: u64,
+    sender: mpsc::Sender<Arc<String>>,
+}
+
+impl Hub {
+    /// `name` tells the connections of different hubs apart in the logs.
+    pub fn new(name: &'static str, presence: Arc<presence::Presence>) -> Self {
+        Self {
+            name,
+            presence,
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Users having at least one open connection.
+    pub fn connected_users(&self) -> Vec<String> {
+        self.connections.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Queues the message to every connection of the users, dropping the connections
+    /// that can't keep up.
+    pub fn send<'a>(&self, users: impl IntoIterator<Item = &'a String>, message: Arc<String>) {
+        let mut connections = self.connections.lock().unwrap();
+        for user_id in users {
+            if let Some(user_connections) = connections.get_mut(user_id) {
+                user_connections.retain(|connection| {
+                    match connection.sender.try_send(message.clone()) {
+                        Ok(()) => true,
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            tracing::warn!(
+                                "{} connection {} of user {} is too slow, closing it",
+                                self.name,
+                                connection.id,
+                                user_id
+                            );
+                            false
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => false,
+                    }
+                });
+                if user_connections.is_empty() {
+                    connections.remove(user_id);
+                }
+            }
+        }
+    }
+
+    /// Serves the connection until the client leaves, stops answering or falls behind,
+    /// passing the text messages of the client to `on_text` one at a time.
+    pub async fn serve<F: std::future::Future<Output = ()>>(
+        &self,
+        user_id: String,
+        mut socket: WebSocket,
+        mut on_text: impl FnMut(String) -> F,
+    ) {
+        let (id, mut receiver) = self.register(&user_id);
+        self.presence.touch(&user_id);
+        tracing::info!("{} connection {} of user {} opened", self.name, id, user_id);
+
+        let mut heartbeat = tokio::time::interval_at(
+            tokio::time::Instant::now() + HEARTBEAT_INTERVAL,
+            HEARTBEAT_INTERVAL,
+        );
+        let mut last_seen = tokio::time::Instant::now();
+        loop {
+            tokio::select! {
+                message = receiver.recv() => {
+                    // the sender is dropped when the connection can't keep up
+                    let Some(message) = message else { break };
+                    if let Err(err) = socket.send(Message::Text(message.as_ref().clone())).await {
+                        tracing::debug!("{} connection {id} send failed: {err}", self.name);
+                        break;
+                    }
+                }
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(message)) => {
+                        last_seen = tokio::time::Instant::now();
+                        self.presence.touch(&user_id);
+                        if let Message::Text(text) = message {
+                            on_text(text).await;
+                        }
+                    }
+                    Some(Err(err)) => {
+                        tracing::debug!("{} connection {id} receive failed: {err}", self.name);
+                        break;
+                    }
+                },
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                        tracing::info!("{} connection {} of user {} timed out", self.name, id, user_id);
+                        break;
+                    }
+                    if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.unregister(&user_id, id);
+        tracing::info!("{} connection {} of user {} closed", self.name, id, user_id);
+    }
+
+    fn register(&self, user_id: &str) -> (u64, mpsc::Receiver<Arc<String>>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel(CONNECTION_BUFFER);
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(user_id.to_owned())
+            .or_default()
+            .push(Connection { id, sender });
+        (id, receiver)
+    }
+
+    fn unregister(&self, user_id: &str, id: u64) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(user_connections) = connections.get_mut(user_id) {
+            user_connections.retain(|connection| connection.id != id);
+            if user_connections.is_empty() {
+                connections.remove(user_id);
+            }
+        }
+    }
+}
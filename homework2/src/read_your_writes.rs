@@ -0,0 +1,152 @@
+use crate::db;
+use axum::{
+    extract::{self, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::{convert::Infallible, sync::Arc};
+
+/// Carries the WAL position of the client's last write, in both directions.
+const LSN_HEADER: &str = "x-min-lsn";
+const LSN_COOKIE: &str = "min_lsn";
+/// Replicas are expected to have caught up with a write by then.
+const LSN_COOKIE_MAX_AGE_SECS: u64 = 300;
+
+/// WAL position the replicas serving the request must have replayed, taken from
+/// the `X-Min-Lsn` header or the `min_lsn` cookie set after the client's last write.
+#[derive(Debug, Clone, Copy)]
+pub struct MinLsn(pub Option<db::Lsn>);
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for MinLsn
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let lsn = header_lsn(&parts.headers).or_else(|| cookie_lsn(&parts.headers));
+        Ok(MinLsn(lsn.and_then(|lsn| match lsn.parse() {
+            Ok(lsn) => Some(lsn),
+            Err(err) => {
+                tracing::debug!("ignoring {err}");
+                None
+            }
+        })))
+    }
+}
+
+/// Set on the successful responses of the handlers writing to the main database.
+#[derive(Debug, Clone, Copy)]
+pub struct WroteMain;
+
+/// Route layer of the handlers writing to the main database, so that the WAL position is
+/// only asked for when there is a write to wait for.
+pub async fn writes_main(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if response.status().is_success() {
+        response.extensions_mut().insert(WroteMain);
+    }
+    response
+}
+
+/// Hands the WAL position of the main database out after every write marked by
+/// `writes_main`, so that the client's next reads wait for the replicas to catch up with it.
+/// Does nothing when reading from the main database only.
+pub async fn track_writes(
+    extract::State(db): extract::State<Arc<db::DB>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if !db.has_replicas() || response.extensions().get::<WroteMain>().is_none() {
+        return response;
+    }
+
+    match db.current_lsn().await {
+        Err(err) => tracing::warn!("reads of the client may miss its write: {err:?}"),
+        Ok(lsn) => {
+            let headers = response.headers_mut();
+            headers.insert(LSN_HEADER, HeaderValue::from_str(&lsn.to_string()).unwrap());
+            let cookie = format!(
+                "{LSN_COOKIE}={lsn}; Path=/; Max-Age={LSN_COOKIE_MAX_AGE_SECS}; HttpOnly; SameSite=Lax"
+            );
+            headers.append(header::SET_COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        }
+    }
+    response
+}
+
+fn header_lsn(headers: &HeaderMap) -> Option<&str> {
+    headers.get(LSN_HEADER)?.to_str().ok().map(str::trim)
+}
+
+fn cookie_lsn(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(LSN_COOKIE)?.strip_prefix('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    async fn min_lsn(pairs: &[(&'static str, &'static str)]) -> Option<String> {
+        let mut request = Request::new(axum::body::Body::empty());
+        *request.headers_mut() = headers(pairs);
+        let (mut parts, _) = request.into_parts();
+        let MinLsn(lsn) = MinLsn::from_request_parts(&mut parts, &()).await.unwrap();
+        lsn.map(|lsn| lsn.to_string())
+    }
+
+    #[test]
+    fn cookie_is_found_among_others() {
+        let found = headers(&[("cookie", "a=1; min_lsn=16/B374D848; b=2")]);
+        assert_eq!(cookie_lsn(&found), Some("16/B374D848"));
+        let several = headers(&[("cookie", "a=1"), ("cookie", "b=2;min_lsn=0/1")]);
+        assert_eq!(cookie_lsn(&several), Some("0/1"));
+        assert_eq!(cookie_lsn(&headers(&[("cookie", "a=1; b=2")])), None);
+        assert_eq!(cookie_lsn(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn cookie_named_alike_is_not_taken() {
+        let alike = headers(&[("cookie", "min_lsn_old=0/5; xmin_lsn=0/6")]);
+        assert_eq!(cookie_lsn(&alike), None);
+        let both = headers(&[("cookie", "min_lsn_old=0/5; min_lsn=0/7")]);
+        assert_eq!(cookie_lsn(&both), Some("0/7"));
+    }
+
+    #[test]
+    fn header_is_trimmed() {
+        assert_eq!(header_lsn(&headers(&[("x-min-lsn", " 0/7 ")])), Some("0/7"));
+        assert_eq!(header_lsn(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn header_wins_over_the_cookie() {
+        let lsn = min_lsn(&[("x-min-lsn", "0/8"), ("cookie", "min_lsn=0/7")]).await;
+        assert_eq!(lsn.as_deref(), Some("0/8"));
+        let lsn = min_lsn(&[("cookie", "min_lsn=0/7")]).await;
+        assert_eq!(lsn.as_deref(), Some("0/7"));
+        assert_eq!(min_lsn(&[]).await, None);
+    }
+
+    #[tokio::test]
+    async fn malformed_lsn_is_ignored() {
+        assert_eq!(min_lsn(&[("x-min-lsn", "latest")]).await, None);
+        assert_eq!(min_lsn(&[("cookie", "min_lsn=0/xyz")]).await, None);
+    }
+}
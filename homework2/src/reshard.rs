@@ -0,0 +1,295 @@
+use crate::{db, db_message, db_reshard, shard};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio_postgres::GenericClient;
+
+/// Time given to dual writes in flight before a mismatching conversation is compared again.
+const RECHECK_DELAY: Duration = Duration::from_secs(1);
+
+/// Copies the messages of the conversations changing their shard to the target shards,
+/// saving a checkpoint after every batch so an interrupted backfill resumes where it stopped.
+/// Messages already copied by dual writes are skipped.
+pub async fn backfill(
+    shards: &shard::Shards,
+    batch_size: usize,
+    restart: bool,
+) -> anyhow::Result<()> {
+    let Some((fingerprint, sources)) = shards.sources() else {
+        anyhow::bail!("no target shards specified, nothing to backfill");
+    };
+    let main_db = shards.main_db().get().await?;
+    for (source_name, source_db) in sources {
+        copy_read_markers(shards, source_name, source_db, batch_size).await?;
+        let mut checkpoint =
+            match db_reshard::Checkpoint::get(fingerprint, source_name, main_db.client()).await? {
+                Some(checkpoint) if !restart => checkpoint,
+                _ => db_reshard::Checkpoint::default(),
+            };
+        if checkpoint.finished {
+            tracing::info!(
+                "backfill of shard '{}' already finished, {} messages copied",
+                source_name,
+                checkpoint.copied
+            );
+            continue;
+        }
+        tracing::info!(
+            "backfill of shard '{}' started after message {}/{}, {} messages copied before",
+            source_name,
+            checkpoint.conversation_id,
+            checkpoint.message_id,
+            checkpoint.copied
+        );
+
+        let started = std::time::Instant::now();
+        let mut scanned = 0;
+        loop {
+            let batch = {
+                let pg_pool = source_db.get().await?;
+                db_message::Message::batch_after(
+                    &checkpoint.conversation_id,
+                    checkpoint.message_id,
+                    batch_size,
+                    pg_pool.client(),
+                )
+                .await?
+            };
+            let mut moving: HashMap<&str, (&Arc<db::DB>, Vec<&db_message::Message>)> =
+                HashMap::new();
+            for message in &batch {
+                let (target_name, target_db) = shards
+                    .target(&message.conversation_id())
+                    .expect("target shards are specified");
+                if target_name != source_name {
+                    moving
+                        .entry(target_name)
+                        .or_insert_with(|| (target_db, Vec::new()))
+                        .1
+                        .push(message);
+                }
+            }
+            for (target_name, (target_db, messages)) in moving {
+                let pg_pool = target_db.get().await?;
+                for message in messages {
+                    if message
+                        .copy_to_db(pg_pool.client())
+                        .await
+                        .map_err(|e| anyhow::anyhow!("message shard '{}': {}", target_name, e))?
+                    {
+                        checkpoint.copied += 1;
+                    }
+                }
+            }
+
+            scanned += batch.len();
+            if let Some(last) = batch.last() {
+                checkpoint.conversation_id = last.conversation_id();
+                checkpoint.message_id = last.id;
+            }
+            checkpoint.finished = batch.len() < batch_size;
+            checkpoint
+                .save(fingerprint, source_name, main_db.client())
+                .await?;
+            tracing::info!(
+                "backfill of shard '{}': {} messages scanned, {} copied, at {}/{}",
+                source_name,
+                scanned,
+                checkpoint.copied,
+                checkpoint.conversation_id,
+                checkpoint.message_id
+            );
+            if checkpoint.finished {
+                break;
+            }
+        }
+        tracing::info!(
+            "backfill of shard '{}' finished in {:?}, {} messages copied",
+            source_name,
+            started.elapsed(),
+            checkpoint.copied
+        );
+    }
+    // what has been verified before may have changed
+    db_reshard::Reshard::set_verified(fingerprint, false, main_db.client()).await?;
+    Ok(())
+}
+
+/// Read markers only move forward, so they are copied all over again on every run
+/// instead of being checkpointed.
+async fn copy_read_markers(
+    shards: &shard::Shards,
+    source_name: &str,
+    source_db: &Arc<db::DB>,
+    batch_size: usize,
+) -> anyhow::Result<()> {
+    let mut after = (String::new(), String::new());
+    let mut copied = 0;
+    loop {
+        let markers = {
+            let pg_pool = source_db.get().await?;
+            db_message::ReadMarker::batch_after(&after.0, &after.1, batch_size, pg_pool.client())
+                .await?
+        };
+        for marker in &markers {
+            let (target_name, target_db) = shards
+                .target(&marker.conversation_id)
+                .expect("target shards are specified");
+            if target_name != source_name {
+                let pg_pool = target_db.get().await?;
+                marker
+                    .save(pg_pool.client())
+                    .await
+                    .map_err(|e| anyhow::anyhow!("message shard '{}': {}", target_name, e))?;
+                copied += 1;
+            }
+        }
+        match markers.last() {
+            Some(last) if markers.len() == batch_size => {
+                after = (last.conversation_id.clone(), last.user_id.clone())
+            }
+            _ => break,
+        }
+    }
+    tracing::info!(
+        "backfill of shard '{}': {} read markers copied",
+        source_name,
+        copied
+    );
+    Ok(())
+}
+
+/// Compares message counts and checksums of every conversation changing its shard between
+/// its current and target location. Conversations that differ are compared once more
+/// after a while, as dual writes may still be in flight. The cut-over is only allowed
+/// once a pass finds no difference.
+pub async fn verify(shards: &shard::Shards, batch_size: usize) -> anyhow::Result<()> {
+    let Some((fingerprint, sources)) = shards.sources() else {
+        anyhow::bail!("no target shards specified, nothing to verify");
+    };
+    let started = std::time::Instant::now();
+    let mut checked = 0;
+    let mut mismatched = Vec::new();
+    for (source_name, source_db) in sources {
+        let mut after = String::new();
+        let mut shard_checked = 0;
+        loop {
+            let checksums = {
+                let pg_pool = source_db.get().await?;
+                db_message::Message::checksums_after(&after, batch_size, pg_pool.client()).await?
+            };
+            let conversations: Vec<String> = checksums
+                .iter()
+                .map(|(conversation_id, _)| conversation_id.clone())
+                .collect();
+            for conversation_id in compare(
+                shards,
+                source_name,
+                source_db,
+                &conversations,
+                Some(&checksums),
+            )
+            .await?
+            {
+                mismatched.push((source_name, source_db, conversation_id));
+            }
+            checked += checksums.len();
+            shard_checked += checksums.len();
+            tracing::info!(
+                "verify of shard '{}': {} conversations checked, {} differ so far",
+                source_name,
+                shard_checked,
+                mismatched.len()
+            );
+            match checksums.last() {
+                Some((last, _)) if checksums.len() == batch_size => after = last.clone(),
+                _ => break,
+            }
+        }
+    }
+
+    if !mismatched.is_empty() {
+        tracing::info!("rechecking {} conversations", mismatched.len());
+        tokio::time::sleep(RECHECK_DELAY).await;
+    }
+    let mut differ = 0;
+    for (source_name, source_db, conversation_id) in mismatched {
+        let conversations = [conversation_id];
+        for conversation_id in compare(shards, source_name, source_db, &conversations, None).await?
+        {
+            tracing::warn!(
+                "conversation {} differs between shard '{}' and its target",
+                conversation_id,
+                source_name
+            );
+            differ += 1;
+        }
+    }
+
+    let main_db = shards.main_db().get().await?;
+    db_reshard::Reshard::set_verified(fingerprint, differ == 0, main_db.client()).await?;
+    if differ > 0 {
+        anyhow::bail!(
+            "{} of {} conversations differ, run reshard-backfill with --restart and verify again",
+            differ,
+            checked
+        );
+    }
+    tracing::info!(
+        "verify finished in {:?}, {} conversations are equal on both sides, cut over is allowed",
+        started.elapsed(),
+        checked
+    );
+    Ok(())
+}
+
+/// Returns the conversations of the source shard whose target copy differs.
+async fn compare(
+    shards: &shard::Shards,
+    source_name: &str,
+    source_db: &Arc<db::DB>,
+    conversations: &[String],
+    source_checksums: Option<&[(String, db_message::Checksum)]>,
+) -> anyhow::Result<Vec<String>> {
+    let source_checksums: HashMap<String, db_message::Checksum> = match source_checksums {
+        Some(checksums) => checksums.iter().cloned().collect(),
+        None => {
+            let pg_pool = source_db.get().await?;
+            db_message::Message::checksums(conversations, pg_pool.client()).await?
+        }
+    };
+
+    let mut moving: HashMap<&str, (&Arc<db::DB>, Vec<String>)> = HashMap::new();
+    for conversation_id in conversations {
+        let (target_name, target_db) = shards
+            .target(conversation_id)
+            .expect("target shards are specified");
+        if target_name != source_name {
+            moving
+                .entry(target_name)
+                .or_insert_with(|| (target_db, Vec::new()))
+                .1
+                .push(conversation_id.clone());
+        }
+    }
+
+    let mut differ = Vec::new();
+    for (target_name, (target_db, conversations)) in moving {
+        let pg_pool = target_db.get().await?;
+        let target_checksums = db_message::Message::checksums(&conversations, pg_pool.client())
+            .await
+            .map_err(|e| anyhow::anyhow!("message shard '{}': {}", target_name, e))?;
+        for conversation_id in conversations {
+            if source_checksums.get(&conversation_id) != target_checksums.get(&conversation_id) {
+                tracing::debug!(
+                    "conversation {} differs: {:?} on '{}', {:?} on '{}'",
+                    conversation_id,
+                    source_checksums.get(&conversation_id),
+                    source_name,
+                    target_checksums.get(&conversation_id),
+                    target_name
+                );
+                differ.push(conversation_id);
+            }
+        }
+    }
+    Ok(differ)
+}
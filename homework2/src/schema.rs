@@ -19,6 +19,42 @@ pub static USER_REGISTER: Lazy<JSONSchema> = Lazy::new(|| {
         .expect("A valid schema")
 });
 
+pub static USER_PRESENCE: Lazy<JSONSchema> = Lazy::new(|| {
+    let schema = include_str!("schema/user_presence.json");
+    let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema")
+});
+
+pub static POST_CREATE: Lazy<JSONSchema> = Lazy::new(|| {
+    let schema = include_str!("schema/post_create.json");
+    let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema")
+});
+
+pub static POST_UPDATE: Lazy<JSONSchema> = Lazy::new(|| {
+    let schema = include_str!("schema/post_update.json");
+    let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema")
+});
+
+pub static DIALOG_SEND: Lazy<JSONSchema> = Lazy::new(|| {
+    let schema = include_str!("schema/dialog_send.json");
+    let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema")
+});
+
 pub fn validate(payload: &serde_json::Value, schema: &JSONSchema) -> anyhow::Result<()> {
     if let Err(err) = schema.validate(payload) {
         let msg = err
@@ -36,3 +72,39 @@ pub fn validate(payload: &serde_json::Value, schema: &JSONSchema) -> anyhow::Res
         Ok(())
     }
 }
+
+pub static DIALOG_READ: Lazy<JSONSchema> = Lazy::new(|| {
+    let schema = include_str!("schema/dialog_read.json");
+    let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema")
+});
+
+pub static GROUP_CREATE: Lazy<JSONSchema> = Lazy::new(|| {
+    let schema = include_str!("schema/group_create.json");
+    let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema")
+});
+
+pub static GROUP_RENAME: Lazy<JSONSchema> = Lazy::new(|| {
+    let schema = include_str!("schema/group_rename.json");
+    let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema")
+});
+
+pub static GROUP_ROLE: Lazy<JSONSchema> = Lazy::new(|| {
+    let schema = include_str!("schema/group_role.json");
+    let schema: serde_json::Value = serde_json::from_str(schema).unwrap();
+    JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .expect("A valid schema")
+});
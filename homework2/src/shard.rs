@@ -0,0 +1,493 @@
+use crate::{db, db_message, db_reshard};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio_postgres::GenericClient;
+
+/// Points on the ring per shard: the more there are, the more even the distribution.
+const VIRTUAL_NODES: usize = 256;
+/// Name of the shard used when no message shards are configured.
+const MAIN_SHARD: &str = "main";
+/// How often the cut-over switch of a resharding is read from the main database.
+const CUT_OVER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Decides which shard a key belongs to.
+pub trait ShardMap: Send + Sync {
+    fn shard(&self, key: &str) -> &str;
+}
+
+/// Consistent hashing with virtual nodes: adding or removing a shard moves only
+/// the keys of the neighbouring ring segments.
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new(shards: &[String], virtual_nodes: usize) -> Self {
+        let mut ring = BTreeMap::new();
+        for shard in shards {
+            for node in 0..virtual_nodes {
+                ring.insert(hash(&format!("{shard}#{node}")), shard.clone());
+            }
+        }
+        Self { ring }
+    }
+}
+
+impl ShardMap for HashRing {
+    fn shard(&self, key: &str) -> &str {
+        let point = hash(key);
+        self.ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, shard)| shard.as_str())
+            .expect("hash ring must have at least one shard")
+    }
+}
+
+/// FNV-1a, stable across builds and platforms unlike `std` hashers,
+/// which matters since the placement of stored data depends on it.
+fn hash(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    // FNV output is poorly mixed in the high bits for short keys, finalize it
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// `name:connection string` of the shards, the messages are kept in the main
+    /// database if there are none.
+    pub shards: Vec<String>,
+    /// `name:connection string` of the shards the messages are being moved to.
+    pub targets: Vec<String>,
+}
+
+/// A shard along with its name.
+pub type Named<'a> = (&'a str, &'a Arc<db::DB>);
+
+/// Where a key is read from and written to.
+pub struct Route<'a> {
+    /// Authoritative location: reads go here and writes go here first.
+    pub primary: Named<'a>,
+    /// While resharding, the other location of the key if it differs.
+    /// Writes are copied there after they succeed on the primary.
+    pub secondary: Option<Named<'a>>,
+}
+
+/// Postgres pools the messages are spread over, routed by conversation id so both
+/// participants of a dialog always hit the same shard.
+///
+/// With target shards configured the messages are being resharded: writes go to both the
+/// current and the target location of a conversation, `reshard-backfill` copies the older
+/// messages, `reshard-verify` compares both locations and the cut-over switch makes the
+/// target location authoritative. Writes keep going to both sides until the server is
+/// restarted with the targets as its shards, so the cut-over can be rolled back.
+pub struct Shards {
+    dbs: HashMap<String, Arc<db::DB>>,
+    current: Box<dyn ShardMap>,
+    current_names: Vec<String>,
+    target: Option<Target>,
+    main_db: Arc<db::DB>,
+}
+
+struct Target {
+    map: Box<dyn ShardMap>,
+    names: Vec<String>,
+    /// Identifies the resharding, so the state stored for another one is not picked up.
+    fingerprint: String,
+    cut_over: AtomicBool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Status {
+    pub current: Vec<String>,
+    pub target: Vec<String>,
+    pub resharding: bool,
+    pub verified: bool,
+    pub cut_over: bool,
+}
+
+impl Shards {
+    pub async fn new(
+        config: &Config,
+        main_db: Arc<db::DB>,
+        conn_pool_size: usize,
+    ) -> anyhow::Result<Self> {
+        let mut conn_strings = HashMap::new();
+        let current_names = parse_specs(&config.shards, &mut conn_strings)?;
+        let target_names = parse_specs(&config.targets, &mut conn_strings)?;
+
+        let mut dbs = HashMap::new();
+        for (name, conn_string) in conn_strings {
+            let db = db::DB::new_messages_shard(&conn_string, main_db.tls(), conn_pool_size)
+                .await
+                .map_err(|e| anyhow::anyhow!("message shard '{}': {}", name, e))?;
+            tracing::info!("message shard '{}' connected", name);
+            dbs.insert(name, Arc::new(db));
+        }
+        let current_names = if current_names.is_empty() {
+            dbs.insert(MAIN_SHARD.to_owned(), main_db.clone());
+            vec![MAIN_SHARD.to_owned()]
+        } else {
+            current_names
+        };
+
+        let mut shards = Self {
+            dbs,
+            current: ring(&current_names),
+            current_names,
+            target: None,
+            main_db,
+        };
+        if !target_names.is_empty() {
+            let fingerprint = format!(
+                "{}->{}",
+                sorted(&shards.current_names).join(","),
+                sorted(&target_names).join(",")
+            );
+            let pg_pool = shards.main_db.get().await?;
+            let state = db_reshard::Reshard::get(&fingerprint, pg_pool.client()).await?;
+            tracing::warn!(
+                "resharding messages from {:?} to {:?}, dual writes enabled, verified: {}, cut over: {}",
+                shards.current_names,
+                target_names,
+                state.verified,
+                state.cut_over
+            );
+            shards.target = Some(Target {
+                map: ring(&target_names),
+                names: target_names,
+                fingerprint,
+                cut_over: AtomicBool::new(state.cut_over),
+            });
+        }
+        shards.skip_legacy_ids().await?;
+        Ok(shards)
+    }
+
+    pub fn route(&self, key: &str) -> Route<'_> {
+        let current = self.current.shard(key);
+        let Some(target) = &self.target else {
+            return Route {
+                primary: self.named(current),
+                secondary: None,
+            };
+        };
+
+        let moved_to = target.map.shard(key);
+        let (primary, secondary) = if target.cut_over.load(Ordering::Acquire) {
+            (moved_to, current)
+        } else {
+            (current, moved_to)
+        };
+        Route {
+            primary: self.named(primary),
+            secondary: (primary != secondary).then(|| self.named(secondary)),
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> anyhow::Result<deadpool_postgres::Object> {
+        let (name, db) = self.route(key).primary;
+        tracing::trace!("key {} routed to message shard {}", key, name);
+        db.get()
+            .await
+            .map_err(|e| anyhow::anyhow!("message shard '{}': {}", name, e))
+    }
+
+    pub async fn status(&self) -> anyhow::Result<Status> {
+        let state = match &self.target {
+            Some(target) => {
+                let pg_pool = self.main_db.get().await?;
+                db_reshard::Reshard::get(&target.fingerprint, pg_pool.client()).await?
+            }
+            None => db_reshard::Reshard::default(),
+        };
+        Ok(Status {
+            current: self.current_names.clone(),
+            target: self
+                .target
+                .as_ref()
+                .map(|target| target.names.clone())
+                .unwrap_or_default(),
+            resharding: self.target.is_some(),
+            verified: state.verified,
+            cut_over: self
+                .target
+                .as_ref()
+                .is_some_and(|target| target.cut_over.load(Ordering::Acquire)),
+        })
+    }
+
+    /// Makes the target shards authoritative for reads and writes, or the current ones
+    /// back again. Cutting over requires a successful verification. The switch is stored in
+    /// the main database, the other servers pick it up within [`CUT_OVER_POLL_INTERVAL`].
+    pub async fn set_cut_over(&self, cut_over: bool) -> anyhow::Result<Status> {
+        let Some(target) = &self.target else {
+            anyhow::bail!("messages are not being resharded");
+        };
+        {
+            let pg_pool = self.main_db.get().await?;
+            let state = db_reshard::Reshard::get(&target.fingerprint, pg_pool.client()).await?;
+            if cut_over && !state.verified {
+                anyhow::bail!("resharding has not been verified, run reshard-verify first");
+            }
+            db_reshard::Reshard::set_cut_over(&target.fingerprint, cut_over, pg_pool.client())
+                .await?;
+        }
+        self.switch(target, cut_over);
+        self.status().await
+    }
+
+    /// Follows the cut-over switch stored in the main database while resharding. Until every
+    /// server has picked a change up they disagree on the authoritative side, which is safe
+    /// as all of them keep writing to both.
+    pub fn spawn_workers(self: &Arc<Self>) {
+        if self.target.is_none() {
+            return;
+        }
+        let shards = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(CUT_OVER_POLL_INTERVAL).await;
+                if let Err(err) = shards.poll_cut_over().await {
+                    tracing::error!("failed to read the resharding cut over: {err:?}");
+                }
+            }
+        });
+    }
+
+    async fn poll_cut_over(&self) -> anyhow::Result<()> {
+        let Some(target) = &self.target else {
+            return Ok(());
+        };
+        let pg_pool = self.main_db.get().await?;
+        let state = db_reshard::Reshard::get(&target.fingerprint, pg_pool.client()).await?;
+        self.switch(target, state.cut_over);
+        Ok(())
+    }
+
+    fn switch(&self, target: &Target, cut_over: bool) {
+        if target.cut_over.swap(cut_over, Ordering::AcqRel) == cut_over {
+            return;
+        }
+        tracing::warn!(
+            "messages resharding cut over {}, reads go to {:?}",
+            if cut_over { "done" } else { "rolled back" },
+            if cut_over {
+                &target.names
+            } else {
+                &self.current_names
+            }
+        );
+    }
+
+    /// Shards the messages are moved from, along with the fingerprint of the resharding.
+    pub fn sources(&self) -> Option<(&str, Vec<Named<'_>>)> {
+        let target = self.target.as_ref()?;
+        let sources = self
+            .current_names
+            .iter()
+            .map(|name| self.named(name))
+            .collect();
+        Some((&target.fingerprint, sources))
+    }
+
+    /// Shards authoritative for their keys at the moment: the target shards once cut over,
+    /// the current ones otherwise. Keys stored on a shard may still be routed elsewhere.
+    pub fn primaries(&self) -> Vec<Named<'_>> {
+        let names = match &self.target {
+            Some(target) if target.cut_over.load(Ordering::Acquire) => &target.names,
+            _ => &self.current_names,
+        };
+        names.iter().map(|name| self.named(name)).collect()
+    }
+
+    /// Whether the conversation is routed to the shard. Resharding leaves copies of the
+    /// messages behind on the shards a conversation has moved away from, they are not
+    /// authoritative and must be skipped by anything reading a shard as a whole.
+    pub fn owns(&self, shard_name: &str, conversation_id: &str) -> bool {
+        self.route(conversation_id).primary.0 == shard_name
+    }
+
+    /// Every shard, including the ones only the current or only the target layout has.
+    pub fn all(&self) -> Vec<Named<'_>> {
+        self.dbs
+            .iter()
+            .map(|(name, db)| (name.as_str(), db))
+            .collect()
+    }
+
+    /// Where the key is going to be kept once resharded.
+    pub fn target(&self, key: &str) -> Option<Named<'_>> {
+        let target = self.target.as_ref()?;
+        Some(self.named(target.map.shard(key)))
+    }
+
+    pub fn main_db(&self) -> &Arc<db::DB> {
+        &self.main_db
+    }
+
+    /// Shards used to assign message ids themselves, the ids taken from the main database
+    /// continue after the ones they have given out.
+    async fn skip_legacy_ids(&self) -> anyhow::Result<()> {
+        let mut last_id = 0;
+        for (name, db) in &self.dbs {
+            let pg_pool = db.get().await?;
+            let shard_last_id = db_message::Message::legacy_last_id(pg_pool.client())
+                .await
+                .map_err(|e| anyhow::anyhow!("message shard '{}': {}", name, e))?;
+            last_id = last_id.max(shard_last_id);
+        }
+        let pg_pool = self.main_db.get().await?;
+        let last_id = db_message::Message::skip_ids(last_id, pg_pool.client()).await?;
+        tracing::info!("message ids continue after {}", last_id);
+        Ok(())
+    }
+
+    fn named<'a>(&'a self, name: &'a str) -> Named<'a> {
+        (name, &self.dbs[name])
+    }
+}
+
+fn ring(names: &[String]) -> Box<dyn ShardMap> {
+    Box::new(HashRing::new(names, VIRTUAL_NODES))
+}
+
+fn sorted(names: &[String]) -> Vec<String> {
+    let mut names = names.to_vec();
+    names.sort();
+    names
+}
+
+/// Collects shard names, checking that a name always refers to the same database.
+fn parse_specs(
+    specs: &[String],
+    conn_strings: &mut HashMap<String, String>,
+) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let (name, conn_string) = parse_spec(spec)?;
+        if names.iter().any(|known| known == name) {
+            anyhow::bail!("message shard '{}' specified more than once", name);
+        }
+        match conn_strings.get(name) {
+            Some(known) if known != conn_string => anyhow::bail!(
+                "message shard '{}' specified with different connection strings",
+                name
+            ),
+            Some(_) => {}
+            None => {
+                conn_strings.insert(name.to_owned(), conn_string.to_owned());
+            }
+        }
+        names.push(name.to_owned());
+    }
+    Ok(names)
+}
+
+fn parse_spec(spec: &str) -> anyhow::Result<(&str, &str)> {
+    match spec.split_once(':') {
+        Some((name, conn_string)) if !name.is_empty() && !conn_string.is_empty() => {
+            Ok((name.trim(), conn_string.trim()))
+        }
+        _ => anyhow::bail!(
+            "invalid message shard '{}', expected 'name:connection string'",
+            spec
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: usize = 30_000;
+
+    fn names(count: usize) -> Vec<String> {
+        (1..=count).map(|n| format!("s{n}")).collect()
+    }
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..KEYS).map(|n| format!("user-{n}:user-{}", n * 7 + 1))
+    }
+
+    #[test]
+    fn keys_are_spread_evenly() {
+        let shards = names(4);
+        let ring = HashRing::new(&shards, VIRTUAL_NODES);
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for key in keys() {
+            *counts.entry(ring.shard(&key)).or_default() += 1;
+        }
+        let fair = KEYS / shards.len();
+        for shard in &shards {
+            let count = counts.get(shard.as_str()).copied().unwrap_or_default();
+            assert!(
+                count.abs_diff(fair) < fair / 5,
+                "shard {shard} got {count} keys, fair share is {fair}: {counts:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn added_shard_takes_keys_from_the_others_only() {
+        let before = HashRing::new(&names(3), VIRTUAL_NODES);
+        let after = HashRing::new(&names(4), VIRTUAL_NODES);
+        let mut moved = 0_usize;
+        for key in keys() {
+            let (from, to) = (before.shard(&key), after.shard(&key));
+            if from != to {
+                assert_eq!(to, "s4", "key {key} moved from {from} to {to}");
+                moved += 1;
+            }
+        }
+        let fair = KEYS / 4;
+        assert!(
+            moved.abs_diff(fair) < fair / 5,
+            "{moved} keys moved, fair share of the new shard is {fair}"
+        );
+    }
+
+    #[test]
+    fn removed_shard_gives_away_its_keys_only() {
+        let before = HashRing::new(&names(4), VIRTUAL_NODES);
+        let after = HashRing::new(&names(3), VIRTUAL_NODES);
+        for key in keys() {
+            let (from, to) = (before.shard(&key), after.shard(&key));
+            if from != "s4" {
+                assert_eq!(from, to, "key {key} of a remaining shard moved");
+            }
+        }
+    }
+
+    #[test]
+    fn placement_does_not_depend_on_shard_order() {
+        let mut reversed = names(4);
+        reversed.reverse();
+        let ring = HashRing::new(&names(4), VIRTUAL_NODES);
+        let reversed = HashRing::new(&reversed, VIRTUAL_NODES);
+        for key in keys() {
+            assert_eq!(ring.shard(&key), reversed.shard(&key));
+        }
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        // stored messages are placed by it, a change would strand them on the wrong shards
+        assert_eq!(hash(""), 17058014651485797458);
+        assert_eq!(hash("a:b"), 1147367681497871253);
+    }
+}
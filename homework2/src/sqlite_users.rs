@@ -0,0 +1,337 @@
+use crate::{db, db_user, password, user_repository::UserRepository};
+use rusqlite::OptionalExtension;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+const SCHEME: &str = "sqlite:";
+const IN_MEMORY: &str = ":memory:";
+
+/// Users in a SQLite file, for running the user API locally without postgres. The schema
+/// is the one of the postgres users table, with the times kept as milliseconds since the epoch.
+pub struct SqliteUserRepository {
+    conn: Arc<Mutex<rusqlite::Connection>>,
+}
+
+impl SqliteUserRepository {
+    /// Path of the database given by a `sqlite://<path>` connection string, `sqlite::memory:`
+    /// keeps it in memory. None for the other connection strings.
+    pub fn path(conn_string: &str) -> Option<&str> {
+        let rest = conn_string.trim().strip_prefix(SCHEME)?;
+        Some(rest.strip_prefix("//").unwrap_or(rest))
+    }
+
+    pub async fn open(path: &str) -> anyhow::Result<Self> {
+        let path = path.to_owned();
+        let conn = tokio::task::spawn_blocking(move || {
+            let conn = if path == IN_MEMORY {
+                rusqlite::Connection::open_in_memory()
+            } else {
+                rusqlite::Connection::open(&path)
+            }
+            .map_err(|e| anyhow::anyhow!("failed to open SQLite database '{}': {}", path, e))?;
+            // LIKE is case-sensitive in postgres
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA case_sensitive_like = ON;")
+                .map_err(|e| anyhow::anyhow!("failed to set up SQLite database: {}", e))?;
+            SqliteUserRepository::apply_migrations(&conn)?;
+            tracing::info!("users are kept in SQLite database '{}'", path);
+            anyhow::Ok(conn)
+        })
+        .await??;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    fn apply_migrations(conn: &rusqlite::Connection) -> anyhow::Result<()> {
+        let table = db::TABLE_USERS;
+        for migration in db::users_migrations() {
+            match migration {
+                db::UsersMigration::Statement { statement, failure } => {
+                    conn.execute_batch(&statement)
+                        .map_err(|e| anyhow::anyhow!("{} '{}': {}", failure, table, e))?;
+                }
+                db::UsersMigration::AddColumn { name, definition } => {
+                    let exists = conn
+                        .prepare(&format!(
+                            "select 1 from pragma_table_info('{table}') where name = ?1"
+                        ))
+                        .and_then(|mut statement| statement.exists([name]))
+                        .map_err(|e| {
+                            anyhow::anyhow!("failed to read columns of '{}': {}", table, e)
+                        })?;
+                    if !exists {
+                        conn.execute_batch(&format!(
+                            "alter table {table} add column {name} {definition}"
+                        ))
+                        .map_err(|e| {
+                            anyhow::anyhow!("failed to add {} to '{}': {}", name, table, e)
+                        })?;
+                    }
+                }
+                db::UsersMigration::Index {
+                    name,
+                    sqlite_columns,
+                    ..
+                } => {
+                    conn.execute_batch(&format!(
+                        "create index if not exists {table}_{name}_idx on {table} ({sqlite_columns})"
+                    ))
+                    .map_err(|e| {
+                        anyhow::anyhow!("failed to create {} index on '{}': {}", name, table, e)
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the queries on a blocking thread, SQLite calls block.
+    async fn call<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut rusqlite::Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(|e| anyhow::anyhow!("SQLite query panicked: {}", e))?
+    }
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<db_user::User> {
+        Ok(db_user::User {
+            id: row.get(0)?,
+            first_name: row.get(1)?,
+            second_name: row.get(2)?,
+            birthdate: row.get(3)?,
+            biography: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+            city: row.get(5)?,
+            password_hash: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+            token: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+        })
+    }
+
+    fn profile_from_row(row: &rusqlite::Row) -> rusqlite::Result<db_user::Profile> {
+        Ok(db_user::Profile {
+            id: row.get(0)?,
+            first_name: row.get(1)?,
+            second_name: row.get(2)?,
+            birthdate: row.get(3)?,
+            biography: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+            city: row.get(5)?,
+        })
+    }
+}
+
+const SELECT_USER: &str =
+    "select id, first_name, second_name, birthdate, biography, city, password_hash, token from users";
+const SELECT_PROFILE: &str =
+    "select id, first_name, second_name, birthdate, biography, city from users";
+
+#[axum::async_trait]
+impl UserRepository for SqliteUserRepository {
+    async fn insert(&self, user: db_user::User) -> anyhow::Result<String> {
+        self.call(move |conn| {
+            let statement = "INSERT INTO users (id, first_name, second_name, birthdate, biography, city, password_hash, token) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, '')";
+            conn.prepare_cached(statement)
+                .and_then(|mut statement| {
+                    statement.execute((
+                        &user.id,
+                        &user.first_name,
+                        &user.second_name,
+                        &user.birthdate,
+                        &user.biography,
+                        &user.city,
+                        &user.password_hash,
+                    ))
+                })
+                .map_err(|e| anyhow::anyhow!("insert failed: {}", e))?;
+            Ok(user.id)
+        })
+        .await
+    }
+
+    async fn get(
+        &self,
+        id: &str,
+        _min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Option<db_user::Profile>> {
+        let id = id.to_owned();
+        self.call(move |conn| {
+            conn.prepare_cached(&format!("{SELECT_PROFILE} where id = ?1"))
+                .and_then(|mut statement| {
+                    statement
+                        .query_row([&id], SqliteUserRepository::profile_from_row)
+                        .optional()
+                })
+                .map_err(|e| anyhow::anyhow!("failed to read user: {}", e))
+        })
+        .await
+    }
+
+    async fn search(
+        &self,
+        first_name: Option<&String>,
+        second_name: Option<&String>,
+        _min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Vec<db_user::Profile>> {
+        let (condition, params) = match (first_name, second_name) {
+            (Some(first_name), Some(second_name)) => (
+                "where first_name LIKE ?1 and second_name LIKE ?2",
+                vec![first_name.to_owned() + "%", second_name.to_owned() + "%"],
+            ),
+            (Some(first_name), None) => (
+                "where first_name LIKE ?1",
+                vec![first_name.to_owned() + "%"],
+            ),
+            (None, Some(second_name)) => (
+                "where second_name LIKE ?1",
+                vec![second_name.to_owned() + "%"],
+            ),
+            (None, None) => return Ok(vec![]),
+        };
+        let (first_name, second_name) = (first_name.cloned(), second_name.cloned());
+        self.call(move |conn| {
+            conn.prepare_cached(&format!("{SELECT_PROFILE} {condition} order by id"))
+                .and_then(|mut statement| {
+                    statement
+                        .query_map(
+                            rusqlite::params_from_iter(&params),
+                            SqliteUserRepository::profile_from_row,
+                        )?
+                        .collect()
+                })
+                .map_err(|e| anyhow::anyhow!("failed to search users: first_name={first_name:?} second_name={second_name:?}: {e}"))
+        })
+        .await
+    }
+
+    async fn update_token(
+        &self,
+        user_id: &str,
+        password: &str,
+    ) -> anyhow::Result<db_user::UpdatedTokenResult> {
+        let id = user_id.to_owned();
+        let password_hash = self
+            .call(move |conn| {
+                conn.prepare_cached("select password_hash from users where id = ?1")
+                    .and_then(|mut statement| {
+                        statement
+                            .query_row([&id], |row| row.get::<_, Option<String>>(0))
+                            .optional()
+                    })
+                    .map_err(|e| anyhow::anyhow!("failed to read user: {}", e))
+            })
+            .await?;
+        let Some(password_hash) = password_hash else {
+            return Ok(db_user::UpdatedTokenResult::UserNotFound);
+        };
+        if !password::verify_password(password, &password_hash.unwrap_or_default()) {
+            return Ok(db_user::UpdatedTokenResult::WrongPassword);
+        }
+
+        let id = user_id.to_owned();
+        let user = self
+            .call(move |conn| {
+                let token = uuid::Uuid::new_v4().to_string();
+                let now = chrono::Utc::now().timestamp_millis();
+                let updated = conn
+                    .prepare_cached("UPDATE users SET token = ?1, last_login_at = ?2 WHERE id = ?3")
+                    .and_then(|mut statement| statement.execute((&token, now, &id)))
+                    .map_err(|e| anyhow::anyhow!("failed to update token: {}", e))?;
+                if updated == 0 {
+                    return Ok(None);
+                }
+                conn.prepare_cached(&format!("{SELECT_USER} where id = ?1"))
+                    .and_then(|mut statement| {
+                        statement.query_row([&id], SqliteUserRepository::from_row)
+                    })
+                    .map(Some)
+                    .map_err(|e| anyhow::anyhow!("failed to read user: {}", e))
+            })
+            .await?;
+        Ok(match user {
+            Some(user) => db_user::UpdatedTokenResult::Ok(user),
+            None => db_user::UpdatedTokenResult::UserNotFound,
+        })
+    }
+
+    async fn user_id_by_token(&self, token: &str) -> anyhow::Result<Option<String>> {
+        if token.is_empty() {
+            return Ok(None);
+        }
+        let token = token.to_owned();
+        self.call(move |conn| {
+            conn.prepare_cached("select id from users where token = ?1")
+                .and_then(|mut statement| {
+                    statement.query_row([&token], |row| row.get(0)).optional()
+                })
+                .map_err(|e| anyhow::anyhow!("failed to read user by token: {}", e))
+        })
+        .await
+    }
+
+    async fn presence(
+        &self,
+        user_ids: &[String],
+        _min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Vec<db_user::StoredPresence>> {
+        let user_ids = user_ids.to_vec();
+        self.call(move |conn| {
+            let mut statement = conn
+                .prepare_cached("select id, last_seen_at, presence_hidden from users where id = ?1")
+                .map_err(|e| anyhow::anyhow!("failed to read presence of users: {}", e))?;
+            let mut presence = Vec::with_capacity(user_ids.len());
+            for user_id in &user_ids {
+                let stored = statement
+                    .query_row([user_id], |row| {
+                        Ok(db_user::StoredPresence {
+                            user_id: row.get(0)?,
+                            last_seen_at: row.get(1)?,
+                            hidden: row.get(2)?,
+                        })
+                    })
+                    .optional()
+                    .map_err(|e| anyhow::anyhow!("failed to read presence of users: {}", e))?;
+                presence.extend(stored);
+            }
+            Ok(presence)
+        })
+        .await
+    }
+
+    async fn update_last_seen(&self, seen: &HashMap<String, i64>) -> anyhow::Result<()> {
+        let seen = seen.clone();
+        self.call(move |conn| {
+            let transaction = conn
+                .transaction()
+                .map_err(|e| anyhow::anyhow!("failed to update last seen times: {}", e))?;
+            {
+                let mut statement = transaction
+                    .prepare_cached("UPDATE users SET last_seen_at = max(coalesce(last_seen_at, 0), ?2) WHERE id = ?1")
+                    .map_err(|e| anyhow::anyhow!("failed to update last seen times: {}", e))?;
+                for (user_id, time) in &seen {
+                    statement
+                        .execute((user_id, time))
+                        .map_err(|e| anyhow::anyhow!("failed to update last seen times: {}", e))?;
+                }
+            }
+            transaction
+                .commit()
+                .map_err(|e| anyhow::anyhow!("failed to update last seen times: {}", e))
+        })
+        .await
+    }
+
+    async fn set_presence_hidden(&self, user_id: &str, hidden: bool) -> anyhow::Result<()> {
+        let user_id = user_id.to_owned();
+        self.call(move |conn| {
+            conn.prepare_cached("UPDATE users SET presence_hidden = ?1 WHERE id = ?2")
+                .and_then(|mut statement| statement.execute((hidden, &user_id)))
+                .map_err(|e| anyhow::anyhow!("failed to update presence setting: {}", e))?;
+            Ok(())
+        })
+        .await
+    }
+}
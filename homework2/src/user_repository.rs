@@ -0,0 +1,143 @@
+use crate::{db, db_user};
+use std::{collections::HashMap, sync::Arc};
+
+/// Storage of the users behind registration, login, the profiles and the presence
+/// settings. Reads given `min_lsn` must see the writes up to it, backends without
+/// replicas may ignore it.
+#[axum::async_trait]
+pub trait UserRepository: Send + Sync + 'static {
+    /// Saves a new user and returns its id.
+    async fn insert(&self, user: db_user::User) -> anyhow::Result<String>;
+
+    async fn get(
+        &self,
+        id: &str,
+        min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Option<db_user::Profile>>;
+
+    /// Users whose first and second names start with the given ones, a missing name
+    /// matches everyone, but at least one is needed to find anybody.
+    async fn search(
+        &self,
+        first_name: Option<&String>,
+        second_name: Option<&String>,
+        min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Vec<db_user::Profile>>;
+
+    /// Issues a new login token if the password is right.
+    async fn update_token(
+        &self,
+        user_id: &str,
+        password: &str,
+    ) -> anyhow::Result<db_user::UpdatedTokenResult>;
+
+    /// Id of the user the login token has been issued to.
+    async fn user_id_by_token(&self, token: &str) -> anyhow::Result<Option<String>>;
+
+    async fn presence(
+        &self,
+        user_ids: &[String],
+        min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Vec<db_user::StoredPresence>>;
+
+    /// Last-seen times in milliseconds since the epoch, by user id. A time older
+    /// than the saved one is ignored.
+    async fn update_last_seen(&self, seen: &HashMap<String, i64>) -> anyhow::Result<()>;
+
+    async fn set_presence_hidden(&self, user_id: &str, hidden: bool) -> anyhow::Result<()>;
+}
+
+/// Users in the main postgres, read from its replicas when there are any. All but
+/// the registration and the login are retried after transient errors.
+pub struct PostgresUserRepository {
+    db: Arc<db::DB>,
+}
+
+impl PostgresUserRepository {
+    pub fn new(db: Arc<db::DB>) -> Self {
+        Self { db }
+    }
+}
+
+#[axum::async_trait]
+impl UserRepository for PostgresUserRepository {
+    async fn insert(&self, user: db_user::User) -> anyhow::Result<String> {
+        user.insert_to_db(&self.db).await
+    }
+
+    async fn get(
+        &self,
+        id: &str,
+        min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Option<db_user::Profile>> {
+        self.db
+            .retry(|| async move {
+                let pg_pool = self.db.get_read(min_lsn).await?;
+                db_user::Profile::from_id(id, &pg_pool).await
+            })
+            .await
+    }
+
+    async fn search(
+        &self,
+        first_name: Option<&String>,
+        second_name: Option<&String>,
+        min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Vec<db_user::Profile>> {
+        self.db
+            .retry(|| async move {
+                let pg_pool = self.db.get_read(min_lsn).await?;
+                db_user::Profile::search(&pg_pool, first_name, second_name).await
+            })
+            .await
+    }
+
+    async fn update_token(
+        &self,
+        user_id: &str,
+        password: &str,
+    ) -> anyhow::Result<db_user::UpdatedTokenResult> {
+        let mut pg_pool = self.db.get().await?;
+        db_user::User::update_token(user_id, password, &mut pg_pool).await
+    }
+
+    async fn user_id_by_token(&self, token: &str) -> anyhow::Result<Option<String>> {
+        self.db
+            .retry(|| async move {
+                let pg_pool = self.db.get().await?;
+                db_user::User::id_by_token(token, &pg_pool).await
+            })
+            .await
+    }
+
+    async fn presence(
+        &self,
+        user_ids: &[String],
+        min_lsn: Option<db::Lsn>,
+    ) -> anyhow::Result<Vec<db_user::StoredPresence>> {
+        self.db
+            .retry(|| async move {
+                let pg_pool = self.db.get_read(min_lsn).await?;
+                db_user::User::presence(user_ids, &pg_pool).await
+            })
+            .await
+    }
+
+    async fn update_last_seen(&self, seen: &HashMap<String, i64>) -> anyhow::Result<()> {
+        self.db
+            .retry(|| async move {
+                let pg_pool = self.db.get().await?;
+                db_user::User::update_last_seen(seen, &pg_pool).await
+            })
+            .await
+    }
+
+    async fn set_presence_hidden(&self, user_id: &str, hidden: bool) -> anyhow::Result<()> {
+        self.db
+            .retry(|| async move {
+                let pg_pool = self.db.get().await?;
+                db_user::User::set_presence_hidden(user_id, hidden, &pg_pool).await
+            })
+            .await
+    }
+}
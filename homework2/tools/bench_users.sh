@@ -0,0 +1,53 @@
+#!/bin/bash
+# Measures /user/get and /user/search throughput of a running server.
+#
+# The database is filled beforehand with the users of generate-inserts:
+#   social-network generate-inserts --limit 200000 | grep '^INSERT' | psql "$PSQL_CONN"
+#
+# usage: PSQL_CONN="host=localhost user=postgres dbname=..." tools/bench_users.sh [server url]
+set -euo pipefail
+
+SERVER=${1:-http://127.0.0.1:8080}
+PSQL_CONN=${PSQL_CONN:?connection string of the server database for psql}
+PARALLEL=${PARALLEL:-16}
+RUNS=${RUNS:-3}
+GETS=${GETS:-3000}
+SEARCHES=${SEARCHES:-400}
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+# the requests need a token, a fresh user gets one
+user_id=$(curl -sf "$SERVER/user/register" -H 'Content-Type: application/json' \
+    -d '{"first_name": "Bench", "second_name": "Bench", "birthdate": "1980-01-01", "biography": "", "city": "Moscow", "password": "bench"}' |
+    python3 -c 'import json, sys; print(json.load(sys.stdin)["user_id"])')
+token=$(curl -sf "$SERVER/login" -H 'Content-Type: application/json' \
+    -d "{\"id\": \"$user_id\", \"password\": \"bench\"}" |
+    python3 -c 'import json, sys; print(json.load(sys.stdin)["token"])')
+
+psql "$PSQL_CONN" -Atc "select id from users order by random() limit $GETS" |
+    while read -r id; do
+        echo "url = \"$SERVER/user/get/$id\""
+        echo 'output = "/dev/null"'
+    done >"$work/get.cfg"
+psql "$PSQL_CONN" -Atc "select left(first_name, 4) from users order by random() limit $SEARCHES" |
+    while read -r prefix; do
+        echo "url = \"$SERVER/user/search?first_name=$prefix\""
+        echo 'output = "/dev/null"'
+    done >"$work/search.cfg"
+
+for kind in get search; do
+    requests=$(grep -c '^url' "$work/$kind.cfg")
+    run() {
+        curl -s --no-progress-meter -Z --parallel-max "$PARALLEL" \
+            -H "Authorization: Bearer $token" -K "$work/$kind.cfg"
+    }
+    # warms up the connections and the caches
+    run
+    for i in $(seq 1 "$RUNS"); do
+        start=$(date +%s.%N)
+        run
+        end=$(date +%s.%N)
+        python3 -c "print('$kind run $i: %d rps' % ($requests / ($end - $start)))"
+    done
+done
@@ -3,13 +3,13 @@ use rnglib::{Language, RNG};
 
 pub async fn run(limit: usize) -> anyhow::Result<()> {
     let mut rng = rand::thread_rng();
-    let rngs = vec![
-        RNG::try_from(&Language::Curse).unwrap(),
-        RNG::try_from(&Language::Demonic).unwrap(),
-        RNG::try_from(&Language::Elven).unwrap(),
-        RNG::try_from(&Language::Fantasy).unwrap(),
-        RNG::try_from(&Language::Goblin).unwrap(),
-        RNG::try_from(&Language::Roman).unwrap(),
+    let rngs = [
+        RNG::from(&Language::Curse),
+        RNG::from(&Language::Demonic),
+        RNG::from(&Language::Elven),
+        RNG::from(&Language::Fantasy),
+        RNG::from(&Language::Goblin),
+        RNG::from(&Language::Roman),
         // RNG::try_from(&Language::Эльфийский).unwrap(),
         // RNG::try_from(&Language::Эльфийский).unwrap(),
         // RNG::try_from(&Language::Эльфийский).unwrap(),